@@ -10,6 +10,8 @@ impl<T: Clone + Eq + Hash> CacheKey for T {}
 impl<T: Clone + Sized> CacheVal for T {}
 
 pub struct Cache<K: CacheKey, V: CacheVal> {
+    enabled: bool,
+    // Number of elements kept at most
     size_limit: usize,
     tot_size: AtomicUsize,
 
@@ -21,12 +23,11 @@ pub struct Cache<K: CacheKey, V: CacheVal> {
 impl<K: CacheKey, V: CacheVal> std::fmt::Debug for Cache<K, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let tot_size = self.tot_size.load(Ordering::Relaxed);
-        let nelements = self.data.read().len();
         let naccesses = self.tot_count.load(Ordering::Relaxed);
         write!(
             f,
-            "Cache {{ {tot_size}/{} bytes used, {} elements, {} accesses }}",
-            self.size_limit, nelements, naccesses,
+            "Cache {{ {tot_size}/{} elements, {} accesses }}",
+            self.size_limit, naccesses,
         )
     }
 }
@@ -34,6 +35,7 @@ impl<K: CacheKey, V: CacheVal> std::fmt::Debug for Cache<K, V> {
 impl<K: CacheKey, V: CacheVal> Cache<K, V> {
     pub fn empty(size_limit: usize) -> Cache<K, V> {
         Cache {
+            enabled: true,
             size_limit,
             tot_size: AtomicUsize::new(0),
             tot_count: AtomicUsize::new(0),
@@ -42,10 +44,15 @@ impl<K: CacheKey, V: CacheVal> Cache<K, V> {
         }
     }
 
-    #[allow(unreachable_code)]
+    // A disabled cache never returns data, used to see changes right away in dev mode
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
     pub fn get(&self, key: &K) -> Option<V> {
-        #[cfg(feature = "hot-reloading")]
-        return None;
+        if !self.enabled {
+            return None;
+        }
 
         let tstart = std::time::Instant::now();
         self.tot_count.fetch_add(1, Ordering::Relaxed);
@@ -62,24 +69,83 @@ impl<K: CacheKey, V: CacheVal> Cache<K, V> {
         res
     }
 
-    pub fn add(&self, key: K, val: V) {
-        let tstart = std::time::Instant::now();
-        let tot_size = self.tot_size.load(Ordering::Relaxed);
-        let val_size = std::mem::size_of::<V>();
-        if (val_size + tot_size) > self.size_limit {
-            self.make_space(val_size);
+    // Returns the keys no longer cached, the ones evicted to make space for this one,
+    // or the key itself if the cache is disabled
+    pub fn add(&self, key: K, val: V) -> Vec<K> {
+        if !self.enabled {
+            return vec![key];
         }
-        self.data.write().insert(key.clone(), val);
+        let tstart = std::time::Instant::now();
+        let mut data = self.data.write();
         let mut count = self.count.write();
+        let mut evicted = vec![];
+        if !data.contains_key(&key) && data.len() >= self.size_limit {
+            evicted = self.make_space(&mut data, &mut count);
+        }
+        data.insert(key.clone(), val);
         if let Some(cnt) = count.get_mut(&key) {
             cnt.fetch_add(1, Ordering::Relaxed);
         } else {
             count.insert(key, AtomicUsize::new(1));
         }
-        self.tot_size.fetch_add(1, Ordering::Relaxed);
+        self.tot_size.store(data.len(), Ordering::Relaxed);
+        evicted
+    }
+
+    // Returns the keys removed from the cache
+    pub fn invalidate<F: Fn(&K) -> bool>(&self, f: F) -> Vec<K> {
+        let mut data = self.data.write();
+        let removed = data.keys().filter(|k| f(k)).cloned().collect::<Vec<K>>();
+        for key in removed.iter() {
+            data.remove(key);
+        }
+        self.count.write().retain(|k, _| !f(k));
+        self.tot_size.store(data.len(), Ordering::Relaxed);
+        removed
+    }
+
+    // Evicts the least accessed element, then halves the counts of the others
+    // so the accesses made long ago weigh less than the recent ones
+    fn make_space(&self, data: &mut HashMap<K, V>, count: &mut HashMap<K, AtomicUsize>) -> Vec<K> {
+        let least_used = count
+            .iter()
+            .min_by_key(|(_, cnt)| cnt.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone());
+        let Some(key) = least_used else {
+            return vec![];
+        };
+        data.remove(&key);
+        count.remove(&key);
+        for cnt in count.values_mut() {
+            *cnt.get_mut() /= 2;
+        }
+        vec![key]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_accessed_elements() {
+        let cache = Cache::empty(2);
+        assert!(cache.add("a", 1).is_empty());
+        assert!(cache.add("b", 2).is_empty());
+        cache.get(&"a");
+        assert_eq!(cache.add("c", 3), ["b"]);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+        assert!(cache.add("c", 4).is_empty());
+        assert_eq!(cache.invalidate(|k| *k == "c"), ["c"]);
+        assert_eq!(format!("{cache:?}"), "Cache { 1/2 elements, 3 accesses }");
     }
 
-    pub fn make_space(&self, size: usize) {
-        // TODO    Remove elements that are not used regularly
+    #[test]
+    fn keeps_nothing_once_disabled() {
+        let mut cache = Cache::empty(2);
+        cache.disable();
+        assert_eq!(cache.add("a", 1), ["a"]);
+        assert_eq!(cache.get(&"a"), None);
     }
 }
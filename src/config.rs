@@ -11,12 +11,22 @@ use tera::Context;
 use crate::errors::Errcode;
 use crate::page::PageType;
 use crate::routes::UploadEndpoint;
+use crate::storage::backend::Backend;
 use crate::storage::{ContextQuery, Storage};
 
 #[derive(Parser)]
 struct Arguments {
     #[arg(short, long)]
     config_file: PathBuf,
+
+    /// Reload data on every request and display detailed errors
+    #[arg(long)]
+    dev: bool,
+
+    /// Pack the local storage into a .tar.zst or .zip bundle, then exit
+    #[cfg(all(feature = "storage-bundle", feature = "storage-local"))]
+    #[arg(long, value_name = "BUNDLE")]
+    pack: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +36,11 @@ pub struct Config {
 
     pub server_port: u16,
     pub default_lang: String,
+
+    // Languages a page is looked for in when missing in the ones of the user,
+    // before the default one
+    #[serde(default)]
+    pub lang_fallback: Vec<String>,
     pub static_files_route: String,
 
     pub notification_template: String,
@@ -47,8 +62,39 @@ pub struct Config {
     #[serde(default)]
     pub redirections: HashMap<String, String>,
 
-    #[cfg(feature = "storage-local")]
-    pub local_storage: crate::storage::backend::local::LocalStorage,
+    pub storage: Backend,
+
+    // Backends serving given storage slugs (or slug prefixes ending with '*'),
+    // tried in order. Other slugs are served by the default storage.
+    #[serde(default)]
+    pub mounts: HashMap<String, Vec<Backend>>,
+
+    #[serde(default)]
+    pub dev_mode: bool,
+
+    // Number of storage answers kept in cache, the least used ones being dropped first
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+
+    // Secret showing the draft pages when given with "?preview_token=<token>"
+    #[serde(default)]
+    pub preview_token: Option<String>,
+
+    // Strip scripts and unknown tags from the pages written in HTML
+    #[serde(default)]
+    pub sanitize_html: bool,
+
+    // Invalidate cached data when storage files change, requires the fs-watcher feature
+    #[serde(default)]
+    pub watch_files: bool,
+
+    // Storage configured with the "local_storage" key of earlier versions
+    #[serde(skip)]
+    legacy_storage: bool,
+
+    #[cfg(all(feature = "storage-bundle", feature = "storage-local"))]
+    #[serde(skip)]
+    pub pack_bundle: Option<PathBuf>,
 }
 
 impl Config {
@@ -56,9 +102,17 @@ impl Config {
         let args = Arguments::parse();
         let config_str = std::fs::read_to_string(&args.config_file)
             .map_err(|e| Errcode::ConfigFileRead(Arc::new(e)))?;
-        let mut config: Config =
-            toml::from_str(&config_str).map_err(|e| Errcode::TomlDecode("config file", e))?;
+        let mut config =
+            Config::from_toml(&config_str).map_err(|e| Errcode::TomlDecode("config file", e))?;
         config.root = args.config_file.parent().unwrap().to_path_buf();
+        if config.root.as_os_str().is_empty() {
+            config.root = PathBuf::from(".");
+        }
+        config.dev_mode |= args.dev;
+        #[cfg(all(feature = "storage-bundle", feature = "storage-local"))]
+        {
+            config.pack_bundle = args.pack;
+        }
 
         let page_def_str = std::fs::read_to_string(config.root.join(&config.page_config))
             .map_err(|e| Errcode::ConfigFileRead(Arc::new(e)))?;
@@ -75,12 +129,37 @@ impl Config {
         Ok(config)
     }
 
+    // Configuration written with the keys of this version or of the earlier ones. The local
+    // storage was the only backend, configured by "local_storage" instead of "storage".
+    fn from_toml(config_str: &str) -> Result<Config, toml::de::Error> {
+        let mut table: toml::Table = toml::from_str(config_str)?;
+        let legacy_storage = match table.remove("local_storage") {
+            Some(toml::Value::Table(mut local)) if !table.contains_key("storage") => {
+                local.insert("backend".to_string(), "local".into());
+                table.insert("storage".to_string(), local.into());
+                true
+            }
+            _ => false,
+        };
+        let mut config: Config = table.try_into()?;
+        config.legacy_storage = legacy_storage;
+        Ok(config)
+    }
+
     pub fn setup_logging(&self) {
         let mut builder = env_logger::Builder::new();
         builder.filter_level(log::LevelFilter::Debug);
         builder.parse_env("RUST_LOG");
         builder.init();
         log::debug!("Logging started");
+        if self.dev_mode {
+            log::warn!("Running in dev mode, data is reloaded on every request");
+        }
+        if self.legacy_storage {
+            log::warn!(
+                "\"local_storage\" is deprecated, move it to \"storage\" with backend = \"local\""
+            );
+        }
     }
 
     pub fn get_default_headers(&self) -> DefaultHeaders {
@@ -96,6 +175,13 @@ impl Config {
         // .add((header::AGE, "0")),
     }
 
+    // Languages looked for once the ones of the user are exhausted
+    pub fn fallback_langs(&self) -> Vec<String> {
+        let mut langs = self.lang_fallback.clone();
+        langs.push(self.default_lang.clone());
+        langs
+    }
+
     pub async fn base_templating_context(&self, storage: &Storage) -> Result<Context, Errcode> {
         let mut ctxt = Context::new();
         ctxt.insert("default_lang", &self.default_lang);
@@ -109,8 +195,73 @@ impl Config {
             }
             let sq = qry.independant_query()?.unwrap();
             let val = storage.query(sq).await;
-            qry.insert_data(slug, &mut ctxt, val)?;
+            qry.insert_data(slug, &mut ctxt, val, None)?;
         }
         Ok(ctxt)
     }
 }
+
+#[cfg(test)]
+impl Config {
+    // Smallest configuration a backend can be initialized with, paths relative to the root
+    pub fn test(root: &std::path::Path, storage: Backend) -> Config {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            server_port = 8080
+            default_lang = "en"
+            static_files_route = "/static/"
+            notification_template = "notification.html"
+            page_config = "pages.toml"
+            "#,
+        )
+        .unwrap();
+        table.insert(
+            "storage".to_string(),
+            toml::Value::try_from(storage).unwrap(),
+        );
+        let mut config: Config = table.try_into().unwrap();
+        config.root = root.to_path_buf();
+        config
+    }
+}
+
+pub fn default_cache_size() -> usize {
+    1024
+}
+
+#[cfg(all(test, feature = "storage-local"))]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+        server_port = 8080
+        default_lang = "en"
+        static_files_route = "/static/"
+        notification_template = "notification.html"
+        page_config = "pages.toml"
+    "#;
+
+    #[test]
+    fn reads_the_legacy_local_storage() {
+        let legacy = format!("{BASE}\n[local_storage]\ndata_root = \"data\"");
+        let config = Config::from_toml(&legacy).unwrap();
+        assert!(matches!(config.storage, Backend::Local(_)));
+        assert!(config.legacy_storage);
+
+        let current = format!("{BASE}\n[storage]\nbackend = \"local\"\ndata_root = \"data\"");
+        let config = Config::from_toml(&current).unwrap();
+        assert!(matches!(config.storage, Backend::Local(_)));
+        assert!(!config.legacy_storage);
+
+        assert!(Config::from_toml(BASE).is_err());
+    }
+
+    #[test]
+    fn reads_the_cache_size() {
+        let storage = "[storage]\nbackend = \"local\"\ndata_root = \"data\"";
+        let config = Config::from_toml(&format!("{BASE}\n{storage}")).unwrap();
+        assert_eq!(config.cache_size, 1024);
+        let config = Config::from_toml(&format!("{BASE}\ncache_size = 64\n{storage}")).unwrap();
+        assert_eq!(config.cache_size, 64);
+    }
+}
@@ -9,13 +9,16 @@ use crate::{render::Render, storage::StorageErrorType};
 pub enum Errcode {
     // General
     FilesystemError(&'static str, Arc<std::io::Error>),
+    FileWatcher(String),
 
     // Configuration
     ConfigFileRead(Arc<std::io::Error>),
     MissingFormConfig(String, String),
 
     // Data extraction
-    ContentIdParsing(std::num::ParseIntError),
+    ContentIdParsing(String),
+    PageNumberParsing(String),
+    TaxonomyTermNotFound(String, String),
     ParameterNotInUrl,
 
     // Storage
@@ -27,6 +30,7 @@ pub enum Errcode {
     // Render
     RegisterTemplate(String),
     MarkdownRender(mdtrans::Errcode),
+    UnknownContentFormat(String),
 
     // Serialization
     TomlDecode(&'static str, toml::de::Error),
@@ -54,7 +58,10 @@ impl Errcode {
 impl From<Errcode> for HttpResponseBuilder {
     fn from(val: Errcode) -> Self {
         match val {
-            Errcode::ParameterNotInUrl => HttpResponse::NotFound(),
+            Errcode::ParameterNotInUrl
+            | Errcode::ContentIdParsing(_)
+            | Errcode::PageNumberParsing(_)
+            | Errcode::TaxonomyTermNotFound(..) => HttpResponse::NotFound(),
             Errcode::StorageError(e) => e.into(),
             _ => HttpResponse::InternalServerError(),
         }
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+
+use crate::page::PageMetadata;
+
+const TOML_FENCE: &str = "+++";
+const YAML_FENCE: &str = "---";
+
+// Separator of pages written before fenced frontmatter was supported
+const LEGACY_SPLIT: &str = "---";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FrontmatterError {
+    pub path: String,
+    // Line of the page the error is at, starting from 1
+    pub line: Option<usize>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for FrontmatterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.path, self.reason),
+            None => write!(f, "{}: {}", self.path, self.reason),
+        }
+    }
+}
+
+impl FrontmatterError {
+    fn new(path: &str, line: Option<usize>, reason: String) -> FrontmatterError {
+        FrontmatterError {
+            path: path.to_string(),
+            line,
+            reason,
+        }
+    }
+}
+
+// Lines of a text, with the offset of their start
+fn lines_with_offset(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line))
+    })
+}
+
+fn is_fence(line: &str, fence: &str) -> bool {
+    line.trim_end() == fence
+}
+
+// Frontmatter enclosed between two fence lines, with the line it starts at and the body after it
+fn fenced<'a>(
+    path: &str,
+    content: &'a str,
+    fence: &str,
+) -> Result<(&'a str, usize, &'a str), FrontmatterError> {
+    let mut lines = lines_with_offset(content);
+    let Some((_, first)) = lines.next() else {
+        return Err(FrontmatterError::new(
+            path,
+            Some(1),
+            "empty page".to_string(),
+        ));
+    };
+    for (offset, line) in lines {
+        if is_fence(line, fence) {
+            let frontmatter = &content[first.len()..offset];
+            return Ok((frontmatter, 2, &content[offset + line.len()..]));
+        }
+    }
+    Err(FrontmatterError::new(
+        path,
+        Some(1),
+        format!("{fence} frontmatter is never closed"),
+    ))
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count()
+}
+
+fn parse_toml(
+    path: &str,
+    frontmatter: &str,
+    first_line: usize,
+) -> Result<PageMetadata, FrontmatterError> {
+    toml::from_str(frontmatter).map_err(|e| {
+        let line = e.span().map(|s| first_line + line_of(frontmatter, s.start));
+        FrontmatterError::new(path, line, e.message().to_string())
+    })
+}
+
+fn parse_yaml(
+    path: &str,
+    frontmatter: &str,
+    first_line: usize,
+) -> Result<PageMetadata, FrontmatterError> {
+    // An empty YAML document is null rather than an empty map
+    if frontmatter.trim().is_empty() {
+        return Ok(PageMetadata::default());
+    }
+    serde_yaml::from_str(frontmatter).map_err(|e| {
+        let line = e.location().map(|l| first_line + l.line() - 1);
+        FrontmatterError::new(path, line, e.to_string())
+    })
+}
+
+fn parse_json<'a>(
+    path: &str,
+    content: &'a str,
+) -> Result<(PageMetadata, &'a str), FrontmatterError> {
+    let mut stream = serde_json::Deserializer::from_str(content).into_iter::<PageMetadata>();
+    match stream.next() {
+        Some(Ok(metadata)) => {
+            let body = &content[stream.byte_offset()..];
+            // The line holding the end of the JSON block isn't part of the body
+            let body = match body.split_once('\n') {
+                Some((rest, body)) if rest.trim().is_empty() => body,
+                _ => body,
+            };
+            Ok((metadata, body))
+        }
+        Some(Err(e)) => Err(FrontmatterError::new(path, Some(e.line()), e.to_string())),
+        None => Err(FrontmatterError::new(
+            path,
+            Some(1),
+            "empty page".to_string(),
+        )),
+    }
+}
+
+// Whether a page starts with a JSON object, rather than with text such as a template
+// tag that happens to start with a brace
+fn starts_with_json_object(content: &str) -> bool {
+    let mut stream = serde_json::Deserializer::from_str(content).into_iter::<serde_json::Value>();
+    matches!(stream.next(), Some(Ok(serde_json::Value::Object(_))))
+}
+
+// Whether the text before a legacy separator was meant as metadata, its first line
+// being a table header or a key set to a value
+fn looks_like_toml(text: &str) -> bool {
+    let Some(line) = text.lines().map(str::trim).find(|l| !l.is_empty()) else {
+        return false;
+    };
+    if line.starts_with('[') && line.ends_with(']') {
+        return true;
+    }
+    match line.split_once('=') {
+        Some((key, _)) => {
+            let key = key.trim();
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '"'))
+        }
+        None => false,
+    }
+}
+
+/// Metadata and body of a page, with the metadata as frontmatter fenced by `+++` for TOML,
+/// `---` for YAML, or as a leading `{...}` JSON block.
+/// Pages without frontmatter get the default metadata.
+pub fn parse_page_content(
+    path: &str,
+    content: &str,
+) -> Result<(PageMetadata, String), FrontmatterError> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    let first_line = content.lines().next().unwrap_or_default();
+
+    if is_fence(first_line, TOML_FENCE) {
+        let (frontmatter, line, body) = fenced(path, content, TOML_FENCE)?;
+        return Ok((parse_toml(path, frontmatter, line)?, body.to_string()));
+    }
+
+    if is_fence(first_line, YAML_FENCE) {
+        let (frontmatter, line, body) = fenced(path, content, YAML_FENCE)?;
+        return Ok((parse_yaml(path, frontmatter, line)?, body.to_string()));
+    }
+
+    if starts_with_json_object(content) {
+        let (metadata, body) = parse_json(path, content)?;
+        return Ok((metadata, body.to_string()));
+    }
+
+    // Unfenced TOML ended by a "---" line, as pages were written before
+    for (offset, line) in lines_with_offset(content) {
+        if is_fence(line, LEGACY_SPLIT) {
+            let frontmatter = &content[..offset];
+            match parse_toml(path, frontmatter, 1) {
+                Ok(metadata) => return Ok((metadata, content[offset + line.len()..].to_string())),
+                Err(e) if looks_like_toml(frontmatter) => return Err(e),
+                Err(_) => (),
+            }
+        }
+    }
+
+    Ok((PageMetadata::default(), content.to_string()))
+}
+
+/// Content of a page to write, with its metadata as TOML frontmatter
+pub fn format_page_content(metadata: &PageMetadata, body: &str) -> Result<String, String> {
+    let mut frontmatter = toml::to_string(metadata).map_err(|e| format!("{e:?}"))?;
+    if !frontmatter.is_empty() && !frontmatter.ends_with('\n') {
+        frontmatter.push('\n');
+    }
+    Ok(format!("{TOML_FENCE}\n{frontmatter}{TOML_FENCE}\n{body}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (PageMetadata, String) {
+        parse_page_content("page.md", content).unwrap()
+    }
+
+    fn title(metadata: &PageMetadata) -> &str {
+        metadata.metadata["title"].as_str().unwrap()
+    }
+
+    #[test]
+    fn parses_each_frontmatter_format() {
+        let (md, body) =
+            parse("+++\ntemplate = \"a.html\"\n[metadata]\ntitle = \"T\"\n+++\nbody\n");
+        assert_eq!((md.template.as_deref(), title(&md)), (Some("a.html"), "T"));
+        assert_eq!(body, "body\n");
+
+        let (md, body) = parse("---\nmetadata:\n  title: Y\n---\nbody\n");
+        assert_eq!((title(&md), body.as_str()), ("Y", "body\n"));
+
+        let (md, body) = parse("{\"metadata\": {\"title\": \"J\"}}\nbody\n");
+        assert_eq!((title(&md), body.as_str()), ("J", "body\n"));
+
+        let (md, body) = parse("[metadata]\ntitle = \"L\"\n---\nbody\n");
+        assert_eq!((title(&md), body.as_str()), ("L", "body\n"));
+    }
+
+    #[test]
+    fn keeps_pages_without_frontmatter() {
+        let (md, body) = parse("# Title\n\nSome text\n");
+        assert!(md.metadata.is_empty());
+        assert_eq!(body, "# Title\n\nSome text\n");
+
+        let (md, body) = parse("\u{feff}---\n---\nbody");
+        assert!(md.metadata.is_empty());
+        assert_eq!(body, "body");
+
+        let (md, body) = parse("{% extends \"base.html\" %}\n{{ title }}\n");
+        assert!(md.metadata.is_empty());
+        assert_eq!(body, "{% extends \"base.html\" %}\n{{ title }}\n");
+
+        let (md, body) = parse("Some text\n\n---\n\nMore text\n");
+        assert!(md.metadata.is_empty());
+        assert_eq!(body, "Some text\n\n---\n\nMore text\n");
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let err = parse_page_content("page.md", "+++\n[metadata]\ntitle = \n+++\n").unwrap_err();
+        assert_eq!((err.path.as_str(), err.line), ("page.md", Some(3)));
+
+        let err = parse_page_content("page.md", "+++\ntitle = 1\n").unwrap_err();
+        assert_eq!(err.line, Some(1));
+        assert!(err.reason.contains("never closed"));
+
+        let err = parse_page_content("page.md", "[metadata]\ntitle = T\n---\nbody\n").unwrap_err();
+        assert_eq!(err.line, Some(2));
+
+        let err = parse_page_content("page.md", "{\"metadata\": 1}\nbody\n").unwrap_err();
+        assert_eq!(err.line, Some(1));
+    }
+
+    #[test]
+    fn formats_back_to_the_same_metadata() {
+        let (md, _) = parse("+++\n[metadata]\ntitle = \"T\"\n+++\n");
+        let content = format_page_content(&md, "body\n").unwrap();
+        let (parsed, body) = parse(&content);
+        assert_eq!((title(&parsed), body.as_str()), ("T", "body\n"));
+    }
+}
@@ -7,11 +7,15 @@ use actix_web::{web::Data, App, HttpServer};
 mod cache;
 mod config;
 mod errors;
+mod frontmatter;
 mod page;
 mod render;
 mod routes;
 mod scss;
+mod search;
 mod storage;
+#[cfg(feature = "fs-watcher")]
+mod watcher;
 
 // TODO    IMPORTANT    For each unwrap of the codebase, add a comment on why it's safe
 //                      If not safe, handle the case where it could be None
@@ -21,17 +25,43 @@ async fn main() -> std::io::Result<()> {
     let config = Data::new(config::Config::load().expect("Unable to load server configuration"));
     config.setup_logging();
 
-    let storage = Data::new(storage::Storage::init(&config).expect("Unable to initialize storage"));
+    #[cfg(all(feature = "storage-bundle", feature = "storage-local"))]
+    if let Some(ref output) = config.pack_bundle {
+        storage::backend::bundle::pack(&config, output).expect("Unable to pack bundle");
+        log::info!("Packed storage into {output:?}");
+        return Ok(());
+    }
+
+    let storage = Data::new(
+        storage::Storage::init(config.storage.clone(), config.mounts.clone(), &config)
+            .expect("Unable to initialize storage"),
+    );
     let render = Data::new(
         render::Render::init(storage.clone().into_inner(), &config)
             .await
             .expect("Error while initializing render engine"),
     );
+
+    #[cfg(feature = "fs-watcher")]
+    let _watcher = if config.watch_files {
+        Some(
+            watcher::FsWatcher::start(storage.clone().into_inner(), render.clone().into_inner())
+                .expect("Unable to watch storage files"),
+        )
+    } else {
+        None
+    };
+    #[cfg(not(feature = "fs-watcher"))]
+    if config.watch_files {
+        log::warn!("Watching files requires the fs-watcher feature, ignoring");
+    }
+
     let base_context = config
         .base_templating_context(&storage)
         .await
         .expect("Unable to generate base context");
     let base_context = Data::new(base_context);
+    let search = Data::new(search::Search::init(&config));
 
     let port = config.server_port;
 
@@ -45,10 +75,11 @@ async fn main() -> std::io::Result<()> {
             .app_data(base_context.clone())
             .app_data(storage.clone())
             .app_data(render.clone())
+            .app_data(search.clone())
             .app_data(config.clone());
 
         app.configure(|app| {
-            routes::configure(&config, app);
+            routes::configure(&config, &storage, app);
         })
     });
 
@@ -6,18 +6,30 @@ use serde::{Deserialize, Serialize};
 use tera::Value;
 
 use crate::render::TemplateSlug;
-use crate::routes::ContentQueryMethod;
+use crate::routes::{ContentQueryMethod, TaxonomyOptions};
+use crate::search::SearchOptions;
 use crate::storage::{ContextQuery, StorageSlug};
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct PageMetadata {
-    // FIXME    u64 deserialization with toml
     #[serde(default)]
-    pub id: u64,
+    pub id: PageId,
 
+    // Explicit identifier of the page, hashed into its id instead of its path
+    #[serde(default)]
+    pub uuid: Option<String>,
+
+    // Id the page had before ids were derived from its path, to redirect old URLs
+    #[serde(skip)]
+    pub legacy_id: u64,
+
+    // Same as an unlisted visibility, kept for the pages written before it
     #[serde(default)]
     pub hidden: bool,
 
+    #[serde(default)]
+    pub visibility: Visibility,
+
     #[serde(default)]
     pub metadata: HashMap<String, serde_json::Value>,
 
@@ -29,6 +41,161 @@ pub struct PageMetadata {
 
     #[serde(default)]
     pub minify: bool,
+
+    // Renderer of the body, derived from the file extension if not set
+    #[serde(default)]
+    pub format: Option<String>,
+
+    // Stored as the index of a directory holding its assets, set by the storage
+    #[serde(skip)]
+    pub bundle: bool,
+
+    // Languages the page is written in, found by the storage when looked up by name
+    #[serde(skip)]
+    pub translations: Vec<String>,
+
+    // Language the page is written in, set by the storage when listing its pages
+    #[serde(skip)]
+    pub lang: Option<String>,
+
+    // The page is only served between these dates, as UNIX timestamps
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub publish_date: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub expire_date: Option<i64>,
+}
+
+/// Id of a page, kept in the form it was written in so templates link to it the same way.
+/// Written as an integer, a quoted integer, or a short base62 string.
+#[derive(Default, Clone, Debug)]
+pub struct PageId {
+    value: u64,
+    repr: Option<String>,
+}
+
+impl PageId {
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    // Short form of the id, as a base62 string unless it would be taken for a number
+    pub fn short(&self) -> String {
+        let short = to_base62(self.value);
+        if short.bytes().all(|c| c.is_ascii_digit()) {
+            self.value.to_string()
+        } else {
+            short
+        }
+    }
+}
+
+impl From<u64> for PageId {
+    fn from(value: u64) -> PageId {
+        PageId { value, repr: None }
+    }
+}
+
+impl std::str::FromStr for PageId {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<PageId, String> {
+        let value = parse_page_id(text).ok_or_else(|| format!("invalid page id {text:?}"))?;
+        Ok(PageId {
+            value,
+            repr: Some(text.to_string()),
+        })
+    }
+}
+
+impl PartialEq for PageId {
+    fn eq(&self, other: &PageId) -> bool {
+        self.value == other.value
+    }
+}
+
+impl PartialEq<u64> for PageId {
+    fn eq(&self, other: &u64) -> bool {
+        self.value == *other
+    }
+}
+
+impl std::fmt::Display for PageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.repr {
+            Some(ref repr) => f.write_str(repr),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+// TOML integers stop at i64::MAX, larger ids are written as strings
+impl Serialize for PageId {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match self.repr {
+            Some(ref repr) => ser.serialize_str(repr),
+            None => match i64::try_from(self.value) {
+                Ok(value) => ser.serialize_i64(value),
+                Err(_) => ser.serialize_str(&self.value.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PageIdRepr {
+    Number(u64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for PageId {
+    fn deserialize<D: serde::Deserializer<'de>>(deser: D) -> Result<PageId, D::Error> {
+        match PageIdRepr::deserialize(deser)? {
+            PageIdRepr::Number(value) => Ok(PageId::from(value)),
+            PageIdRepr::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn to_base62(mut value: u64) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(BASE62[(value % 62) as usize]);
+        value /= 62;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    // Safe to unwrap, all the digits are ASCII
+    String::from_utf8(digits).unwrap()
+}
+
+// Numeric value of an id written in decimal, or in base62 if it holds a letter
+pub fn parse_page_id(text: &str) -> Option<u64> {
+    if text.bytes().all(|c| c.is_ascii_digit()) {
+        return text.parse().ok();
+    }
+    text.bytes().try_fold(0u64, |value, c| {
+        let digit = BASE62.iter().position(|d| *d == c)? as u64;
+        value.checked_mul(62)?.checked_add(digit)
+    })
+}
+
+/// Who can see a page, and whether it is listed along with the others
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    // Reachable from its URL, but never listed
+    Unlisted,
+    // Only served in dev mode or with the preview token
+    Draft,
+    // Never served
+    Private,
 }
 
 impl PageMetadata {
@@ -59,7 +226,58 @@ impl PageMetadata {
         val
     }
 
-    pub fn update_id(&mut self, page_name: String) {
+    pub fn visibility(&self) -> Visibility {
+        if self.hidden && self.visibility == Visibility::Public {
+            Visibility::Unlisted
+        } else {
+            self.visibility
+        }
+    }
+
+    // Whether the page can be fetched from its name, slug or id
+    pub fn is_visible(&self, now: i64, drafts: bool) -> bool {
+        self.is_published(now)
+            && match self.visibility() {
+                Visibility::Public | Visibility::Unlisted => true,
+                Visibility::Draft => drafts,
+                Visibility::Private => false,
+            }
+    }
+
+    // Whether the page appears in the listings and metadata queries
+    pub fn is_listed(&self, now: i64, drafts: bool) -> bool {
+        self.is_published(now)
+            && match self.visibility() {
+                Visibility::Public => true,
+                Visibility::Draft => drafts,
+                Visibility::Unlisted | Visibility::Private => false,
+            }
+    }
+
+    pub fn is_published(&self, now: i64) -> bool {
+        self.publish_date.is_none_or(|date| date <= now)
+            && self.expire_date.is_none_or(|date| now < date)
+    }
+
+    pub fn set_default_format(&mut self, path: &str) {
+        if self.format.is_none() {
+            self.format = content_format_of(path).map(|f| f.to_string());
+        }
+    }
+
+    // Derives the id of the page from its uuid or its path, unless set explicitly
+    pub fn update_id(&mut self, page_path: &str) {
+        if self.id != 0 {
+            self.legacy_id = self.id.value();
+            return;
+        }
+        self.legacy_id = self.legacy_hash();
+        let key = self.uuid.as_deref().unwrap_or(page_path);
+        self.id = PageId::from(stable_hash(key.as_bytes()));
+    }
+
+    // Changes with any of the metadata, and isn't guaranteed to be the same across Rust versions
+    fn legacy_hash(&self) -> u64 {
         let mut s = DefaultHasher::new();
         s.write_u8(if self.hidden { 1 } else { 0 });
         let mut keys: Vec<&String> = self.metadata.keys().collect();
@@ -68,7 +286,76 @@ impl PageMetadata {
             s.write(key.as_bytes());
             hash_json(&mut s, self.metadata.get(key).unwrap());
         }
-        self.id = s.finish();
+        s.finish()
+    }
+}
+
+/// Extensions of the files holding pages, tried in this order when looking for one
+pub const PAGE_EXTENSIONS: [&str; 4] = ["md", "html", "htm", "txt"];
+
+// Format of the body of a page, from the extension of its file
+pub fn content_format_of(path: &str) -> Option<&'static str> {
+    let (_, ext) = path.rsplit_once('.')?;
+    match ext {
+        "md" => Some("markdown"),
+        "html" | "htm" => Some("html"),
+        "txt" => Some("text"),
+        _ => None,
+    }
+}
+
+/// Current time as a UNIX timestamp, compared to the publication dates of the pages
+pub fn timestamp_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+// Timestamp of a date written in RFC 3339, or as a date and time in UTC
+pub fn parse_date(text: &str) -> Option<i64> {
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Some(date.timestamp());
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(date) = chrono::NaiveDateTime::parse_from_str(text, fmt) {
+            return Some(date.and_utc().timestamp());
+        }
+    }
+    let day = chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(day.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+}
+
+// Timestamp of a date found in the metadata, TOML datetimes of the frontmatter being kept
+// as an object holding their written form
+pub fn metadata_date(val: &serde_json::Value) -> Option<i64> {
+    match val {
+        serde_json::Value::Number(n) => n.as_i64(),
+        serde_json::Value::String(s) => parse_date(s),
+        serde_json::Value::Object(o) if o.len() == 1 => parse_date(o.values().next()?.as_str()?),
+        _ => None,
+    }
+}
+
+// Dates are written as timestamps, strings or TOML datetimes depending on the frontmatter
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DateRepr {
+    Timestamp(i64),
+    Text(String),
+    Toml(toml::value::Datetime),
+}
+
+fn deserialize_date<'de, D>(deser: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = match Option::<DateRepr>::deserialize(deser)? {
+        None => return Ok(None),
+        Some(DateRepr::Timestamp(ts)) => return Ok(Some(ts)),
+        Some(DateRepr::Text(text)) => text,
+        Some(DateRepr::Toml(date)) => date.to_string(),
+    };
+    match parse_date(&text) {
+        Some(ts) => Ok(Some(ts)),
+        None => Err(serde::de::Error::custom(format!("invalid date {text:?}"))),
     }
 }
 
@@ -76,9 +363,15 @@ impl PageMetadata {
 pub struct PageType {
     pub route: String,
 
+    // Negotiate the language from the Accept-Language header of the requests
     #[serde(default)]
     pub lang_detect: bool,
 
+    // Also serve the route under "/<lang>" for every language of its storage,
+    // the unprefixed route redirecting to the negotiated language
+    #[serde(default)]
+    pub lang_prefix: bool,
+
     #[serde(default)]
     pub add_context: HashMap<String, ContextQuery>,
     pub default_template: TemplateSlug,
@@ -94,12 +387,47 @@ pub struct PageType {
 
     #[serde(default = "default_cache_max_age")]
     pub cache_max_age: u32,
+
+    // Redirects the ids pages had before being derived from their path to the current ones
+    #[serde(default)]
+    pub redirect_legacy_ids: bool,
+
+    // Pages found for the "?q=" parameter, given to the template as "search"
+    #[serde(default)]
+    pub search: Option<SearchOptions>,
+
+    // Metadata keys whose terms get an index page, and a page listing the pages of each term
+    #[serde(default)]
+    pub taxonomies: Vec<String>,
+
+    #[serde(default)]
+    pub taxonomy: TaxonomyOptions,
+
+    // Page types whose taxonomies also give their terms to the templates as "taxonomies",
+    // for sitemaps and feeds to link to the term pages
+    #[serde(default)]
+    pub taxonomies_of: Vec<String>,
 }
 
 pub fn default_cache_max_age() -> u32 {
     60 * 60 * 24
 }
 
+/// FNV-1a hash, unlike the hashers of the standard library it never changes
+pub fn stable_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Path of a page in its storage whatever its format, hashed into its id
+pub fn page_id_path(slug: &str, lang: Option<&str>, name: &str) -> String {
+    match lang {
+        Some(lang) => format!("{slug}/{lang}/{name}"),
+        None => format!("{slug}/{name}"),
+    }
+}
+
 pub fn hash_json(s: &mut DefaultHasher, val: &serde_json::Value) {
     match val {
         tera::Value::Null => s.write_u8(0),
@@ -117,6 +445,19 @@ pub fn hash_json(s: &mut DefaultHasher, val: &serde_json::Value) {
     }
 }
 
+pub fn compare_similar_md(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    if a.is_array() && b.is_array() {
+        return a == b;
+    }
+    if a.is_array() {
+        return a.as_array().unwrap().contains(b);
+    }
+    if b.is_array() {
+        return b.as_array().unwrap().contains(a);
+    }
+    a == b
+}
+
 // Implementation to compare values of metadata
 pub fn compare_tera_values(a: Option<&Value>, b: Option<&Value>) -> std::cmp::Ordering {
     match (a, b) {
@@ -131,13 +472,10 @@ pub fn compare_tera_values(a: Option<&Value>, b: Option<&Value>) -> std::cmp::Or
                     n.as_i64().unwrap().cmp(&b.as_i64().unwrap())
                 } else if n.is_u64() && b.is_u64() {
                     n.as_u64().unwrap().cmp(&b.as_u64().unwrap())
-                } else if n.is_f64() && b.is_f64() {
-                    n.as_f64()
-                        .unwrap()
-                        .partial_cmp(&b.as_f64().unwrap())
-                        .unwrap_or(std::cmp::Ordering::Equal)
                 } else {
-                    unreachable!()
+                    // Integers and floats mixed in the metadata are compared as floats
+                    let (a, b) = (n.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+                    a.total_cmp(&b)
                 }
             }
             tera::Value::String(s) if b.is_string() => s.cmp(&b.as_str().unwrap().to_string()),
@@ -171,17 +509,79 @@ pub fn compare_tera_values(a: Option<&Value>, b: Option<&Value>) -> std::cmp::Or
                     std::cmp::Ordering::Equal
                 }
             }
-            _ => std::cmp::Ordering::Greater,
+            // Values of different types are ordered by type, for the order to stay total
+            _ => value_type_rank(a).cmp(&value_type_rank(b)),
         },
     }
 }
 
-fn deserialize_id<'de, D>(deser: D) -> Result<u64, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    log::debug!("Custom deserialize");
-    let val = serde_json::Value::deserialize(deser)?;
-    log::debug!("Got id: {val}");
-    Ok(val.as_u64().unwrap_or(0))
+pub fn value_type_rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids_in_decimal_and_base62() {
+        assert_eq!(parse_page_id("0"), Some(0));
+        assert_eq!(parse_page_id("1234"), Some(1234));
+        assert_eq!(parse_page_id("A"), Some(10));
+        assert_eq!(parse_page_id("z"), Some(61));
+        assert_eq!(parse_page_id("10a"), Some(62 * 62 + 36));
+        assert_eq!(parse_page_id("LygHa16AHYF"), Some(u64::MAX));
+        assert_eq!(parse_page_id("LygHa16AHYG"), None);
+        assert_eq!(parse_page_id("18446744073709551616"), None);
+        assert_eq!(parse_page_id("a-b"), None);
+        assert_eq!(parse_page_id(""), None);
+    }
+
+    #[test]
+    fn keeps_ids_when_metadata_changes() {
+        let mut before: PageMetadata = toml::from_str("[metadata]\ntags = [\"rsut\"]").unwrap();
+        let mut after: PageMetadata = toml::from_str("[metadata]\ntags = [\"rust\"]").unwrap();
+        before.update_id("blog/hello");
+        after.update_id("blog/hello");
+        assert_eq!(before.id, after.id);
+        assert_ne!(before.legacy_id, after.legacy_id);
+
+        let mut moved: PageMetadata = toml::from_str("uuid = \"3f2c\"").unwrap();
+        let mut kept: PageMetadata = toml::from_str("uuid = \"3f2c\"").unwrap();
+        moved.update_id("blog/hello");
+        kept.update_id("blog/2024/hello");
+        assert_eq!(moved.id, kept.id);
+        assert_ne!(moved.id, before.id);
+    }
+
+    #[test]
+    fn shortens_ids_back_to_their_value() {
+        for value in [0, 61, 62, 3843, 1_000_000_007, u64::MAX] {
+            let id = PageId::from(value);
+            assert_eq!(parse_page_id(&id.short()), Some(value), "{value}");
+        }
+        // Base62 forms made only of digits would be read in decimal
+        assert_eq!(PageId::from(61).short(), "z");
+        assert_eq!(PageId::from(62).short(), "62");
+    }
+
+    #[test]
+    fn keeps_the_written_form_of_ids() {
+        let md: PageMetadata = toml::from_str("id = \"LygHa16AHYF\"").unwrap();
+        assert_eq!(md.id, u64::MAX);
+        assert_eq!(md.id.to_string(), "LygHa16AHYF");
+        let md: PageMetadata = toml::from_str("id = 42").unwrap();
+        assert_eq!(
+            toml::to_string(&md).unwrap().lines().next(),
+            Some("id = 42")
+        );
+        assert!(toml::from_str::<PageMetadata>("id = \"a-b\"").is_err());
+    }
 }
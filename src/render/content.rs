@@ -0,0 +1,469 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::errors::Errcode;
+
+static HEADING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<h([1-6])(\s[^>]*)?>(.*?)</h[1-6]\s*>").unwrap());
+static ID_ATTR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bid\s*=\s*["']([^"']*)["']"#).unwrap());
+// Quoted attribute values may hold brackets without ending the tag
+static TAG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?s)<(/?)([a-zA-Z][a-zA-Z0-9]*)\b((?:[^<>"']|"[^"]*"|'[^']*')*)>"#).unwrap()
+});
+static ATTR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"([a-zA-Z_:][-a-zA-Z0-9_:.]*)\s*=\s*("[^"]*"|'[^']*'|[^\s"'=<>`]+)"#).unwrap()
+});
+static COMMENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static UNSAFE_ELEMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?is)<(script|style|iframe|object|embed|noscript|template|textarea)\b.*?</(script|style|iframe|object|embed|noscript|template|textarea)\s*>",
+    )
+    .unwrap()
+});
+
+// Tags and attributes kept when sanitizing HTML, anything else is removed
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "article",
+    "b",
+    "blockquote",
+    "br",
+    "caption",
+    "code",
+    "dd",
+    "del",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "s",
+    "section",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+const ALLOWED_ATTRS: &[&str] = &[
+    "alt", "class", "colspan", "height", "href", "id", "lang", "rowspan", "src", "title", "width",
+];
+const URL_ATTRS: &[&str] = &["href", "src"];
+// Schemes of the URLs kept when sanitizing, relative URLs being kept as well
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Body of a page rendered to HTML
+pub struct RenderedContent {
+    pub body: String,
+    // Links to the sections of the body
+    pub nav: String,
+}
+
+/// Renders the body of a page written in a given format to HTML
+pub trait ContentRenderer: Send + Sync {
+    fn render(&self, content: String) -> Result<RenderedContent, Errcode>;
+}
+
+pub fn slugify_header(index: usize, text: &str) -> String {
+    format!(
+        "{index}-{}",
+        text.to_lowercase()
+            .replace(' ', "-")
+            .chars()
+            .filter(|c| *c == '-' || c.is_alphanumeric())
+            .collect::<String>()
+    )
+}
+
+//                    Level  Title   Slug
+pub fn render_nav(sections: Vec<(usize, String, String)>) -> String {
+    let mut nav = String::new();
+    let mut last_level = 0;
+    for (level, title, slug) in sections {
+        let link = format!("<a href=\"#{slug}\" class=\"h{level}\">{title}</a>");
+        if level == last_level {
+            nav += "<li>";
+            nav += link.as_str();
+            nav += "</li>";
+        }
+        if level > last_level {
+            if last_level > 0 {
+                nav += "<li>";
+            }
+            while level > last_level {
+                nav += "<ul><li>";
+                last_level += 1;
+            }
+            nav += link.as_str();
+            nav += "</li>";
+        }
+        if level < last_level {
+            while level < last_level {
+                nav += "</ul></li>";
+                last_level -= 1;
+            }
+            nav += "<li>";
+            nav += link.as_str();
+            nav += "</li>";
+        }
+        last_level = level;
+        nav += "\n";
+    }
+    while last_level > 1 {
+        nav += "</ul></li>";
+        last_level -= 1;
+    }
+    nav += "</ul>";
+    nav
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Text between tags can't open a tag that was not sanitized
+fn escape_brackets(text: &str) -> String {
+    text.replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn strip_tags(html: &str) -> String {
+    TAG.replace_all(html, "").trim().to_string()
+}
+
+// Sections of an HTML document from its headings, which get an id if they don't have one
+fn html_sections(html: &str) -> (String, Vec<(usize, String, String)>) {
+    let mut sections = vec![];
+    let body = HEADING.replace_all(html, |caps: &regex::Captures| {
+        // Safe to unwrap as the regex only matches a digit between 1 and 6
+        let level = caps[1].parse::<usize>().unwrap();
+        let attrs = caps.get(2).map(|a| a.as_str()).unwrap_or_default();
+        let title = strip_tags(&caps[3]);
+        let (slug, heading) = match ID_ATTR.captures(attrs) {
+            Some(id) => (id[1].to_string(), caps[0].to_string()),
+            None => {
+                let slug = slugify_header(sections.len(), &title);
+                let heading = format!("<h{level} id=\"{slug}\"{attrs}>{}</h{level}>", &caps[3]);
+                (slug, heading)
+            }
+        };
+        sections.push((level, title, slug));
+        heading
+    });
+    (body.to_string(), sections)
+}
+
+// Text of an attribute value as the browser reads it, with its character references decoded
+fn decode_char_refs(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded += &rest[..start];
+        rest = &rest[start + 1..];
+        let (c, len) = decode_char_ref(rest).unwrap_or(('&', 0));
+        decoded.push(c);
+        rest = &rest[len..];
+    }
+    decoded + rest
+}
+
+// Character referenced at the start of the text after a '&', with the length of the
+// reference, browsers not requiring the ending ';'
+fn decode_char_ref(text: &str) -> Option<(char, usize)> {
+    if let Some(num) = text.strip_prefix('#') {
+        let (radix, digits) = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => (16, hex),
+            None => (10, num),
+        };
+        let end = digits
+            .find(|c: char| !c.is_digit(radix))
+            .unwrap_or(digits.len());
+        let value = u32::from_str_radix(&digits[..end], radix).ok()?;
+        let c = char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+        let len = text.len() - digits.len() + end;
+        return Some((c, len + usize::from(text[len..].starts_with(';'))));
+    }
+    let end = text
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(text.len());
+    let c = match text[..end].to_lowercase().as_str() {
+        "colon" => ':',
+        "tab" => '\t',
+        "newline" => '\n',
+        "amp" => '&',
+        "sol" => '/',
+        "quot" => '"',
+        "apos" => '\'',
+        "lt" => '<',
+        "gt" => '>',
+        _ => return None,
+    };
+    Some((c, end + usize::from(text[end..].starts_with(';'))))
+}
+
+// Only relative URLs, and the ones of a few schemes that can't run scripts
+fn is_safe_url(url: &str) -> bool {
+    let url = decode_char_refs(url)
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_lowercase();
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => ALLOWED_SCHEMES.contains(&&url[..i]),
+        _ => true,
+    }
+}
+
+fn sanitize_tag(caps: &regex::Captures) -> Option<String> {
+    let closing = &caps[1];
+    let tag = caps[2].to_lowercase();
+    if !ALLOWED_TAGS.contains(&tag.as_str()) {
+        return None;
+    }
+    if !closing.is_empty() {
+        return Some(format!("</{tag}>"));
+    }
+
+    let mut attrs = String::new();
+    for attr in ATTR.captures_iter(&caps[3]) {
+        let name = attr[1].to_lowercase();
+        if !ALLOWED_ATTRS.contains(&name.as_str()) {
+            continue;
+        }
+        let val = attr[2].trim_matches(|c| c == '"' || c == '\'');
+        if URL_ATTRS.contains(&name.as_str()) && !is_safe_url(val) {
+            continue;
+        }
+        attrs += &format!(" {name}=\"{}\"", val.replace('"', "&quot;"));
+    }
+    Some(format!("<{tag}{attrs}>"))
+}
+
+/// Keep only the tags and attributes that cannot run scripts or alter the rest of the page
+pub fn sanitize_html(html: &str) -> String {
+    let html = COMMENT.replace_all(html, "");
+    let html = UNSAFE_ELEMENT.replace_all(&html, "");
+
+    let mut result = String::new();
+    let mut last = 0;
+    for caps in TAG.captures_iter(&html) {
+        // Safe to unwrap, the capture group 0 always exists
+        let m = caps.get(0).unwrap();
+        result += &escape_brackets(&html[last..m.start()]);
+        if let Some(tag) = sanitize_tag(&caps) {
+            result += &tag;
+        }
+        last = m.end();
+    }
+    result += &escape_brackets(&html[last..]);
+    result
+}
+
+// Whether a URL is relative to the page, rather than absolute or an anchor in it
+fn is_relative_url(url: &str) -> bool {
+    if url.is_empty() || url.starts_with(['/', '#', '?']) {
+        return false;
+    }
+    // A scheme is only made of these characters, and comes before any path separator
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme);
+    !scheme.is_some_and(|s| {
+        s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['+', '-', '.'].contains(&c))
+    })
+}
+
+/// Point the relative links and images of a page to files under the URL passed
+pub fn rewrite_relative_urls(html: &str, base_url: &str) -> String {
+    let base_url = escape_attr(base_url.trim_end_matches('/'));
+    TAG.replace_all(html, |caps: &regex::Captures| {
+        if !caps[1].is_empty() {
+            return caps[0].to_string();
+        }
+        // Only the values are replaced, the rest of the tag is kept as it was written
+        let tag = caps.get(3).unwrap();
+        let mut rewritten = format!("<{}", &caps[2]);
+        let mut last = 0;
+        for attr in ATTR.captures_iter(tag.as_str()) {
+            let quoted = &attr[2];
+            let val = quoted.trim_matches(|c| c == '"' || c == '\'');
+            if !URL_ATTRS.contains(&attr[1].to_lowercase().as_str()) || !is_relative_url(val) {
+                continue;
+            }
+            let val = val.trim_start_matches("./");
+            let quote = if quoted.starts_with('\'') { "'" } else { "\"" };
+            let span = attr.get(2).unwrap();
+            rewritten += &tag.as_str()[last..span.start()];
+            rewritten += &format!("{quote}{base_url}/{val}{quote}");
+            last = span.end();
+        }
+        rewritten += &tag.as_str()[last..];
+        rewritten += ">";
+        rewritten
+    })
+    .to_string()
+}
+
+// Text inserted in an attribute value can't end it, whatever its quotes
+fn escape_attr(text: &str) -> String {
+    escape_html(text)
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+/// Body written in HTML, inserted as it is
+pub struct HtmlRenderer {
+    sanitize: bool,
+}
+
+impl HtmlRenderer {
+    pub fn init(sanitize: bool) -> HtmlRenderer {
+        HtmlRenderer { sanitize }
+    }
+}
+
+impl ContentRenderer for HtmlRenderer {
+    fn render(&self, content: String) -> Result<RenderedContent, Errcode> {
+        let content = if self.sanitize {
+            sanitize_html(&content)
+        } else {
+            content
+        };
+        let (body, sections) = html_sections(&content);
+        Ok(RenderedContent {
+            body,
+            nav: render_nav(sections),
+        })
+    }
+}
+
+/// Body written as plain text, with paragraphs separated by blank lines
+pub struct TextRenderer {}
+
+impl TextRenderer {
+    pub fn init() -> TextRenderer {
+        TextRenderer {}
+    }
+}
+
+impl ContentRenderer for TextRenderer {
+    fn render(&self, content: String) -> Result<RenderedContent, Errcode> {
+        let content = content.replace("\r\n", "\n");
+        let body = content
+            .split("\n\n")
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("<p>{}</p>", escape_html(p).replace('\n', "<br/>")))
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(RenderedContent {
+            body,
+            nav: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_only_relative_url_values() {
+        let html = "<p>\n<img\n  src = './a.png'  alt=\"a.png\" />\
+            <a HREF=b/c.pdf title=\"x > y\">c</a><a href=\"/d\">d</a><a href=\"#e\">e</a></p>";
+        assert_eq!(
+            rewrite_relative_urls(html, "/blog/post/"),
+            "<p>\n<img\n  src = '/blog/post/a.png'  alt=\"a.png\" />\
+            <a HREF=\"/blog/post/b/c.pdf\" title=\"x > y\">c</a><a href=\"/d\">d</a><a href=\"#e\">e</a></p>"
+        );
+    }
+
+    #[test]
+    fn escapes_the_base_of_rewritten_urls() {
+        assert_eq!(
+            rewrite_relative_urls("<img src='a.png'>", "/p/x'y\"<z>&"),
+            "<img src='/p/x&#x27;y&quot;&lt;z&gt;&amp;/a.png'>"
+        );
+    }
+
+    #[test]
+    fn sanitize_removes_script_urls() {
+        for href in [
+            "javascript:alert(1)",
+            "&#106;avascript:alert(1)",
+            "&#x6A;avascript:alert(1)",
+            "&#106avascript:alert(1)",
+            "java&#x09;script:alert(1)",
+            "java&Tab;script:alert(1)",
+            "javascript&colon;alert(1)",
+            " JaVaScRiPt:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html,<script>alert(1)</script>",
+        ] {
+            let html = sanitize_html(&format!("<a href=\"{href}\">link</a>"));
+            assert_eq!(html, "<a>link</a>", "{href}");
+        }
+    }
+
+    #[test]
+    fn sanitize_keeps_safe_urls() {
+        for href in [
+            "https://example.com/a?b=c",
+            "http://example.com",
+            "mailto:me@example.com",
+            "/page",
+            "image.png",
+            "./dir/file:with-colon",
+            "#anchor",
+            "?q=a:b",
+        ] {
+            let html = sanitize_html(&format!("<a href=\"{href}\">link</a>"));
+            assert_eq!(html, format!("<a href=\"{href}\">link</a>"), "{href}");
+        }
+    }
+
+    #[test]
+    fn sanitize_quoted_brackets() {
+        let html = sanitize_html(r#"<img title="a>b" onerror=alert(1) src="x.png">"#);
+        assert_eq!(html, r#"<img title="a>b" src="x.png">"#);
+
+        let html = sanitize_html(r#"<img title='a>b' onerror="alert(1)">"#);
+        assert_eq!(html, r#"<img title="a>b">"#);
+    }
+
+    #[test]
+    fn sanitize_removes_unsafe_tags() {
+        let html =
+            sanitize_html("<p onclick=\"x()\">a<script>alert(1)</script><svg onload=x()>b</p>");
+        assert_eq!(html, "<p>ab</p>");
+    }
+}
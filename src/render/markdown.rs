@@ -4,7 +4,11 @@ use mdtrans::{transform_markdown_string, MarkdownTransformer};
 use syntect::html::{ClassStyle, ClassedHTMLGenerator};
 use syntect::parsing::SyntaxSet;
 use syntect::util::LinesWithEndings;
-use tera::{try_get_value, Context};
+use tera::try_get_value;
+
+use crate::errors::Errcode;
+
+use super::content::{render_nav, slugify_header, ContentRenderer, RenderedContent};
 
 pub fn markdown_render(
     val: &tera::Value,
@@ -39,63 +43,19 @@ impl MarkdownRenderer {
         }
         Ok(body)
     }
+}
 
-    pub fn render_to_ctxt(
-        &self,
-        content: String,
-        ctxt: &mut Context,
-    ) -> Result<(), mdtrans::Errcode> {
+impl ContentRenderer for MarkdownRenderer {
+    fn render(&self, content: String) -> Result<RenderedContent, Errcode> {
         let mut transformer = MarkdownToHtml::init();
         let mut body = transform_markdown_string(content, &mut transformer)?;
         if transformer.current_section > 0 {
             body += "</section>";
         }
-
-        let nav = self.render_nav(transformer.sections);
-        ctxt.insert("page_content", &body);
-        ctxt.insert("page_nav", &nav);
-        Ok(())
-    }
-
-    fn render_nav(&self, sections: Vec<(usize, String, String)>) -> String {
-        let mut nav = String::new();
-        let mut last_level = 0;
-        for (level, title, slug) in sections {
-            let link = format!("<a href=\"#{slug}\" class=\"h{level}\">{title}</a>");
-            if level == last_level {
-                nav += "<li>";
-                nav += link.as_str();
-                nav += "</li>";
-            }
-            if level > last_level {
-                if last_level > 0 {
-                    nav += "<li>";
-                }
-                while level > last_level {
-                    nav += "<ul><li>";
-                    last_level += 1;
-                }
-                nav += link.as_str();
-                nav += "</li>";
-            }
-            if level < last_level {
-                while level < last_level {
-                    nav += "</ul></li>";
-                    last_level -= 1;
-                }
-                nav += "<li>";
-                nav += link.as_str();
-                nav += "</li>";
-            }
-            last_level = level;
-            nav += "\n";
-        }
-        while last_level > 1 {
-            nav += "</ul></li>";
-            last_level -= 1;
-        }
-        nav += "</ul>";
-        nav
+        Ok(RenderedContent {
+            body,
+            nav: render_nav(transformer.sections),
+        })
     }
 }
 
@@ -122,15 +82,7 @@ impl MarkdownToHtml {
     }
 
     fn slugify_header(&self, text: &str) -> String {
-        format!(
-            "{}-{}",
-            self.sections.len(),
-            text.to_lowercase()
-                .replace(' ', "-")
-                .chars()
-                .filter(|c| *c == '-' || c.is_alphanumeric())
-                .collect::<String>()
-        )
+        slugify_header(self.sections.len(), text)
     }
 }
 
@@ -8,28 +8,44 @@ use crate::config::Config;
 use crate::errors::Errcode;
 use crate::storage::{Storage, StorageQuery};
 
+use self::content::{rewrite_relative_urls, ContentRenderer, HtmlRenderer, TextRenderer};
 use self::markdown::MarkdownRenderer;
 
 pub type TemplateSlug = String;
 
+// Format of the pages that don't set one
+pub const DEFAULT_CONTENT_FORMAT: &str = "markdown";
+
+pub mod content;
 mod markdown;
 
 pub struct Render {
     storage: Arc<Storage>,
     engine: Arc<RwLock<Tera>>,
-    markdown_render: MarkdownRenderer,
+    // Renderer of the page bodies, for each content format
+    renderers: HashMap<String, Arc<dyn ContentRenderer>>,
     notification_template: String,
+    dev_mode: bool,
 }
 
 impl Render {
     pub async fn init(storage: Arc<Storage>, cfg: &Config) -> Result<Render, Errcode> {
         let engine = Self::init_engine(&storage).await?;
-        Ok(Render {
+        let mut render = Render {
             storage,
             engine: Arc::new(RwLock::new(engine)),
-            markdown_render: MarkdownRenderer::init(),
+            renderers: HashMap::new(),
             notification_template: cfg.notification_template.clone(),
-        })
+            dev_mode: cfg.dev_mode,
+        };
+        render.register_renderer(DEFAULT_CONTENT_FORMAT, Arc::new(MarkdownRenderer::init()));
+        render.register_renderer("html", Arc::new(HtmlRenderer::init(cfg.sanitize_html)));
+        render.register_renderer("text", Arc::new(TextRenderer::init()));
+        Ok(render)
+    }
+
+    pub fn register_renderer(&mut self, format: &str, renderer: Arc<dyn ContentRenderer>) {
+        self.renderers.insert(format.to_string(), renderer);
     }
 
     pub async fn init_engine(storage: &Arc<Storage>) -> Result<Tera, Errcode> {
@@ -42,28 +58,58 @@ impl Render {
         Ok(engine)
     }
 
+    pub async fn reload_engine(&self) -> Result<(), Errcode> {
+        let engine = Self::init_engine(&self.storage).await?;
+        *self.engine.write() = engine;
+        Ok(())
+    }
+
     pub async fn render_content(
         &self,
         template: &str,
         body: String,
+        format: Option<&str>,
+        assets_url: Option<&str>,
         mut ctxt: Context,
     ) -> Result<String, Errcode> {
-        #[cfg(feature = "hot-reloading")]
-        {
-            *self.engine.write() = Self::init_engine(&self.storage).await?;
+        if self.dev_mode {
+            self.reload_engine().await?;
         }
 
-        self.markdown_render.render_to_ctxt(body, &mut ctxt)?;
+        let tstart = std::time::Instant::now();
+        let format = format.unwrap_or(DEFAULT_CONTENT_FORMAT);
+        let Some(renderer) = self.renderers.get(format) else {
+            return Err(Errcode::UnknownContentFormat(format.to_string()));
+        };
+        let mut content = renderer.render(body)?;
+        if let Some(url) = assets_url {
+            content.body = rewrite_relative_urls(&content.body, url);
+        }
+        ctxt.insert("page_content", &content.body);
+        ctxt.insert("page_nav", &content.nav);
         let result = self.engine.read().render(template, &ctxt)?;
+        if self.dev_mode {
+            log::info!("Rendered {template} in {:?}", tstart.elapsed());
+        }
         Ok(result)
     }
 
     pub async fn render_error(&self, err: &Errcode, ctxt: Context) -> String {
+        // Details about the error are only displayed in dev mode, they are logged otherwise
+        let msg = if self.dev_mode {
+            format!("{err:?}")
+        } else {
+            "An error occured while processing your request".to_string()
+        };
         match self
-            .render_notification("Error".to_string(), format!("{err:?}"), ctxt)
+            .render_notification("Error".to_string(), msg, ctxt)
             .await
         {
             Ok(body) => body,
+            Err(_) if !self.dev_mode => {
+                "<html><body><h1>Error</h1><p>Unable to display this page</p></body></html>"
+                    .to_string()
+            }
             Err(e) => {
                 format!(
                     "
@@ -87,9 +133,8 @@ impl Render {
         msg: String,
         mut ctxt: Context,
     ) -> Result<String, Errcode> {
-        #[cfg(feature = "hot-reloading")]
-        {
-            *self.engine.write() = Self::init_engine(&self.storage).await?;
+        if self.dev_mode {
+            self.reload_engine().await?;
         }
 
         ctxt.insert("notif_title", &title);
@@ -1,19 +1,43 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
 use actix_web::dev::{Path, Payload, Url};
-use actix_web::http::header;
-use actix_web::web::Data;
-use actix_web::{FromRequest, HttpRequest};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::web::{Data, Query};
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use tera::Context;
 
+use crate::config::Config;
 use crate::errors::Errcode;
+use crate::page::parse_page_id;
 use crate::render::Render;
+use crate::search::Search;
 use crate::storage::Storage;
 
+// Cookie remembering the language chosen by the user
+const LANG_COOKIE: &str = "lang";
+
 #[derive(Clone)]
 pub struct RequestArgs {
     pub uri: String,
-    pub lang: Option<Vec<String>>,
+    // Languages of the user, followed by the fallback ones of the config
+    pub lang: Vec<String>,
+    // Language given in the URL, either as a prefix of the route or with "?lang=<lang>"
+    pub url_lang: Option<String>,
+    // Language prefixing the route the request was made to
+    pub route_lang: Option<String>,
+    // Language of the cookie, then the ones of the Accept-Language header
+    cookie_lang: Option<String>,
+    accept_lang: Vec<String>,
+    fallback_lang: Vec<String>,
+    lang_detect: bool,
+    pub preview: Option<String>,
+    // Draft pages are served, in dev mode or with the preview token
+    pub drafts: bool,
     pub storage: Data<Storage>,
     pub render: Data<Render>,
+    pub search: Data<Search>,
     pub ctxt: Context,
     pub match_infos: Path<Url>,
 }
@@ -26,20 +50,100 @@ impl FromRequest for RequestArgs {
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
         let ctxt: Data<Context> = get_from_req(req);
         let mut ctxt = ctxt.get_ref().clone();
-        let lang = get_lang(req);
-        ctxt.insert("pref_langs", &lang);
-        std::future::ready(Ok(RequestArgs {
+        let config: Data<Config> = get_from_req(req);
+        let url_lang = get_query_param(req, "lang")
+            .filter(|lang| is_lang_tag(lang))
+            .map(|lang| lang.to_string());
+        let cookie_lang = req
+            .cookie(LANG_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .filter(|lang| is_lang_tag(lang));
+        let accept_lang = get_accept_lang(req);
+        let pref_langs = url_lang
+            .iter()
+            .chain(cookie_lang.iter())
+            .chain(accept_lang.iter())
+            .collect::<Vec<&String>>();
+        ctxt.insert("pref_langs", &pref_langs);
+        let preview = get_preview(req);
+        if let Some(ref preview) = preview {
+            ctxt.insert("preview", preview);
+        }
+        let drafts = config.dev_mode
+            || config
+                .preview_token
+                .as_ref()
+                .is_some_and(|token| has_preview_token(req, token));
+        let mut args = RequestArgs {
             uri: req.uri().to_string(),
             storage: get_from_req(req),
             render: get_from_req(req),
+            search: get_from_req(req),
             match_infos: req.match_info().clone(),
-            lang,
+            lang: vec![],
+            url_lang,
+            route_lang: None,
+            cookie_lang,
+            accept_lang,
+            fallback_lang: config.fallback_langs(),
+            lang_detect: true,
+            preview,
+            drafts,
             ctxt,
-        }))
+        };
+        args.negotiate_lang(true, None);
+        std::future::ready(Ok(args))
     }
 }
 
 impl RequestArgs {
+    // Order the languages to serve the data in: the one of the URL, then the one of the
+    // cookie, the ones of the Accept-Language header if detected, and the fallback ones
+    pub fn negotiate_lang(&mut self, lang_detect: bool, route_lang: Option<&str>) {
+        if let Some(lang) = route_lang {
+            self.url_lang = Some(lang.to_string());
+            self.route_lang = Some(lang.to_string());
+        }
+        self.lang_detect = lang_detect;
+        let accept_lang = self.accept_lang.iter().filter(|_| lang_detect);
+        let mut seen = HashSet::new();
+        self.lang = self
+            .url_lang
+            .iter()
+            .chain(self.cookie_lang.iter())
+            .chain(accept_lang)
+            .chain(self.fallback_lang.iter())
+            .filter(|lang| seen.insert(lang.as_str()))
+            .cloned()
+            .collect();
+    }
+
+    // Remember the language chosen in the URL, and tell caches what the language of
+    // the response depends on
+    pub fn set_lang_headers(&self, resp: &mut HttpResponse) {
+        if let Some(ref lang) = self.url_lang {
+            if self.cookie_lang.as_ref() != Some(lang) {
+                let cookie = Cookie::build(LANG_COOKIE, lang.clone())
+                    .path("/")
+                    .max_age(Duration::days(365))
+                    .same_site(SameSite::Lax)
+                    .http_only(true)
+                    .finish();
+                if let Err(e) = resp.add_cookie(&cookie) {
+                    log::warn!("Unable to set the language cookie: {e:?}");
+                }
+            }
+            return;
+        }
+        let vary = if self.lang_detect {
+            "Accept-Language, Cookie"
+        } else {
+            "Cookie"
+        };
+        resp.headers_mut()
+            .append(header::VARY, HeaderValue::from_static(vary));
+    }
+
     pub fn get_query_slug(&self, slug: &str) -> Result<String, Errcode> {
         if let Some(slug) = self.match_infos.get(slug) {
             Ok(slug.to_string())
@@ -48,50 +152,149 @@ impl RequestArgs {
         }
     }
 
+    // Number of the page of a listing to show, from a URL segment or a query parameter,
+    // the first one if missing
+    pub fn get_page_number(&self, param: &str) -> Result<usize, Errcode> {
+        let val = match self.match_infos.get(param) {
+            Some(val) => Some(val),
+            None => self
+                .uri
+                .split_once('?')
+                .and_then(|(_, query)| query_param(query, param)),
+        };
+        let Some(val) = val else {
+            return Ok(1);
+        };
+        val.parse::<usize>()
+            .ok()
+            .filter(|page| *page > 0)
+            .ok_or_else(|| Errcode::PageNumberParsing(val.to_string()))
+    }
+
+    // URL of another page of a listing, with its number in the same URL segment
+    // or query parameter
+    pub fn page_url(&self, param: &str, page: usize) -> String {
+        let (path, params) = self.uri.split_once('?').unwrap_or((&self.uri, ""));
+        let mut path = path.to_string();
+        let mut params = params
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>();
+        if let Some(current) = self.match_infos.get(param) {
+            let mut segments = path.split('/').collect::<Vec<&str>>();
+            let page = page.to_string();
+            if let Some(segment) = segments.iter_mut().rev().find(|s| **s == current) {
+                *segment = &page;
+            }
+            path = segments.join("/");
+        } else {
+            params.retain(|p| p.split('=').next() != Some(param));
+            if page > 1 {
+                params.push(format!("{param}={page}"));
+            }
+        }
+        if params.is_empty() {
+            path
+        } else {
+            format!("{path}?{}", params.join("&"))
+        }
+    }
+
+    // Decoded value of a query parameter
+    pub fn get_query_text(&self, name: &str) -> Option<String> {
+        let (_, query) = self.uri.split_once('?')?;
+        let params = Query::<HashMap<String, String>>::from_query(query).ok()?;
+        params.get(name).cloned()
+    }
+
+    // Ids are accepted in decimal or in their short base62 form
     pub fn get_query_id(&self, slug: &str) -> Result<u64, Errcode> {
         if let Some(id) = self.match_infos.get(slug) {
-            Ok(id.parse::<u64>().map_err(Errcode::ContentIdParsing)?)
+            parse_page_id(id).ok_or_else(|| Errcode::ContentIdParsing(id.to_string()))
         } else {
             Err(Errcode::ParameterNotInUrl)
         }
     }
 }
 
-pub fn get_lang(req: &HttpRequest) -> Option<Vec<String>> {
-    let mut res_langs = vec![];
-    if let Some(qry) = req.uri().query() {
-        for q in qry.split('&') {
-            let q = q.to_string();
-            let mut it = q.split('=');
-            let Some(key) = it.next() else {
-                continue;
+// Languages of the Accept-Language header by decreasing quality, each one followed by
+// its less specific ranges ("fr-ca" then "fr"), as in the lookup scheme of RFC 4647
+pub fn get_accept_lang(req: &HttpRequest) -> Vec<String> {
+    let Some(header) = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return vec![];
+    };
+    let mut ranges = header
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let tag = params.next()?.trim().to_lowercase();
+            let quality = match params.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => q.trim().parse::<f32>().ok()?,
+                None => 1.0,
             };
-            if key == "lang" {
-                let Some(val) = it.next() else {
-                    continue;
-                };
-                res_langs.push(val.to_string());
-            }
+            (quality > 0.0 && is_lang_tag(&tag)).then_some((tag, quality))
+        })
+        .collect::<Vec<(String, f32)>>();
+    // Stable sort, ranges of equal quality keep the order they were given in
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut langs = vec![];
+    for (tag, _) in ranges {
+        let mut range = tag.as_str();
+        langs.push(range.to_string());
+        while let Some((prefix, _)) = range.rsplit_once('-') {
+            range = prefix;
+            langs.push(range.to_string());
         }
     }
+    langs
+}
 
-    if let Some(langs) = req.headers().get(header::ACCEPT_LANGUAGE) {
-        res_langs.extend(langs.to_str().unwrap().split(',').map(|l| {
-            l.split(';')
-                .next()
-                .unwrap()
-                .split('-')
-                .next()
-                .unwrap()
-                .to_lowercase()
-        }));
-    }
-    res_langs.dedup();
-    if res_langs.is_empty() {
-        None
-    } else {
-        Some(res_langs)
-    }
+// Language tags are written to the cookie, anything else than letters, digits and
+// dashes is refused (including the "*" wildcard of the Accept-Language header)
+fn is_lang_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.len() <= 35 && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Revision of the storage data asked for with "?preview=<ref>"
+pub fn get_preview(req: &HttpRequest) -> Option<String> {
+    get_query_param(req, "preview").map(|val| val.to_string())
+}
+
+// Whether "?preview_token=<token>" is given, decoded and compared in constant time
+fn has_preview_token(req: &HttpRequest, token: &str) -> bool {
+    let Ok(params) = Query::<HashMap<String, String>>::from_query(req.query_string()) else {
+        return false;
+    };
+    params
+        .get("preview_token")
+        .is_some_and(|given| !given.is_empty() && same_secret(given.as_bytes(), token.as_bytes()))
+}
+
+// Takes the same time wherever the secrets differ, so they can't be guessed byte by byte
+fn same_secret(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len()
+        && given
+            .iter()
+            .zip(secret)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn get_query_param<'r>(req: &'r HttpRequest, name: &str) -> Option<&'r str> {
+    query_param(req.uri().query()?, name)
+}
+
+fn query_param<'q>(query: &'q str, name: &str) -> Option<&'q str> {
+    query.split('&').find_map(|q| {
+        let (key, val) = q.split_once('=')?;
+        (key == name && !val.is_empty()).then_some(val)
+    })
 }
 
 fn get_from_req<T: 'static>(req: &HttpRequest) -> Data<T> {
@@ -112,3 +315,57 @@ fn get_from_req<T: 'static>(req: &HttpRequest) -> Data<T> {
 //     "connection": "keep-alive"
 //     "accept-language": "fr,fr-FR;q=0.8,en-US;q=0.5,en;q=0.3"
 //     "upgrade-insecure-requests": "1"
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn accept_lang(header: &str) -> Vec<String> {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT_LANGUAGE, header))
+            .to_http_request();
+        get_accept_lang(&req)
+    }
+
+    #[test]
+    fn orders_languages_by_quality() {
+        assert_eq!(
+            accept_lang("en;q=0.5, fr-CA, de;q=0.8, *;q=0.1"),
+            ["fr-ca", "fr", "de", "en"]
+        );
+        assert_eq!(accept_lang("fr;q=0.8,en;q=0.8"), ["fr", "en"]);
+        assert_eq!(accept_lang("zh-Hant-TW"), ["zh-hant-tw", "zh-hant", "zh"]);
+    }
+
+    #[test]
+    fn ignores_refused_ranges() {
+        assert_eq!(accept_lang("fr;q=0, en;q=abc, de; q=0.2"), ["de"]);
+        assert_eq!(accept_lang("x\"y, <script>, it"), ["it"]);
+        assert!(accept_lang("").is_empty());
+        assert!(get_accept_lang(&TestRequest::default().to_http_request()).is_empty());
+    }
+
+    #[test]
+    fn decodes_the_preview_token() {
+        let token = "a+b/c=d";
+        let given =
+            |uri: &str| has_preview_token(&TestRequest::get().uri(uri).to_http_request(), token);
+        assert!(given("/page?preview_token=a%2Bb%2Fc%3Dd"));
+        assert!(given("/page?lang=fr&preview_token=a%2bb/c%3dd"));
+        assert!(!given("/page?preview_token=a+b/c=d"));
+        assert!(!given("/page?preview_token=a%2Bb%2Fc%3D"));
+        assert!(!given("/page?preview_token="));
+        assert!(!given("/page"));
+        assert!(same_secret(b"", b""));
+        assert!(!same_secret(b"abc", b"abd"));
+    }
+
+    #[test]
+    fn finds_query_parameters() {
+        assert_eq!(query_param("a=1&page=3&b=", "page"), Some("3"));
+        assert_eq!(query_param("a=1&b=", "b"), None);
+        assert_eq!(query_param("pages=3", "page"), None);
+    }
+}
@@ -1,18 +1,25 @@
 pub mod data_extract;
+mod page_assets;
 mod request_handler;
 mod static_files;
+mod taxonomy;
+#[cfg(all(test, feature = "storage-memory"))]
+mod tests;
 mod upload;
 
+pub use taxonomy::TaxonomyOptions;
 pub use upload::UploadEndpoint;
 
 use actix_web::web::{self, ServiceConfig};
 use request_handler::PageHandler;
 use serde::{Deserialize, Serialize};
+use taxonomy::TaxonomyHandler;
 
 use crate::{
     config::Config,
     errors::Errcode,
-    storage::{StorageQuery, StorageQueryMethod},
+    page::PageType,
+    storage::{Storage, StorageQuery, StorageQueryMethod},
 };
 
 pub use self::data_extract::RequestArgs;
@@ -28,7 +35,7 @@ pub enum ContentQueryMethod {
     // Get content slug from URL, with storage passed in parameter, has to be a str
     ContentSlug(String),
 
-    // Get content ID from URL, with storage passed in parameter, has to be a number
+    // Get content ID from URL, with storage passed in parameter, in decimal or base62
     ContentId(String),
 
     FromName(String),
@@ -54,17 +61,57 @@ impl ContentQueryMethod {
         };
         Ok(method.build_query(storage))
     }
+
+    // Whether the assets of a page bundle can be served under the route of the page
+    pub fn has_assets(&self) -> bool {
+        matches!(
+            self,
+            ContentQueryMethod::ContentSlug(_) | ContentQueryMethod::FromName(_)
+        )
+    }
+
+    pub fn build_asset_query(
+        &self,
+        storage: &String,
+        args: &RequestArgs,
+        asset: &str,
+    ) -> Result<StorageQuery, Errcode> {
+        let name = match self {
+            ContentQueryMethod::ContentSlug(ref slug) => args.get_query_slug(slug)?,
+            ContentQueryMethod::FromName(name) => name.clone(),
+            _ => return Err(Errcode::ParameterNotInUrl),
+        };
+        Ok(StorageQuery::page_asset(storage, name, asset.to_string()))
+    }
 }
 
-pub fn configure(cfg: &Config, app: &mut ServiceConfig) {
+pub fn configure(cfg: &Config, storage: &Storage, app: &mut ServiceConfig) {
     for (from, to) in cfg.redirections.iter() {
         app.service(web::redirect(from.clone(), to.clone()));
     }
 
+    // Registered first, so the routes of other pages can't shadow the language prefixes
+    for (_, ptype) in cfg.page_type.iter().filter(|(_, ptype)| ptype.lang_prefix) {
+        for lang in storage.supported_lang(&ptype.storage) {
+            configure_taxonomies(ptype, app, &format!("/{lang}"), vec![], Some(lang.clone()));
+            app.route(
+                &format!("/{lang}{}", ptype.route),
+                web::get()
+                    .to(PageHandler::create(ptype, vec![], Some(lang)).with_taxonomies_of(cfg)),
+            );
+        }
+    }
+
     for (_, ptype) in cfg.page_type.iter() {
+        let prefix_langs = if ptype.lang_prefix {
+            storage.supported_lang(&ptype.storage)
+        } else {
+            vec![]
+        };
+        configure_taxonomies(ptype, app, "", prefix_langs.clone(), None);
         app.route(
             ptype.route.as_str(),
-            web::get().to(PageHandler::create(ptype, &cfg.default_lang)),
+            web::get().to(PageHandler::create(ptype, prefix_langs, None).with_taxonomies_of(cfg)),
         );
     }
     upload::setup_routes(cfg, app);
@@ -75,4 +122,51 @@ pub fn configure(cfg: &Config, app: &mut ServiceConfig) {
         &static_endpoint,
         web::get().to(static_files::StaticFilesRoute::init(cfg)),
     );
+
+    // Registered last, so the routes of other pages below a page take precedence
+    for (_, ptype) in cfg.page_type.iter() {
+        if !ptype.content_query.has_assets() {
+            continue;
+        }
+        let assets_route = ptype.route.trim_end_matches('/').to_string() + "/{asset:.+}";
+        if ptype.lang_prefix {
+            for lang in storage.supported_lang(&ptype.storage) {
+                app.route(
+                    &format!("/{lang}{assets_route}"),
+                    web::get().to(page_assets::PageAssetsRoute::create(ptype, cfg, Some(lang))),
+                );
+            }
+        }
+        app.route(
+            &assets_route,
+            web::get().to(page_assets::PageAssetsRoute::create(ptype, cfg, None)),
+        );
+    }
+}
+
+// Routes of the index of each taxonomy and of its terms, before the route of the page type
+// as it would often match them
+fn configure_taxonomies(
+    ptype: &PageType,
+    app: &mut ServiceConfig,
+    prefix: &str,
+    prefix_langs: Vec<String>,
+    route_lang: Option<String>,
+) {
+    for taxonomy in ptype.taxonomies.iter() {
+        let index_route = prefix.to_string() + &ptype.taxonomy.index_route(ptype, taxonomy);
+        for (route, term_page) in [
+            (index_route.clone(), false),
+            (index_route + "/{term}", true),
+        ] {
+            let handler = TaxonomyHandler::create(
+                ptype,
+                taxonomy,
+                term_page,
+                prefix_langs.clone(),
+                route_lang.clone(),
+            );
+            app.route(&route, web::get().to(handler));
+        }
+    }
 }
@@ -0,0 +1,63 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::body::BoxBody;
+use actix_web::{Handler, HttpResponse};
+
+use crate::config::Config;
+use crate::page::PageType;
+
+use super::data_extract::RequestArgs;
+use super::static_files::serve_query;
+
+/// Serves the assets of a page bundle under the URL of the page
+#[derive(Clone)]
+pub struct PageAssetsRoute {
+    ptype: PageType,
+    dev_mode: bool,
+
+    // Language prefixing the route
+    route_lang: Option<String>,
+}
+
+impl PageAssetsRoute {
+    pub fn create(ptype: &PageType, cfg: &Config, route_lang: Option<String>) -> PageAssetsRoute {
+        PageAssetsRoute {
+            ptype: ptype.clone(),
+            dev_mode: cfg.dev_mode,
+            route_lang,
+        }
+    }
+}
+
+impl Handler<RequestArgs> for PageAssetsRoute {
+    type Output = HttpResponse<BoxBody>;
+
+    type Future = Pin<Box<dyn Future<Output = Self::Output>>>;
+
+    fn call(&self, mut args: RequestArgs) -> Self::Future {
+        args.negotiate_lang(self.ptype.lang_detect, self.route_lang.as_deref());
+        // Safe to unwrap, the route is always registered with an asset parameter
+        let asset = args.match_infos.get("asset").unwrap().to_string();
+        let content_query = &self.ptype.content_query;
+        let mut qry = match content_query.build_asset_query(&self.ptype.storage, &args, &asset) {
+            Ok(qry) => qry,
+            Err(e) => return Box::pin(e.build_http_response_from_data(args.render, args.ctxt)),
+        };
+
+        // Assets of a translated page are stored along with it
+        qry.set_lang(args.lang.clone());
+        if let Some(ref preview) = args.preview {
+            qry.set_revision(preview.clone());
+        }
+        if args.drafts {
+            qry.show_drafts();
+        }
+        let dev_mode = self.dev_mode;
+        Box::pin(async move {
+            let mut resp = serve_query(asset, qry, args.storage.clone(), dev_mode).await;
+            args.set_lang_headers(&mut resp);
+            resp
+        })
+    }
+}
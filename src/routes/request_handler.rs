@@ -1,22 +1,34 @@
 use actix_web::body::BoxBody;
+use actix_web::http::header;
 use actix_web::web::Data;
 use actix_web::{Handler, HttpResponse};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use tera::Context;
 
 use super::data_extract::RequestArgs;
+use super::taxonomy::insert_taxonomies;
+use crate::config::Config;
 use crate::errors::Errcode;
-use crate::page::{PageMetadata, PageType};
+use crate::page::{parse_page_id, PageMetadata, PageType};
 use crate::render::Render;
+use crate::storage::backend::supported_lang_of;
 use crate::storage::StorageQuery;
 use crate::storage::{ContextQuery, StorageQueryMethod};
 
 #[derive(Clone)]
 pub struct PageHandler {
     ptype: PageType,
-    default_lang: String,
+
+    // Languages the unprefixed route redirects to, for page types served under "/<lang>"
+    prefix_langs: Vec<String>,
+    // Language prefixing the route
+    route_lang: Option<String>,
+
+    // Page types listed in `taxonomies_of`
+    taxonomies_of: Vec<PageType>,
 }
 
 impl Handler<RequestArgs> for PageHandler {
@@ -25,9 +37,18 @@ impl Handler<RequestArgs> for PageHandler {
 
     // Function called every time we have a request to handle
     fn call(&self, mut args: RequestArgs) -> Self::Future {
+        args.negotiate_lang(self.ptype.lang_detect, self.route_lang.as_deref());
         log::debug!("Handling request with lang {:?}", args.lang);
-        if args.lang.is_none() {
-            args.lang = Some(vec![self.default_lang.clone()]);
+        let prefix_lang = args
+            .lang
+            .iter()
+            .find_map(|l| supported_lang_of(l, &self.prefix_langs));
+        if let Some(lang) = prefix_lang {
+            let mut resp = HttpResponse::Found()
+                .insert_header((header::LOCATION, format!("/{lang}{}", args.uri)))
+                .finish();
+            args.set_lang_headers(&mut resp);
+            return Box::pin(std::future::ready(resp));
         }
         let default_template = self.ptype.default_template.clone();
         let add_ctxt = self.ptype.add_context.clone();
@@ -47,23 +68,60 @@ impl Handler<RequestArgs> for PageHandler {
             Err(e) => return Box::pin(e.build_http_response_from_data(args.render, args.ctxt)),
         };
 
-        Box::pin(Self::respond(
-            storage_query,
-            add_ctxt,
-            add_headers,
-            default_template,
-            args,
-        ))
+        // Relative links of a page bundle point to its assets, served under the page route
+        let assets_url = if self.ptype.content_query.has_assets() {
+            args.uri.split('?').next().map(|path| path.to_string())
+        } else {
+            None
+        };
+
+        let ptype = self.ptype.clone();
+        let taxonomies_of = self.taxonomies_of.clone();
+        Box::pin(async move {
+            if let Err(e) = Self::insert_page_type_context(&ptype, &taxonomies_of, &mut args).await
+            {
+                return e
+                    .build_http_response_from_data(args.render, args.ctxt)
+                    .await;
+            }
+            Self::respond(
+                storage_query,
+                add_ctxt,
+                add_headers,
+                default_template,
+                assets_url,
+                ptype.redirect_legacy_ids,
+                args,
+            )
+            .await
+        })
     }
 }
 
 impl PageHandler {
     // Function called on initialization for each worker
-    pub fn create(ptype: &PageType, default_lang: &str) -> PageHandler {
+    pub fn create(
+        ptype: &PageType,
+        prefix_langs: Vec<String>,
+        route_lang: Option<String>,
+    ) -> PageHandler {
         PageHandler {
             ptype: ptype.clone(),
-            default_lang: default_lang.to_owned(),
+            prefix_langs,
+            route_lang,
+            taxonomies_of: vec![],
+        }
+    }
+
+    // Page types of the configuration named in `taxonomies_of`
+    pub fn with_taxonomies_of(mut self, cfg: &Config) -> PageHandler {
+        for name in self.ptype.taxonomies_of.iter() {
+            match cfg.page_type.get(name) {
+                Some(ptype) => self.taxonomies_of.push(ptype.clone()),
+                None => log::warn!("No page type {name} to list the taxonomies of"),
+            }
         }
+        self
     }
 
     pub async fn respond(
@@ -71,20 +129,94 @@ impl PageHandler {
         add_ctxt: HashMap<String, ContextQuery>,
         add_headers: HashMap<String, String>,
         default_template: String,
+        assets_url: Option<String>,
+        redirect_legacy_ids: bool,
         args: RequestArgs,
     ) -> HttpResponse<BoxBody> {
         // Fine tune content query
-        if let Some(ref lang) = args.lang {
-            qry.set_lang(lang.clone());
+        qry.set_lang(args.lang.clone());
+        if let Some(ref preview) = args.preview {
+            qry.set_revision(preview.clone());
+        }
+        if args.drafts {
+            qry.show_drafts();
+        }
+
+        let res =
+            Self::handle_request(qry.clone(), &args, add_ctxt, default_template, assets_url).await;
+        let mut resp = None;
+        if let Err(Errcode::StorageError(ref e)) = res {
+            if redirect_legacy_ids && e.is_not_found() {
+                if let Some(location) = Self::legacy_id_location(qry, &args).await {
+                    resp = Some(
+                        HttpResponse::MovedPermanently()
+                            .insert_header((header::LOCATION, location))
+                            .finish(),
+                    );
+                }
+            }
+        }
+        let mut resp = match resp {
+            Some(resp) => resp,
+            None => Self::build_response(args.render.clone(), add_headers, res, &args.ctxt).await,
+        };
+        args.set_lang_headers(&mut resp);
+        resp
+    }
+
+    // Pages found for the "?q=" parameter, and terms of the taxonomies of the page type
+    // and of the ones it lists the terms of
+    async fn insert_page_type_context(
+        ptype: &PageType,
+        taxonomies_of: &[PageType],
+        args: &mut RequestArgs,
+    ) -> Result<(), Errcode> {
+        if let Some(ref opts) = ptype.search {
+            let query = args.get_query_text("q").unwrap_or_default();
+            let results = args
+                .search
+                .search(&args.storage, opts, &args.lang, args.drafts, &query)
+                .await?;
+            args.ctxt.insert("search", &results);
+        }
+        let ptypes = std::iter::once(ptype)
+            .chain(taxonomies_of.iter())
+            .filter(|ptype| !ptype.taxonomies.is_empty())
+            .collect::<Vec<&PageType>>();
+        if !ptypes.is_empty() {
+            insert_taxonomies(&ptypes, args).await?;
         }
+        Ok(())
+    }
+
+    // URL of a page requested with the id it had before ids were derived from its path
+    async fn legacy_id_location(mut qry: StorageQuery, args: &RequestArgs) -> Option<String> {
+        let StorageQueryMethod::ContentNumId(id) = qry.method else {
+            return None;
+        };
+        qry.method = StorageQueryMethod::ContentLegacyId(id);
+        qry.update_key();
+        let (_, metadata, _) = args.storage.query(qry).await.page_content().ok()?;
 
-        Self::build_response(
-            args.render.clone(),
-            add_headers,
-            Self::handle_request(qry, &args, add_ctxt, default_template).await,
-            &args.ctxt,
-        )
-        .await
+        let (path, params) = match args.uri.split_once('?') {
+            Some((path, params)) => (path, Some(params)),
+            None => (args.uri.as_str(), None),
+        };
+        let path = path
+            .split('/')
+            .map(|part| {
+                if parse_page_id(part) == Some(id) {
+                    metadata.id.to_string()
+                } else {
+                    part.to_string()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("/");
+        Some(match params {
+            Some(params) => format!("{path}?{params}"),
+            None => path,
+        })
     }
 
     pub async fn handle_request(
@@ -92,6 +224,7 @@ impl PageHandler {
         args: &RequestArgs,
         add_ctxt: HashMap<String, ContextQuery>,
         default_template: String,
+        assets_url: Option<String>,
     ) -> Result<String, Errcode> {
         let mut ctxt = args.ctxt.clone();
         let (lang_opt, metadata, body) = if let StorageQueryMethod::NoOp = qry.method {
@@ -99,7 +232,11 @@ impl PageHandler {
         } else {
             let (l, md, b) = args.storage.query(qry.clone()).await.page_content()?;
             ctxt.insert("id", &md.id);
+            ctxt.insert("short_id", &md.id.short());
             ctxt.insert("metadata", &md.metadata);
+            let translations =
+                translations(&args.uri, args.route_lang.as_deref(), &md.translations);
+            ctxt.insert("translations", &translations);
             (l, md, b)
         };
 
@@ -119,7 +256,17 @@ impl PageHandler {
             &default_template
         };
 
-        let res = args.render.render_content(template, body, ctxt).await?;
+        let assets_url = assets_url.filter(|_| metadata.bundle);
+        let res = args
+            .render
+            .render_content(
+                template,
+                body,
+                metadata.format.as_deref(),
+                assets_url.as_deref(),
+                ctxt,
+            )
+            .await?;
 
         #[cfg(feature = "html_minify")]
         if metadata.minify {
@@ -152,6 +299,47 @@ impl PageHandler {
     }
 }
 
+// Language a page is written in, with its URL
+#[derive(Serialize)]
+struct Translation {
+    lang: String,
+    url: String,
+}
+
+// Links to the page in each language it is written in, for templates to render
+// a language switcher and hreflang links. Routes prefixed by a language get the other
+// prefixes, the others a "?lang=<lang>" parameter.
+fn translations(uri: &str, route_lang: Option<&str>, langs: &[String]) -> Vec<Translation> {
+    let (path, params) = uri.split_once('?').unwrap_or((uri, ""));
+    let params = params
+        .split('&')
+        .filter(|p| !p.is_empty() && p.split('=').next() != Some("lang"))
+        .collect::<Vec<&str>>();
+    let prefixed = route_lang.and_then(|lang| {
+        let rest = path.strip_prefix(&format!("/{lang}"))?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    });
+    langs
+        .iter()
+        .map(|lang| {
+            let url = match prefixed {
+                Some(rest) if params.is_empty() => format!("/{lang}{rest}"),
+                Some(rest) => format!("/{lang}{rest}?{}", params.join("&")),
+                None => {
+                    let mut params = params.clone();
+                    let lang_param = format!("lang={lang}");
+                    params.push(&lang_param);
+                    format!("{path}?{}", params.join("&"))
+                }
+            };
+            Translation {
+                lang: lang.clone(),
+                url,
+            }
+        })
+        .collect()
+}
+
 pub async fn insert_add_context(
     add_ctxt: &HashMap<String, ContextQuery>,
     page_md: &PageMetadata,
@@ -165,11 +353,16 @@ pub async fn insert_add_context(
         }
 
         if let Some(mut qry) = context_query.get_storage_query(args, page_md)? {
-            if let Some(ref lang) = args.lang {
-                qry.set_lang(lang.clone());
+            qry.set_lang(args.lang.clone());
+            if let Some(ref preview) = args.preview {
+                qry.set_revision(preview.clone());
+            }
+            if args.drafts {
+                qry.show_drafts();
             }
 
-            context_query.insert_data(name, ctxt, args.storage.query(qry).await)?;
+            let data = context_query.run_query(qry, args).await?;
+            context_query.insert_data(name, ctxt, data, Some(args))?;
         }
     }
     Ok(())
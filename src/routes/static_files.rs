@@ -13,29 +13,55 @@ use crate::storage::{Storage, StorageQuery};
 use super::data_extract::RequestArgs;
 
 #[derive(Clone)]
-pub struct StaticFilesRoute;
+pub struct StaticFilesRoute {
+    dev_mode: bool,
+}
+
 impl StaticFilesRoute {
     pub fn init(cfg: &Config) -> StaticFilesRoute {
-        StaticFilesRoute
+        StaticFilesRoute {
+            dev_mode: cfg.dev_mode,
+        }
     }
 
-    pub async fn serve_file(fname: String, storage: Data<Storage>) -> HttpResponse<BoxBody> {
-        let mime = mime_guess::from_path(&fname).first_or_octet_stream();
-
-        let qry = StorageQuery::static_file(fname);
-        match storage.query(qry).await.static_file() {
-            Ok(data) => HttpResponse::Ok()
-                .insert_header(header::ContentType(mime))
-                .insert_header(header::CacheControl(vec![header::CacheDirective::MaxAge(
-                    default_cache_max_age(),
-                )]))
-                .body(data),
-            Err(e) => {
-                log::warn!("Unable to get file: {e:?}");
-                let msg = format!("{e:?}");
-                let mut err: HttpResponseBuilder = e.into();
-                err.body(msg)
-            }
+    pub async fn serve_file(
+        fname: String,
+        preview: Option<String>,
+        storage: Data<Storage>,
+        dev_mode: bool,
+    ) -> HttpResponse<BoxBody> {
+        let mut qry = StorageQuery::static_file(fname.clone());
+        if let Some(preview) = preview {
+            qry.set_revision(preview);
+        }
+        serve_query(fname, qry, storage, dev_mode).await
+    }
+}
+
+// Reply with the file returned by the storage, its type guessed from its name
+pub async fn serve_query(
+    fname: String,
+    qry: StorageQuery,
+    storage: Data<Storage>,
+    dev_mode: bool,
+) -> HttpResponse<BoxBody> {
+    let mime = mime_guess::from_path(&fname).first_or_octet_stream();
+    match storage.query(qry).await.static_file_body() {
+        Ok(data) => HttpResponse::Ok()
+            .insert_header(header::ContentType(mime))
+            .insert_header(header::CacheControl(vec![header::CacheDirective::MaxAge(
+                default_cache_max_age(),
+            )]))
+            .body(data),
+        Err(e) => {
+            log::warn!("Unable to get file: {e:?}");
+            let msg = if dev_mode {
+                format!("{e:?}")
+            } else {
+                String::new()
+            };
+            let mut err: HttpResponseBuilder = e.into();
+            err.body(msg)
         }
     }
 }
@@ -48,6 +74,11 @@ impl Handler<RequestArgs> for StaticFilesRoute {
     fn call(&self, args: RequestArgs) -> Self::Future {
         // TODO    Add caching headers to request
         let fname = args.match_infos.get("filename").unwrap();
-        Box::pin(Self::serve_file(fname.to_string(), args.storage))
+        Box::pin(Self::serve_file(
+            fname.to_string(),
+            args.preview,
+            args.storage,
+            self.dev_mode,
+        ))
     }
 }
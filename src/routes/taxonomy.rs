@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::body::BoxBody;
+use actix_web::{Handler, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Errcode;
+use crate::page::{stable_hash, PageType};
+use crate::render::TemplateSlug;
+use crate::storage::{
+    query_listing_page, ContextQuery, QueryListOptions, StorageQuery, StorageSlug,
+};
+
+use super::data_extract::RequestArgs;
+use super::request_handler::PageHandler;
+use super::ContentQueryMethod;
+
+/// Pages generated for the taxonomies of a page type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaxonomyOptions {
+    // Route the taxonomies are served under, the start of the route of the page type
+    // before its first parameter if not set
+    #[serde(default)]
+    pub route: Option<String>,
+
+    // Index of the terms of a taxonomy, given "taxonomy" and "terms"
+    #[serde(default = "default_index_template")]
+    pub index_template: TemplateSlug,
+
+    // Listing of the pages of a term, given "taxonomy", "terms", "term" and "pages"
+    #[serde(default = "default_term_template")]
+    pub term_template: TemplateSlug,
+
+    #[serde(default)]
+    pub list: QueryListOptions,
+}
+
+impl Default for TaxonomyOptions {
+    fn default() -> TaxonomyOptions {
+        TaxonomyOptions {
+            route: None,
+            index_template: default_index_template(),
+            term_template: default_term_template(),
+            list: QueryListOptions::default(),
+        }
+    }
+}
+
+fn default_index_template() -> TemplateSlug {
+    "taxonomy".to_string()
+}
+
+fn default_term_template() -> TemplateSlug {
+    "taxonomy_term".to_string()
+}
+
+impl TaxonomyOptions {
+    // Route of the index of a taxonomy, its terms being served below it
+    pub fn index_route(&self, ptype: &PageType, taxonomy: &str) -> String {
+        let base = match self.route {
+            Some(ref route) => route.as_str(),
+            None => ptype.route.split('{').next().unwrap_or_default(),
+        };
+        format!("{}/{taxonomy}", base.trim_end_matches('/'))
+    }
+}
+
+/// Value of a taxonomy found in the metadata of the pages, with the number of pages having it
+#[derive(Serialize)]
+pub struct Term {
+    pub name: String,
+    pub slug: String,
+    pub count: usize,
+    pub url: String,
+    #[serde(skip)]
+    value: serde_json::Value,
+}
+
+/// Serves the index of the terms of a taxonomy, or the listing of the pages of a term
+#[derive(Clone)]
+pub struct TaxonomyHandler {
+    page: PageHandler,
+    storage: StorageSlug,
+    taxonomy: String,
+    index_route: String,
+    list: QueryListOptions,
+    // Serves the pages of the term in the "{term}" parameter of the route
+    term_page: bool,
+}
+
+impl TaxonomyHandler {
+    pub fn create(
+        ptype: &PageType,
+        taxonomy: &str,
+        term_page: bool,
+        prefix_langs: Vec<String>,
+        route_lang: Option<String>,
+    ) -> TaxonomyHandler {
+        let opts = &ptype.taxonomy;
+        let mut page_type = ptype.clone();
+        page_type.content_query = ContentQueryMethod::EmptyContent;
+        page_type.search = None;
+        page_type.default_template = if term_page {
+            opts.term_template.clone()
+        } else {
+            opts.index_template.clone()
+        };
+        TaxonomyHandler {
+            page: PageHandler::create(&page_type, prefix_langs, route_lang),
+            storage: ptype.storage.clone(),
+            taxonomy: taxonomy.to_string(),
+            index_route: opts.index_route(ptype, taxonomy),
+            list: opts.list.clone(),
+            term_page,
+        }
+    }
+
+    async fn insert_context(&self, args: &mut RequestArgs) -> Result<(), Errcode> {
+        let terms = taxonomy_terms(args, &self.storage, &self.taxonomy, &self.index_route).await?;
+        if self.term_page {
+            let slug = args.get_query_slug("term")?;
+            // Terms stay reachable from their suffixed slug once their collision is gone
+            let Some(term) = terms
+                .iter()
+                .find(|t| t.slug == slug || suffixed_slug(&t.name) == slug)
+            else {
+                return Err(Errcode::TaxonomyTermNotFound(self.taxonomy.clone(), slug));
+            };
+
+            let keys = vec![self.taxonomy.clone()];
+            let qry = StorageQuery::similar_pages(
+                &self.storage,
+                (keys.clone(), Some(term.value.clone())),
+                &self.list,
+            );
+            let data = query_listing_page(tune_query(qry, args), &self.list, args).await?;
+            let listing = ContextQuery::SimilarPagesFromMetadata(
+                self.storage.clone(),
+                keys,
+                self.list.clone(),
+            );
+            let mut ctxt = args.ctxt.clone();
+            listing.insert_data(&"pages".to_string(), &mut ctxt, data, Some(args))?;
+            ctxt.insert("term", term);
+            args.ctxt = ctxt;
+        }
+        args.ctxt.insert("taxonomy", &self.taxonomy);
+        args.ctxt.insert("terms", &terms);
+        Ok(())
+    }
+}
+
+impl Handler<RequestArgs> for TaxonomyHandler {
+    type Output = HttpResponse<BoxBody>;
+    type Future = Pin<Box<dyn Future<Output = Self::Output>>>;
+
+    fn call(&self, mut args: RequestArgs) -> Self::Future {
+        let handler = self.clone();
+        Box::pin(async move {
+            if let Err(e) = handler.insert_context(&mut args).await {
+                return e
+                    .build_http_response_from_data(args.render, args.ctxt)
+                    .await;
+            }
+            handler.page.call(args).await
+        })
+    }
+}
+
+// Terms of every taxonomy of page types, given to the templates as "taxonomies"
+pub async fn insert_taxonomies(
+    ptypes: &[&PageType],
+    args: &mut RequestArgs,
+) -> Result<(), Errcode> {
+    let mut taxonomies = HashMap::new();
+    for ptype in ptypes {
+        for taxonomy in ptype.taxonomies.iter() {
+            let index_route = ptype.taxonomy.index_route(ptype, taxonomy);
+            let terms = taxonomy_terms(args, &ptype.storage, taxonomy, &index_route).await?;
+            taxonomies.insert(taxonomy, terms);
+        }
+    }
+    args.ctxt.insert("taxonomies", &taxonomies);
+    Ok(())
+}
+
+// Terms of a taxonomy in the listed pages, the most used first
+async fn taxonomy_terms(
+    args: &RequestArgs,
+    storage: &StorageSlug,
+    taxonomy: &str,
+    index_route: &str,
+) -> Result<Vec<Term>, Errcode> {
+    let qry = StorageQuery::query_metadata(storage, (vec![], None), vec![taxonomy.to_string()]);
+    let values = args
+        .storage
+        .query(tune_query(qry, args))
+        .await
+        .query_metadata()?;
+
+    let mut counts: HashMap<String, (serde_json::Value, usize)> = HashMap::new();
+    for value in values {
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            serde_json::Value::Null => continue,
+            value => vec![value],
+        };
+        for value in values {
+            let name = match value {
+                serde_json::Value::String(ref s) => s.clone(),
+                ref value => value.to_string(),
+            };
+            counts.entry(name).or_insert((value, 0)).1 += 1;
+        }
+    }
+
+    // Terms are linked under the language prefixing the route
+    let prefix = match args.route_lang {
+        Some(ref lang) => format!("/{lang}"),
+        None => String::new(),
+    };
+    let counts = counts
+        .into_iter()
+        .filter(|(name, _)| !term_slug(name).is_empty())
+        .collect::<Vec<(String, (serde_json::Value, usize))>>();
+    let slugs = term_slugs(counts.iter().map(|(name, _)| name.as_str()));
+    let mut terms = counts
+        .into_iter()
+        .zip(slugs)
+        .map(|((name, (value, count)), slug)| Term {
+            url: format!("{prefix}{index_route}/{slug}"),
+            name,
+            slug,
+            count,
+            value,
+        })
+        .collect::<Vec<Term>>();
+    terms.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    Ok(terms)
+}
+
+fn tune_query(mut qry: StorageQuery, args: &RequestArgs) -> StorageQuery {
+    qry.set_lang(args.lang.clone());
+    if let Some(ref preview) = args.preview {
+        qry.set_revision(preview.clone());
+    }
+    if args.drafts {
+        qry.show_drafts();
+    }
+    qry
+}
+
+// Lowercased alphanumeric words of a term joined by dashes, to be used in its URL
+fn term_slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+// Slug of a term followed by a hash of its name, the same whatever the other terms are
+fn suffixed_slug(name: &str) -> String {
+    let hash = stable_hash(name.as_bytes()) & 0xff_ffff;
+    format!("{}-{hash:06x}", term_slug(name))
+}
+
+// Slugs of terms. Terms written differently may have the same slug, such as "C" and "C++",
+// those get the suffixed slug, except the one written as its slug in any case.
+fn term_slugs<'a>(names: impl Iterator<Item = &'a str> + Clone) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for name in names.clone() {
+        *counts.entry(term_slug(name)).or_default() += 1;
+    }
+    names
+        .map(|name| {
+            let slug = term_slug(name);
+            match counts[&slug] > 1 && name.to_lowercase() != slug {
+                true => suffixed_slug(name),
+                false => slug,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_each_term_its_own_slug() {
+        let names = ["C", "C#", "C++", "Rust lang", "c 2"];
+        let slugs = term_slugs(names.into_iter());
+        assert_eq!(slugs[0], "c");
+        assert_eq!(slugs[1], suffixed_slug("C#"));
+        assert_eq!(slugs[2], suffixed_slug("C++"));
+        assert_eq!(slugs[3..], ["rust-lang", "c-2"]);
+        assert!(slugs[1].starts_with("c-") && slugs[1] != slugs[2]);
+
+        // The suffix of a term only depends on its name, not on the other terms
+        let names = ["C++", "C#"];
+        let before = term_slugs(names.into_iter());
+        let names = ["c 2", "C#", "C++", "Go"];
+        let after = term_slugs(names.into_iter());
+        assert_eq!(before, [after[2].as_str(), after[1].as_str()]);
+        assert_eq!(term_slugs(["C++"].into_iter()), ["c"]);
+    }
+}
@@ -0,0 +1,474 @@
+// Requests served end to end by the routes of a site stored in memory
+
+use std::collections::HashMap;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use actix_web::App;
+use tera::Context;
+
+use crate::config::Config;
+use crate::page::{page_id_path, timestamp_now, PageMetadata};
+use crate::render::Render;
+use crate::search::Search;
+use crate::storage::backend::memory::MemoryStorage;
+use crate::storage::backend::Backend;
+use crate::storage::Storage;
+
+const CONFIG: &str = r#"
+server_port = 8080
+default_lang = "en"
+lang_fallback = ["fr"]
+static_files_route = "/static/"
+notification_template = "notification.html"
+page_config = "pages.toml"
+storage = { backend = "memory" }
+
+[page_type.index]
+route = "/"
+storage = "blog"
+default_template = "list.html"
+
+[page_type.index.add_context.recent]
+query = "recent_pages"
+args = ["blog", { sort_by = ["title"], rev_sort = true, limit = 2, page_param = "page" }]
+
+[page_type.index.add_context.site]
+query = "query_context"
+args = ["blog", "site"]
+
+[page_type.archive]
+route = "/archive/{p}"
+storage = "blog"
+default_template = "archive.html"
+
+[page_type.archive.add_context.recent]
+query = "recent_pages"
+args = ["blog", { sort_by = ["title"], rev_sort = true, limit = 2, page_param = "p" }]
+
+[page_type.post]
+route = "/blog/{slug}"
+storage = "blog"
+default_template = "page.html"
+content_query = { method = "content_slug", args = "slug" }
+taxonomies = ["tags"]
+taxonomy.list = { sort_by = ["title"], rev_sort = true, limit = 1, page_param = "page" }
+
+[page_type.sitemap]
+route = "/sitemap.xml"
+storage = "blog"
+default_template = "sitemap.xml"
+taxonomies_of = ["post"]
+
+[page_type.doc]
+route = "/docs/{slug}"
+storage = "docs"
+lang_detect = true
+default_template = "page.html"
+content_query = { method = "content_slug", args = "slug" }
+
+[page_type.short]
+route = "/p/{id}"
+storage = "blog"
+default_template = "page.html"
+content_query = { method = "content_id", args = "id" }
+
+[page_type.short_doc]
+route = "/d/{id}"
+storage = "docs"
+default_template = "page.html"
+content_query = { method = "content_id", args = "id" }
+"#;
+
+struct Site {
+    config: Data<Config>,
+    storage: Data<Storage>,
+    render: Data<Render>,
+    search: Data<Search>,
+    base_context: Data<Context>,
+}
+
+impl Site {
+    async fn new(mem: MemoryStorage) -> Site {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let storage = Storage::init(Backend::Memory(mem), HashMap::new(), &config).unwrap();
+        let storage = Data::new(storage);
+        let render = Render::init(storage.clone().into_inner(), &config)
+            .await
+            .unwrap();
+        let base_context = config.base_templating_context(&storage).await.unwrap();
+        Site {
+            search: Data::new(Search::init(&config)),
+            config: Data::new(config),
+            storage,
+            render: Data::new(render),
+            base_context: Data::new(base_context),
+        }
+    }
+
+    async fn get(&self, req: TestRequest) -> (StatusCode, header::HeaderMap, String) {
+        let (config, storage) = (self.config.clone(), self.storage.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(self.base_context.clone())
+                .app_data(self.storage.clone())
+                .app_data(self.render.clone())
+                .app_data(self.search.clone())
+                .app_data(self.config.clone())
+                .configure(|app| super::configure(&config, &storage, app)),
+        )
+        .await;
+        let resp = test::call_service(&app, req.to_request()).await;
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        let body = test::read_body(resp).await;
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn page(&self, uri: &str) -> (StatusCode, String) {
+        let (status, _, body) = self.get(TestRequest::get().uri(uri)).await;
+        (status, body)
+    }
+}
+
+fn metadata(toml: &str) -> PageMetadata {
+    toml::from_str(toml).unwrap()
+}
+
+fn memory_site() -> MemoryStorage {
+    let mem: MemoryStorage = toml::from_str("supported_lang = [\"en\", \"fr\"]").unwrap();
+    mem.add_template("notification.html", "{{ notif_title }}");
+    mem.add_template(
+        "page.html",
+        "<h1>{{ metadata.title }}</h1>{{ page_content | safe }}\
+        {% for t in translations %}[{{ t.lang }} {{ t.url | safe }}]{% endfor %}\
+        {% if lang %}({{ lang }}){% endif %}",
+    );
+    mem.add_template(
+        "list.html",
+        "{{ site.name }}:{% for p in recent %} {{ p.metadata.title }}{% endfor %} \
+        ({{ recent_pagination.page }}/{{ recent_pagination.page_count }})",
+    );
+    mem.add_template(
+        "archive.html",
+        "{% for p in recent %}{{ p.metadata.title }} {% endfor %}\
+        {% if recent_pagination.prev_url %}<{{ recent_pagination.prev_url | safe }}{% endif %}\
+        {% if recent_pagination.next_url %}>{{ recent_pagination.next_url | safe }}{% endif %}",
+    );
+    mem.add_template(
+        "taxonomy",
+        "{{ taxonomy }}:{% for t in terms %} {{ t.name }}={{ t.count }}@{{ t.url }}{% endfor %}",
+    );
+    mem.add_template(
+        "sitemap.xml",
+        "{% for t in taxonomies.tags %}<loc>{{ t.url | safe }}</loc>{% endfor %}",
+    );
+    mem.add_template(
+        "taxonomy_term",
+        "{{ term.name }}:{% for p in pages %} {{ p.metadata.title }}{% endfor %} \
+        ({{ pages_pagination.page }}/{{ pages_pagination.page_count }})",
+    );
+    mem.add_context(
+        "blog",
+        Some("en"),
+        "site",
+        toml::from_str("name = \"Blog\"").unwrap(),
+    );
+
+    let pages = [
+        (
+            "alpha",
+            "[metadata]\ntitle = \"Alpha\"\ntags = [\"rust\", \"web\"]",
+            "Some *text*",
+        ),
+        (
+            "beta",
+            "[metadata]\ntitle = \"Beta\"\ntags = [\"rust\"]",
+            "Beta",
+        ),
+        ("gamma", "[metadata]\ntitle = \"Gamma\"", "Gamma"),
+        (
+            "draft",
+            "visibility = \"draft\"\n[metadata]\ntitle = \"Draft\"",
+            "",
+        ),
+        (
+            "later",
+            "publish_date = 4102444800\n[metadata]\ntitle = \"Later\"",
+            "",
+        ),
+        (
+            "old",
+            "expire_date = 946684800\n[metadata]\ntitle = \"Old\"",
+            "",
+        ),
+        (
+            "unlisted",
+            "visibility = \"unlisted\"\n[metadata]\ntitle = \"Unlisted\"\ntags = [\"web\"]",
+            "",
+        ),
+        (
+            "private",
+            "visibility = \"private\"\n[metadata]\ntitle = \"Private\"\ntags = [\"web\"]",
+            "",
+        ),
+    ];
+    for (name, md, body) in pages {
+        mem.add_page("blog", None, name, metadata(md), body);
+    }
+    mem.add_page(
+        "docs",
+        Some("en"),
+        "hello",
+        metadata("[metadata]\ntitle = \"Hello\""),
+        "",
+    );
+    mem.add_page(
+        "docs",
+        Some("fr"),
+        "hello",
+        metadata("[metadata]\ntitle = \"Bonjour\""),
+        "",
+    );
+    mem.add_page(
+        "docs",
+        Some("fr"),
+        "guide",
+        metadata("[metadata]\ntitle = \"Guide\""),
+        "",
+    );
+    mem
+}
+
+fn page_id(slug: &str, lang: Option<&str>, name: &str) -> String {
+    let mut md = PageMetadata::default();
+    md.update_id(&page_id_path(slug, lang, name));
+    md.id.short()
+}
+
+#[actix_web::test]
+async fn serves_pages_with_their_template() {
+    let site = Site::new(memory_site()).await;
+    let (status, body) = site.page("/blog/alpha").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Alpha</h1>"), "{body}");
+    assert!(body.contains("<em>text</em>"), "{body}");
+
+    let (status, body) = site
+        .page(&format!("/p/{}", page_id("blog", None, "beta")))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Beta</h1>"), "{body}");
+}
+
+#[actix_web::test]
+async fn hides_unpublished_pages() {
+    let site = Site::new(memory_site()).await;
+    for uri in [
+        "/blog/missing",
+        "/blog/draft",
+        "/blog/later",
+        "/blog/old",
+        "/p/zz-",
+    ] {
+        let (status, body) = site.page(uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        assert_eq!(body, "Error", "{uri}");
+    }
+}
+
+#[actix_web::test]
+async fn refuses_ids_shared_by_several_pages() {
+    let mem = memory_site();
+    mem.add_page(
+        "blog",
+        None,
+        "twin",
+        metadata("uuid = \"twin\"\n[metadata]\ntitle = \"Twin\""),
+        "",
+    );
+    mem.add_page(
+        "blog",
+        None,
+        "twin-draft",
+        metadata("uuid = \"twin\"\nvisibility = \"draft\"\n[metadata]\ntitle = \"Twin\""),
+        "",
+    );
+    let site = Site::new(mem).await;
+    let mut md = metadata("uuid = \"twin\"");
+    md.update_id(&page_id_path("blog", None, "twin"));
+    let (status, _) = site.page(&format!("/p/{}", md.id.short())).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    let (status, body) = site.page("/blog/twin").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Twin</h1>"), "{body}");
+}
+
+#[actix_web::test]
+async fn serves_unlisted_pages_only_from_their_url() {
+    let site = Site::new(memory_site()).await;
+    let (status, body) = site.page("/blog/unlisted").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Unlisted</h1>"), "{body}");
+    let (status, _) = site
+        .page(&format!("/p/{}", page_id("blog", None, "unlisted")))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    for uri in [
+        "/blog/private".to_string(),
+        format!("/p/{}", page_id("blog", None, "private")),
+        format!("/p/{}", page_id("blog", None, "draft")),
+    ] {
+        assert_eq!(site.page(&uri).await.0, StatusCode::NOT_FOUND, "{uri}");
+    }
+
+    let (_, body) = site.page("/blog/tags").await;
+    assert_eq!(body, "tags: rust=2@/blog/tags/rust web=1@/blog/tags/web");
+}
+
+#[actix_web::test]
+async fn publishes_cached_listings_on_time() {
+    let mem = memory_site();
+    mem.add_page(
+        "blog",
+        None,
+        "soon",
+        metadata(&format!(
+            "publish_date = {}\n[metadata]\ntitle = \"Aa\"",
+            timestamp_now() + 1
+        )),
+        "",
+    );
+    let site = Site::new(mem).await;
+    assert_eq!(site.page("/").await.1, "Blog: Alpha Beta (1/2)");
+    assert_eq!(site.page("/blog/soon").await.0, StatusCode::NOT_FOUND);
+
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert_eq!(site.page("/").await.1, "Blog: Aa Alpha (1/2)");
+    assert_eq!(site.page("/blog/soon").await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn lists_pages_in_context_queries() {
+    let site = Site::new(memory_site()).await;
+    let (status, body) = site.page("/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "Blog: Alpha Beta (1/2)");
+
+    let (status, body) = site.page("/?page=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "Blog: Gamma (2/2)");
+
+    let (status, _) = site.page("/?page=3").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = site.page("/archive/1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "Alpha Beta >/archive/2");
+    let (_, body) = site.page("/archive/2").await;
+    assert_eq!(body, "Gamma </archive/1");
+    assert_eq!(site.page("/archive/3").await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn lists_the_terms_of_taxonomies() {
+    let site = Site::new(memory_site()).await;
+    let (status, body) = site.page("/blog/tags/rust").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "rust: Alpha (1/2)");
+
+    let (status, body) = site.page("/blog/tags/rust?page=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "rust: Beta (2/2)");
+
+    let (status, body) = site.page("/blog/tags/web").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "web: Alpha (1/1)");
+
+    assert_eq!(site.page("/blog/tags/go").await.0, StatusCode::NOT_FOUND);
+
+    let (status, body) = site.page("/sitemap.xml").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "<loc>/blog/tags/rust</loc><loc>/blog/tags/web</loc>");
+}
+
+#[actix_web::test]
+async fn falls_back_to_the_configured_languages() {
+    let site = Site::new(memory_site()).await;
+    let req = TestRequest::get()
+        .uri("/docs/guide")
+        .insert_header((header::ACCEPT_LANGUAGE, "de"));
+    let (status, _, body) = site.get(req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Guide</h1>"), "{body}");
+    assert!(body.ends_with("(fr)"), "{body}");
+
+    // Then to the default language, for pages and for contexts
+    let req = TestRequest::get()
+        .uri("/docs/hello")
+        .insert_header((header::ACCEPT_LANGUAGE, "de"));
+    let (_, _, body) = site.get(req).await;
+    assert!(body.starts_with("<h1>Bonjour</h1>"), "{body}");
+    let (status, body) = site.page("/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("Blog:"), "{body}");
+}
+
+#[actix_web::test]
+async fn negotiates_the_language_of_pages() {
+    let site = Site::new(memory_site()).await;
+    let req = TestRequest::get()
+        .uri("/docs/hello")
+        .insert_header((header::ACCEPT_LANGUAGE, "de, fr-CH;q=0.9, en;q=0.8"));
+    let (status, headers, body) = site.get(req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Bonjour</h1>"), "{body}");
+    assert!(
+        body.contains("[en /docs/hello?lang=en][fr /docs/hello?lang=fr]"),
+        "{body}"
+    );
+    assert_eq!(
+        headers.get(header::VARY).unwrap(),
+        "Accept-Language, Cookie"
+    );
+
+    let (status, headers, body) = site
+        .get(TestRequest::get().uri("/docs/hello?lang=en"))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Hello</h1>"), "{body}");
+    let cookie = headers.get(header::SET_COOKIE).unwrap().to_str().unwrap();
+    assert!(cookie.starts_with("lang=en;"), "{cookie}");
+}
+
+#[actix_web::test]
+async fn finds_the_language_of_pages_by_id() {
+    let site = Site::new(memory_site()).await;
+    let req = TestRequest::get()
+        .uri(&format!("/d/{}", page_id("docs", Some("fr"), "hello")))
+        .insert_header((header::ACCEPT_LANGUAGE, "en"));
+    let (status, _, body) = site.get(req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("<h1>Bonjour</h1>"), "{body}");
+    assert!(body.contains("[en "), "{body}");
+    assert!(body.ends_with("(fr)"), "{body}");
+}
+
+#[actix_web::test]
+async fn serves_pages_written_after_startup() {
+    let mem = memory_site();
+    let site = Site::new(mem.clone()).await;
+    assert_eq!(site.page("/blog/delta").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(site.page("/").await.1, "Blog: Alpha Beta (1/2)");
+
+    mem.add_page(
+        "blog",
+        None,
+        "delta",
+        metadata("[metadata]\ntitle = \"Aa\""),
+        "",
+    );
+    assert_eq!(site.page("/blog/delta").await.0, StatusCode::OK);
+    assert_eq!(site.page("/").await.1, "Blog: Aa Alpha (1/2)");
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
@@ -22,3 +22,58 @@ pub fn compile_scss(fpath: PathBuf) -> Result<String, ScssError> {
 
     Ok(out_css)
 }
+
+#[cfg(any(feature = "storage-git", feature = "storage-bundle"))]
+// Compile a SCSS file read, along with its imports, from another source than the disk
+pub fn compile_scss_from(fpath: &Path, fs: &dyn grass::Fs) -> Result<String, ScssError> {
+    let grass_opts = grass::Options::default().fs(fs);
+    let out_css = grass::from_path(fpath, &grass_opts)?;
+
+    Ok(out_css)
+}
+
+// Files pulled by @import, @use and @forward rules, followed recursively
+pub fn scss_dependencies(fpath: &Path, deps: &mut Vec<PathBuf>) {
+    if deps.iter().any(|d| d == fpath) {
+        return;
+    }
+    deps.push(fpath.to_path_buf());
+    let Ok(content) = std::fs::read_to_string(fpath) else {
+        return;
+    };
+    let dir = fpath.parent().unwrap_or(Path::new(""));
+    for line in content.lines() {
+        let line = line.trim_start();
+        let Some(rule) = ["@import", "@use", "@forward"]
+            .iter()
+            .find_map(|r| line.strip_prefix(r))
+        else {
+            continue;
+        };
+        for import in rule.split(['"', '\'']).skip(1).step_by(2) {
+            if let Some(path) = resolve_import(dir, import) {
+                scss_dependencies(&path, deps);
+            }
+        }
+    }
+}
+
+fn resolve_import(dir: &Path, import: &str) -> Option<PathBuf> {
+    if import.starts_with("sass:") || import.contains("://") {
+        return None;
+    }
+    let import = Path::new(import);
+    let parent = dir.join(import.parent().unwrap_or(Path::new("")));
+    let name = import.file_name()?.to_str()?;
+    [
+        name.to_string(),
+        format!("{name}.scss"),
+        format!("_{name}.scss"),
+        format!("{name}.css"),
+        format!("{name}/_index.scss"),
+        format!("{name}/index.scss"),
+    ]
+    .into_iter()
+    .map(|candidate| parent.join(candidate))
+    .find(|path| path.is_file())
+}
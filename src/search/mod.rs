@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use parking_lot::{Mutex, RwLock};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::errors::Errcode;
+use crate::page::PageMetadata;
+use crate::render::content::escape_html;
+use crate::storage::backend::supported_lang_of;
+use crate::storage::{Storage, StorageQueryMethod, StorageSlug};
+
+mod stem;
+
+static HTML_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<[^<>]*>").unwrap());
+static MD_LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap());
+static MD_MARKUP: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[#*_`>~|]+").unwrap());
+
+// Terms found in the metadata fields count more than the ones of the body
+const FIELD_WEIGHT: f32 = 3.0;
+
+// Parameters of the BM25 ranking
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+// Words of the body shown around the first term found
+const SNIPPET_WORDS: usize = 30;
+
+/// Search of the pages of a storage slug, run by a page type for the "?q=" parameter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchOptions {
+    pub storage: StorageSlug,
+
+    // Metadata indexed along with the body of the pages, as keys to the values
+    #[serde(default)]
+    pub fields: Vec<Vec<String>>,
+
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub metadata: PageMetadata,
+    pub score: f32,
+    // Body of the page around the terms searched, escaped HTML with the terms in <mark>
+    pub snippet: String,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    pub query: String,
+    pub total: usize,
+    pub results: Vec<SearchResult>,
+}
+
+struct IndexedPage {
+    metadata: PageMetadata,
+    text: String,
+    len: f32,
+}
+
+/// Inverted index of the pages of a storage slug in a language
+struct SearchIndex {
+    // Generation of the storage slug the index was built from
+    generation: usize,
+    lang: Option<String>,
+    pages: Vec<IndexedPage>,
+    // Pages each term appears in, with its weighted frequency in the page
+    postings: HashMap<String, Vec<(usize, f32)>>,
+    avg_len: f32,
+}
+
+// Storage slug, language and whether drafts are shown
+type IndexKey = (StorageSlug, Option<String>, bool);
+
+/// Indexes of every storage slug and language searched, rebuilt once the storage finds
+/// the pages of their slug changed
+pub struct Search {
+    indexes: RwLock<HashMap<IndexKey, Arc<SearchIndex>>>,
+    // Held while an index is built, so concurrent searches wait for it instead of
+    // building it as well
+    builds: Mutex<HashMap<IndexKey, Arc<tokio::sync::Mutex<()>>>>,
+    // Data isn't cached in dev mode, so changes are never found
+    dev_mode: bool,
+}
+
+impl Search {
+    pub fn init(config: &Config) -> Search {
+        Search {
+            indexes: RwLock::new(HashMap::new()),
+            builds: Mutex::new(HashMap::new()),
+            dev_mode: config.dev_mode,
+        }
+    }
+
+    // Search the pages in the first language of the preferences the slug is translated in
+    pub async fn search(
+        &self,
+        storage: &Storage,
+        opts: &SearchOptions,
+        lang_pref: &[String],
+        drafts: bool,
+        query: &str,
+    ) -> Result<SearchResults, Errcode> {
+        let supported = storage.supported_lang(&opts.storage);
+        let lang = lang_pref
+            .iter()
+            .find_map(|l| supported_lang_of(l, &supported))
+            .cloned();
+
+        // Listing the pages first lets the storage find whether they changed
+        let mut qry = StorageQueryMethod::RecentPages.build_query(&opts.storage);
+        if drafts {
+            qry.show_drafts();
+        }
+        let (pages, _) = storage.query(qry).await.recent_pages()?;
+        let generation = storage.generation(&opts.storage);
+
+        let key = (opts.storage.clone(), lang.clone(), drafts);
+        if let Some(index) = self.built_index(&key, generation) {
+            return Ok(index.search(query, opts.limit));
+        }
+
+        let build = self.builds.lock().entry(key.clone()).or_default().clone();
+        let _building = build.lock().await;
+        // Another search may have built it while this one was waiting
+        let index = match self.built_index(&key, generation) {
+            Some(index) => index,
+            None => {
+                let pages = pages
+                    .into_iter()
+                    .filter(|m| supported.is_empty() || m.lang == lang)
+                    .collect();
+                let index = SearchIndex::build(storage, opts, lang, drafts, pages, generation);
+                let index = Arc::new(index.await);
+                self.indexes.write().insert(key, index.clone());
+                index
+            }
+        };
+        Ok(index.search(query, opts.limit))
+    }
+
+    // Index built from the current generation of the pages of its slug
+    fn built_index(&self, key: &IndexKey, generation: usize) -> Option<Arc<SearchIndex>> {
+        let index = self.indexes.read().get(key).cloned()?;
+        (index.generation == generation && !self.dev_mode).then_some(index)
+    }
+}
+
+impl SearchIndex {
+    async fn build(
+        storage: &Storage,
+        opts: &SearchOptions,
+        lang: Option<String>,
+        drafts: bool,
+        pages: Vec<PageMetadata>,
+        generation: usize,
+    ) -> SearchIndex {
+        let mut index = SearchIndex {
+            generation,
+            lang,
+            pages: vec![],
+            postings: HashMap::new(),
+            avg_len: 0.0,
+        };
+        // Pages are read once, so they aren't kept in the cache of the storage
+        for metadata in pages {
+            let mut qry =
+                StorageQueryMethod::ContentNumId(metadata.id.value()).build_query(&opts.storage);
+            if drafts {
+                qry.show_drafts();
+            }
+            let body = match storage.query_uncached(qry).await.page_content() {
+                Ok((_, _, body)) => body,
+                Err(e) => {
+                    log::warn!(
+                        "Unable to index page {} of {}: {e:?}",
+                        metadata.id,
+                        opts.storage
+                    );
+                    continue;
+                }
+            };
+            index.add(metadata, &body, &opts.fields);
+        }
+        let total_len: f32 = index.pages.iter().map(|p| p.len).sum();
+        index.avg_len = total_len / index.pages.len().max(1) as f32;
+        log::debug!(
+            "Indexed {} pages of {} for search, in {:?}",
+            index.pages.len(),
+            opts.storage,
+            index.lang
+        );
+        index
+    }
+
+    fn add(&mut self, metadata: PageMetadata, body: &str, fields: &[Vec<String>]) {
+        let n = self.pages.len();
+        let lang = self.lang.as_deref();
+        let text = plain_text(body);
+        let mut freqs: HashMap<String, f32> = HashMap::new();
+        let mut len = 0.0;
+        for (start, end) in words(&text) {
+            *freqs.entry(term(&text[start..end], lang)).or_default() += 1.0;
+            len += 1.0;
+        }
+        for field in fields {
+            let Some(value) = metadata.get_metadata(field) else {
+                continue;
+            };
+            let value = field_text(value);
+            for (start, end) in words(&value) {
+                *freqs.entry(term(&value[start..end], lang)).or_default() += FIELD_WEIGHT;
+                len += 1.0;
+            }
+        }
+        for (term, freq) in freqs {
+            self.postings.entry(term).or_default().push((n, freq));
+        }
+        self.pages.push(IndexedPage {
+            metadata,
+            text,
+            len,
+        });
+    }
+
+    fn search(&self, query: &str, limit: usize) -> SearchResults {
+        let lang = self.lang.as_deref();
+        let mut terms = words(query)
+            .map(|(start, end)| term(&query[start..end], lang))
+            .collect::<Vec<String>>();
+        terms.sort();
+        terms.dedup();
+
+        let nb_pages = self.pages.len() as f32;
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for term in terms.iter() {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let found_in = postings.len() as f32;
+            let idf = (1.0 + (nb_pages - found_in + 0.5) / (found_in + 0.5)).ln();
+            for (n, freq) in postings {
+                let norm = 1.0 - BM25_B + BM25_B * self.pages[*n].len / self.avg_len.max(1.0);
+                *scores.entry(*n).or_default() +=
+                    idf * freq * (BM25_K1 + 1.0) / (freq + BM25_K1 * norm);
+            }
+        }
+
+        let mut ranked = scores.into_iter().collect::<Vec<(usize, f32)>>();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let total = ranked.len();
+        let results = ranked
+            .into_iter()
+            .take(limit)
+            .map(|(n, score)| {
+                let page = &self.pages[n];
+                SearchResult {
+                    metadata: page.metadata.clone(),
+                    score,
+                    snippet: snippet(&page.text, &terms, lang),
+                }
+            })
+            .collect();
+        SearchResults {
+            query: query.to_string(),
+            total,
+            results,
+        }
+    }
+}
+
+// Text of a page body written in markdown or HTML, without its markup
+fn plain_text(body: &str) -> String {
+    let text = HTML_TAG.replace_all(body, " ");
+    let text = MD_LINK.replace_all(&text, "$1");
+    let text = MD_MARKUP.replace_all(&text, " ");
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn field_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(field_text)
+            .collect::<Vec<String>>()
+            .join(" "),
+        value => value.to_string(),
+    }
+}
+
+// Start and end of the words of a text
+fn words(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (start, _) = chars.find(|(_, c)| c.is_alphanumeric())?;
+        let mut end = text.len();
+        while let Some((i, c)) = chars.peek() {
+            if !c.is_alphanumeric() {
+                end = *i;
+                break;
+            }
+            chars.next();
+        }
+        Some((start, end))
+    })
+}
+
+// Term of a word in the index, lowercased, without diacritics and stemmed
+fn term(word: &str, lang: Option<&str>) -> String {
+    let folded = word
+        .to_lowercase()
+        .chars()
+        .map(fold_diacritic)
+        .collect::<String>();
+    stem::stem(&folded, lang)
+}
+
+fn fold_diacritic(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        c => c,
+    }
+}
+
+// Words of the text around the first term searched, with the terms highlighted
+fn snippet(text: &str, terms: &[String], lang: Option<&str>) -> String {
+    let words = words(text).collect::<Vec<(usize, usize)>>();
+    let matches = words
+        .iter()
+        .map(|(start, end)| terms.contains(&term(&text[*start..*end], lang)))
+        .collect::<Vec<bool>>();
+    let first = matches.iter().position(|m| *m).unwrap_or(0);
+    let from = first.saturating_sub(SNIPPET_WORDS / 3);
+    let to = (from + SNIPPET_WORDS).min(words.len());
+    if from >= to {
+        return String::new();
+    }
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet += "… ";
+    }
+    let mut last = words[from].0;
+    for n in from..to {
+        let (start, end) = words[n];
+        if !matches[n] {
+            continue;
+        }
+        snippet += &escape_html(&text[last..start]);
+        snippet += &format!("<mark>{}</mark>", escape_html(&text[start..end]));
+        last = end;
+    }
+    snippet += &escape_html(&text[last..words[to - 1].1]);
+    if to < words.len() {
+        snippet += " …";
+    }
+    snippet
+}
@@ -0,0 +1,146 @@
+// Light stemmers, removing the inflectional and most common derivational suffixes of
+// the words. Terms of the pages and of the queries go through the same stemmer, so
+// the stems only have to be consistent, not to be real words.
+
+// Suffixes of english words, tried in order, with what replaces them. Words ending in a
+// suffix replaced by itself are kept as they are.
+const EN_SUFFIXES: &[(&str, &str)] = &[
+    ("ational", "ate"),
+    ("ization", "ize"),
+    ("fulness", "ful"),
+    ("ousness", "ous"),
+    ("iveness", "ive"),
+    ("ations", "ate"),
+    ("ation", "ate"),
+    ("ments", ""),
+    ("ment", ""),
+    ("ness", ""),
+    ("ingly", ""),
+    ("edly", ""),
+    ("ings", ""),
+    ("ing", ""),
+    ("ies", "y"),
+    ("ied", "y"),
+    ("sses", "ss"),
+    ("ss", "ss"),
+    ("ers", ""),
+    ("er", ""),
+    ("ed", ""),
+    ("ly", ""),
+    ("es", ""),
+    ("s", ""),
+    ("e", ""),
+];
+
+// Suffixes of french words, without their diacritics, tried in order
+const FR_SUFFIXES: &[(&str, &str)] = &[
+    ("issements", ""),
+    ("issement", ""),
+    ("atrices", ""),
+    ("atrice", ""),
+    ("ateurs", ""),
+    ("ateur", ""),
+    ("ations", ""),
+    ("ation", ""),
+    ("ements", ""),
+    ("ement", ""),
+    ("ments", ""),
+    ("ment", ""),
+    ("euses", ""),
+    ("euse", ""),
+    ("eux", ""),
+    ("ites", ""),
+    ("ite", ""),
+    ("iques", ""),
+    ("ique", ""),
+    ("ables", ""),
+    ("able", ""),
+    ("istes", ""),
+    ("iste", ""),
+    ("ismes", ""),
+    ("isme", ""),
+    ("ances", ""),
+    ("ance", ""),
+    ("ences", ""),
+    ("ence", ""),
+    ("elles", "el"),
+    ("elle", "el"),
+    ("ives", "if"),
+    ("ive", "if"),
+    ("ifs", "if"),
+    ("aux", "al"),
+    ("ees", ""),
+    ("ee", ""),
+    ("es", ""),
+    ("er", ""),
+    ("ez", ""),
+    ("e", ""),
+    ("s", ""),
+    ("x", ""),
+];
+
+// Stems shorter than this are left as they are, too short to be told apart
+const MIN_STEM_LEN: usize = 3;
+
+fn strip_suffix(word: &str, suffixes: &[(&str, &str)]) -> String {
+    for (suffix, replacement) in suffixes {
+        if let Some(stem) = word.strip_suffix(suffix) {
+            if suffix == replacement {
+                break;
+            }
+            if stem.chars().count() >= MIN_STEM_LEN {
+                return format!("{stem}{replacement}");
+            }
+        }
+    }
+    word.to_string()
+}
+
+// Stem of a word already lowercased and without diacritics, unchanged if its
+// language has no stemmer
+pub fn stem(word: &str, lang: Option<&str>) -> String {
+    let primary = lang.map(|l| l.split('-').next().unwrap_or(l));
+    match primary {
+        Some("en") => strip_suffix(word, EN_SUFFIXES),
+        Some("fr") => strip_suffix(word, FR_SUFFIXES),
+        _ => word.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stems_english_words() {
+        assert_eq!(stem("relational", Some("en")), "relate");
+        assert_eq!(stem("running", Some("en-US")), "runn");
+        assert_eq!(stem("ponies", Some("en")), "pony");
+        assert_eq!(stem("caresses", Some("en")), "caress");
+        assert_eq!(stem("pages", Some("en")), stem("page", Some("en")));
+        assert_eq!(stem("darkness", Some("en")), "dark");
+    }
+
+    #[test]
+    fn keeps_english_words_ending_in_ss() {
+        assert_eq!(stem("class", Some("en")), "class");
+        assert_eq!(stem("classes", Some("en")), "class");
+        assert_eq!(stem("boss", Some("en")), stem("bosses", Some("en")));
+    }
+
+    #[test]
+    fn stems_french_words() {
+        assert_eq!(stem("rapidement", Some("fr")), "rapid");
+        assert_eq!(stem("chevaux", Some("fr")), "cheval");
+        assert_eq!(stem("actives", Some("fr")), "actif");
+        assert_eq!(stem("pages", Some("fr")), stem("page", Some("fr")));
+    }
+
+    #[test]
+    fn keeps_short_stems_and_unknown_languages() {
+        assert_eq!(stem("bus", Some("en")), "bus");
+        assert_eq!(stem("sing", Some("en")), "sing");
+        assert_eq!(stem("pages", Some("de")), "pages");
+        assert_eq!(stem("pages", None), "pages");
+    }
+}
@@ -0,0 +1,270 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::page::{metadata_date, value_type_rank, PageMetadata};
+
+use super::context::MetadataQuery;
+use super::filter::FilterExpr;
+
+/// Grouping of the listed pages by the distinct values of a metadata key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Aggregation {
+    // Each value of an array is a group of its own
+    pub group_by: MetadataQuery,
+
+    #[serde(default)]
+    pub derive: Option<DerivedKey>,
+
+    // Key holding the date of the pages, the one they are sorted by if not set. Pages
+    // without a date there are dated by their publication date.
+    #[serde(default)]
+    pub date: Option<MetadataQuery>,
+
+    #[serde(default)]
+    pub sort_by: AggregateSort,
+    #[serde(default)]
+    pub rev_sort: bool,
+
+    #[serde(default)]
+    pub limit: usize,
+
+    // Only group the pages whose metadata match this expression
+    #[serde(default)]
+    pub filter: Option<FilterExpr>,
+}
+
+/// Key of the groups derived from the dates found in the values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivedKey {
+    Year,
+    Month,
+    Day,
+}
+
+impl DerivedKey {
+    fn format(&self) -> &'static str {
+        match self {
+            DerivedKey::Year => "%Y",
+            DerivedKey::Month => "%Y-%m",
+            DerivedKey::Day => "%Y-%m-%d",
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateSort {
+    // The groups with the most pages first
+    #[default]
+    Count,
+    // In the order of their keys
+    Key,
+}
+
+/// Pages having a value of the key grouped by, with the first and last of their dates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub key: Value,
+    pub count: usize,
+    pub min_date: Option<i64>,
+    pub max_date: Option<i64>,
+}
+
+impl Aggregation {
+    pub fn apply<'a>(
+        &self,
+        pages: impl Iterator<Item = &'a PageMetadata>,
+        sort_key: &MetadataQuery,
+    ) -> Vec<Aggregate> {
+        let date_key = self.date.as_ref().unwrap_or(sort_key);
+        let mut groups: HashMap<String, Aggregate> = HashMap::new();
+        for page in pages {
+            let Some(value) = page.get_metadata(&self.group_by) else {
+                continue;
+            };
+            let date = page
+                .get_metadata(date_key)
+                .and_then(metadata_date)
+                .or(page.publish_date);
+
+            let mut keys = match value {
+                Value::Array(values) => values.iter().filter_map(|v| self.key(v)).collect(),
+                value => self.key(value).into_iter().collect::<Vec<Value>>(),
+            };
+            // A page is counted once in a group, even with the same value twice
+            keys.sort_by_key(|k| k.to_string());
+            keys.dedup();
+            for key in keys {
+                let group = groups.entry(key.to_string()).or_insert(Aggregate {
+                    key,
+                    count: 0,
+                    min_date: None,
+                    max_date: None,
+                });
+                group.count += 1;
+                if let Some(date) = date {
+                    group.min_date = Some(group.min_date.map_or(date, |d| d.min(date)));
+                    group.max_date = Some(group.max_date.map_or(date, |d| d.max(date)));
+                }
+            }
+        }
+
+        let mut groups = groups.into_values().collect::<Vec<Aggregate>>();
+        groups.sort_by(|a, b| {
+            let by_key = compare_keys(&a.key, &b.key);
+            match self.sort_by {
+                AggregateSort::Count => b.count.cmp(&a.count).then(by_key),
+                AggregateSort::Key => by_key,
+            }
+        });
+        if self.rev_sort {
+            groups.reverse();
+        }
+        if self.limit > 0 {
+            groups.truncate(self.limit);
+        }
+        groups
+    }
+
+    // Key of the group of a value, none for the values no key can be derived from
+    fn key(&self, value: &Value) -> Option<Value> {
+        if value.is_null() {
+            return None;
+        }
+        let Some(derive) = self.derive else {
+            return Some(value.clone());
+        };
+        let date = chrono::DateTime::from_timestamp(metadata_date(value)?, 0)?;
+        Some(Value::String(date.format(derive.format()).to_string()))
+    }
+}
+
+// Total order of the keys of the groups, by type first, whatever the metadata holds
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.total_cmp(&b)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| compare_keys(a, b))
+            .find(|ord| ord.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(_), Value::Object(_)) => a.to_string().cmp(&b.to_string()),
+        _ => value_type_rank(a).cmp(&value_type_rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(metadata: &str, publish_date: i64) -> PageMetadata {
+        PageMetadata {
+            metadata: serde_json::from_str(metadata).unwrap(),
+            publish_date: Some(publish_date),
+            ..Default::default()
+        }
+    }
+
+    fn sort_key() -> MetadataQuery {
+        vec!["date".to_string()]
+    }
+
+    fn aggregation(toml: &str) -> Aggregation {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn counts_distinct_values() {
+        let pages = [
+            page(r#"{"tags": ["rust", "web", "rust"]}"#, 10),
+            page(r#"{"tags": ["rust"]}"#, 20),
+            page(r#"{"tags": "go"}"#, 5),
+            page(r#"{"title": "no tags"}"#, 5),
+        ];
+        let groups = aggregation(r#"group_by = ["tags"]"#).apply(pages.iter(), &sort_key());
+        let groups = groups
+            .iter()
+            .map(|g| (g.key.as_str().unwrap(), g.count, g.min_date, g.max_date))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                ("rust", 2, Some(10), Some(20)),
+                ("go", 1, Some(5), Some(5)),
+                ("web", 1, Some(10), Some(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_by_year() {
+        let pages = [
+            page(r#"{"date": "2023-05-01"}"#, 1),
+            page(r#"{"date": "2023-12-31T23:00:00Z"}"#, 2),
+            page(r#"{"date": 1641038400}"#, 3),
+            page(r#"{"date": "not a date"}"#, 4),
+        ];
+        let agg = aggregation(
+            "group_by = [\"date\"]\nderive = \"year\"\nsort_by = \"key\"\nrev_sort = true",
+        );
+        let groups = agg
+            .apply(pages.iter(), &sort_key())
+            .into_iter()
+            .map(|g| (g.key, g.count))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, [("2023".into(), 2), ("2022".into(), 1)]);
+    }
+
+    #[test]
+    fn dates_pages_by_their_sort_key() {
+        let mut pages = [
+            page(
+                r#"{"tags": "rust", "date": "2023-05-01", "updated": 30}"#,
+                1,
+            ),
+            page(r#"{"tags": "rust", "date": 1641038400}"#, 2),
+            page(r#"{"tags": "rust"}"#, 3),
+            page(r#"{"tags": "web"}"#, 0),
+        ];
+        pages[3].publish_date = None;
+        let dates = |agg: &Aggregation| {
+            agg.apply(pages.iter(), &sort_key())
+                .into_iter()
+                .map(|g| (g.min_date, g.max_date))
+                .collect::<Vec<_>>()
+        };
+        let agg = aggregation("group_by = [\"tags\"]");
+        assert_eq!(dates(&agg), [(Some(3), Some(1682899200)), (None, None)]);
+        let agg = aggregation("group_by = [\"tags\"]\ndate = [\"updated\"]");
+        assert_eq!(dates(&agg), [(Some(2), Some(30)), (None, None)]);
+    }
+
+    #[test]
+    fn sorts_keys_of_mixed_types() {
+        let pages = [
+            page(r#"{"rating": 4}"#, 1),
+            page(r#"{"rating": 4.5}"#, 1),
+            page(r#"{"rating": "good"}"#, 1),
+            page(r#"{"rating": true}"#, 1),
+            page(r#"{"rating": -1}"#, 1),
+            page(r#"{"rating": {"a": 1}}"#, 1),
+        ];
+        let agg = aggregation("group_by = [\"rating\"]\nsort_by = \"key\"");
+        let keys = agg
+            .apply(pages.iter(), &sort_key())
+            .into_iter()
+            .map(|g| g.key.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["true", "-1", "4", "4.5", "\"good\"", "{\"a\":1}"]);
+    }
+}
@@ -0,0 +1,880 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::frontmatter::{parse_page_content, FrontmatterError};
+use crate::page::{compare_similar_md, timestamp_now, PageMetadata};
+use crate::scss::{compile_scss_from, ScssError};
+use crate::storage::query::StorageQueryMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
+
+use super::{
+    check_id_collisions, is_bundle_index, lang_candidates, normalize_relative_path,
+    page_file_candidates, page_file_id_path, page_file_lang, page_file_name, supported_lang_of,
+    PageFiles, PageSchedules, RelativePathError, StorageBackend,
+};
+
+/// Name of the file describing the layout of the bundle, at the root of the archive
+pub const BUNDLE_MANIFEST: &str = "bundle.toml";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum BundleStorageError {
+    OpenArchive(String),
+    ReadArchive(String),
+    UnsupportedFormat(PathBuf),
+    NoManifest,
+    ManifestDecode(String),
+
+    DataNotFound(String),
+    NotUtf8(String),
+    Frontmatter(FrontmatterError),
+
+    NoMatch(String),
+    TooManyMatches(usize, usize),
+
+    TomlDecode(String),
+
+    CssNotFound(String),
+    ScssProcess(ScssError),
+
+    BadRequest(String),
+    AttackSuspected(String),
+
+    ReadOnly,
+
+    // Packing a bundle
+    NotLocalStorage,
+    BadSourceDir(PathBuf),
+    ReadSource(String),
+    WriteArchive(String),
+    TomlEncode(String),
+}
+
+impl From<BundleStorageError> for HttpResponseBuilder {
+    fn from(val: BundleStorageError) -> Self {
+        match val {
+            BundleStorageError::DataNotFound(_) | BundleStorageError::NoMatch(_) => {
+                HttpResponse::NotFound()
+            }
+            _ => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+impl BundleStorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            BundleStorageError::DataNotFound(_)
+                | BundleStorageError::NoMatch(_)
+                | BundleStorageError::CssNotFound(_)
+        )
+    }
+}
+
+impl From<FrontmatterError> for BundleStorageError {
+    fn from(value: FrontmatterError) -> Self {
+        BundleStorageError::Frontmatter(value)
+    }
+}
+
+impl From<ScssError> for BundleStorageError {
+    fn from(value: ScssError) -> Self {
+        BundleStorageError::ScssProcess(value)
+    }
+}
+
+/// Layout of the bundle, written from the LocalStorage config it was packed from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BundleManifest {
+    #[serde(default)]
+    data_root: PathBuf,
+    #[serde(default)]
+    supported_lang: Vec<String>,
+    #[serde(default)]
+    default_sort: (Vec<String>, bool),
+    #[serde(default)]
+    template_root: PathBuf,
+    #[serde(default)]
+    include_assets: Vec<PathBuf>,
+    #[serde(default)]
+    scss: HashMap<String, Vec<PathBuf>>,
+    #[serde(default)]
+    scss_root: PathBuf,
+}
+
+impl BundleManifest {
+    // Paths are looked up as written in the archive, without any "./"
+    // The pages, templates and assets can't be served from the root of the archive, as it
+    // holds the manifest and every other file of the bundle
+    fn normalize_paths(&mut self) -> Result<(), BundleStorageError> {
+        bundle_dir(&mut self.data_root, false)?;
+        bundle_dir(&mut self.template_root, false)?;
+        bundle_dir(&mut self.scss_root, true)?;
+        for inc in self.include_assets.iter_mut() {
+            bundle_dir(inc, false)?;
+        }
+        Ok(())
+    }
+}
+
+// Directory of the bundle, empty for its root if allowed
+fn bundle_dir(path: &mut PathBuf, allow_root: bool) -> Result<(), BundleStorageError> {
+    match normalize_relative_path(&path.to_string_lossy()) {
+        Ok(dir) => *path = PathBuf::from(dir),
+        Err(RelativePathError::NoFileName) if allow_root => *path = PathBuf::new(),
+        Err(_) => return Err(BundleStorageError::BadSourceDir(path.clone())),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArchiveFormat {
+    TarZstd,
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn of(path: &Path) -> Result<ArchiveFormat, BundleStorageError> {
+        let fname = path.file_name().unwrap_or_default().to_string_lossy();
+        if fname.ends_with(".tar.zst") || fname.ends_with(".tzst") {
+            Ok(ArchiveFormat::TarZstd)
+        } else if fname.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(BundleStorageError::UnsupportedFormat(path.to_path_buf()))
+        }
+    }
+}
+
+/// Files of the archive, loaded in memory at startup
+#[derive(Debug, Default)]
+struct BundleIndex {
+    files: HashMap<String, Vec<u8>>,
+    dirs: HashSet<String>,
+}
+
+impl BundleIndex {
+    fn add(&mut self, path: String, data: Vec<u8>) {
+        let mut dir = path.as_str();
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            self.dirs.insert(parent.to_string());
+            dir = parent;
+        }
+        self.files.insert(path, data);
+    }
+
+    fn get(&self, path: &Path) -> Result<&Vec<u8>, BundleStorageError> {
+        self.files
+            .get(path.to_string_lossy().as_ref())
+            .ok_or_else(|| BundleStorageError::DataNotFound(format!("{path:?}")))
+    }
+
+    fn get_text(&self, path: &Path) -> Result<&str, BundleStorageError> {
+        std::str::from_utf8(self.get(path)?)
+            .map_err(|_| BundleStorageError::NotUtf8(format!("{path:?}")))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path.to_string_lossy().as_ref())
+    }
+
+    // Every file under a directory, with its path relative to it
+    fn list(&self, dir: &Path) -> Vec<(&str, &String)> {
+        let dir = dir.to_string_lossy();
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{dir}/")
+        };
+        let mut files = self
+            .files
+            .keys()
+            .filter_map(|path| Some((path.strip_prefix(prefix.as_str())?, path)))
+            .collect::<Vec<(&str, &String)>>();
+        files.sort();
+        files
+    }
+}
+
+// Path of an archive entry, refusing entries that would be extracted outside of it
+fn entry_path(name: &Path) -> Result<String, BundleStorageError> {
+    let name = name.to_string_lossy();
+    normalize_relative_path(&name).map_err(|e| {
+        log::error!("Refusing archive entry {name:?}: {e:?}");
+        BundleStorageError::AttackSuspected("bundle-storage::archive::directory-traversal".into())
+    })
+}
+
+fn read_archive(path: &Path) -> Result<BundleIndex, BundleStorageError> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| BundleStorageError::OpenArchive(format!("{path:?}: {e:?}")))?;
+    let read_err = |e: &dyn std::fmt::Debug| BundleStorageError::ReadArchive(format!("{e:?}"));
+
+    let mut index = BundleIndex::default();
+    match ArchiveFormat::of(path)? {
+        ArchiveFormat::TarZstd => {
+            let decoder = zstd::Decoder::new(file).map_err(|e| read_err(&e))?;
+            let mut archive = tar::Archive::new(decoder);
+            for entry in archive.entries().map_err(|e| read_err(&e))? {
+                let mut entry = entry.map_err(|e| read_err(&e))?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry_path(&entry.path().map_err(|e| read_err(&e))?)?;
+                let mut data = vec![];
+                entry.read_to_end(&mut data).map_err(|e| read_err(&e))?;
+                index.add(name, data);
+            }
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(|e| read_err(&e))?;
+            for n in 0..archive.len() {
+                let mut entry = archive.by_index(n).map_err(|e| read_err(&e))?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = entry_path(Path::new(entry.name()))?;
+                let mut data = vec![];
+                entry.read_to_end(&mut data).map_err(|e| read_err(&e))?;
+                index.add(name, data);
+            }
+        }
+    }
+    Ok(index)
+}
+
+// Storage slug    Path in bundle    Metadata
+type PageCache = HashMap<String, Arc<Vec<(String, PageMetadata)>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleStorage {
+    #[serde(skip)]
+    index: Arc<BundleIndex>,
+    #[serde(skip)]
+    manifest: BundleManifest,
+    #[serde(skip)]
+    all_pages: Arc<RwLock<PageCache>>,
+    // Next publish and expire dates of the pages of each slug
+    #[serde(skip)]
+    schedules: Arc<RwLock<HashMap<String, PageSchedules>>>,
+
+    archive: PathBuf,
+}
+
+impl BundleStorage {
+    pub fn open(&mut self, config: &Config) -> Result<(), BundleStorageError> {
+        self.archive = config.root.join(&self.archive);
+        let index = read_archive(&self.archive)?;
+        let manifest = index
+            .files
+            .get(BUNDLE_MANIFEST)
+            .ok_or(BundleStorageError::NoManifest)?;
+        let manifest = std::str::from_utf8(manifest)
+            .map_err(|e| BundleStorageError::ManifestDecode(format!("{e:?}")))?;
+        self.manifest = toml::from_str(manifest)
+            .map_err(|e| BundleStorageError::ManifestDecode(format!("{e:?}")))?;
+        self.manifest.normalize_paths()?;
+        self.index = Arc::new(index);
+        Ok(())
+    }
+
+    fn content_path(&self, slug: &str, name: &str, lang: Option<&String>, ext: &str) -> PathBuf {
+        let mut path = self.manifest.data_root.join(slug);
+        if let Some(lang) = lang {
+            path.push(lang);
+        }
+        path.push(format!("{name}.{ext}"));
+        path
+    }
+
+    // Path of a page in a language, as the first of its candidate files found in the bundle,
+    // with whether it is a page bundle
+    fn find_page_path(
+        &self,
+        slug: &str,
+        name: &str,
+        lang: Option<&String>,
+    ) -> Option<(PathBuf, bool)> {
+        let mut dir = self.manifest.data_root.join(slug);
+        if let Some(lang) = lang {
+            dir.push(lang);
+        }
+        page_file_candidates(name)
+            .into_iter()
+            .map(|(fname, bundle)| (dir.join(fname), bundle))
+            .find(|(path, _)| self.index.get(path).is_ok())
+    }
+
+    // Path of a page in the first language of the query it is written in,
+    // with whether it is a page bundle and the language found
+    fn page_path(&self, qry: &StorageQuery, name: &str) -> (PathBuf, bool, Option<String>) {
+        let slug = &qry.storage_slug;
+        for lang in lang_candidates(qry, &self.manifest.supported_lang) {
+            if let Some((path, bundle)) = self.find_page_path(slug, name, lang.as_ref()) {
+                return (path, bundle, lang);
+            }
+        }
+        let lang = self.select_lang(qry);
+        let mut dir = self.manifest.data_root.join(slug);
+        if let Some(ref lang) = lang {
+            dir.push(lang);
+        }
+        (dir.join(&page_file_candidates(name)[0].0), false, lang)
+    }
+
+    // Languages a page is written in
+    fn page_translations(&self, slug: &str, name: &str) -> Vec<String> {
+        self.manifest
+            .supported_lang
+            .iter()
+            .filter(|lang| self.find_page_path(slug, name, Some(lang)).is_some())
+            .cloned()
+            .collect()
+    }
+
+    fn load_content(&self, path: &Path) -> Result<(PageMetadata, String), BundleStorageError> {
+        let content = self.index.get_text(path)?;
+        let (mut metadata, body) = parse_page_content(&path.to_string_lossy(), content)?;
+        let rel = path.strip_prefix(&self.manifest.data_root).unwrap_or(path);
+        metadata.update_id(page_file_id_path(&rel.to_string_lossy()));
+        metadata.set_default_format(&path.to_string_lossy());
+        Ok((metadata, body))
+    }
+
+    // The bundle never changes, so pages are only listed once
+    fn all_pages(
+        &self,
+        slug: &String,
+    ) -> Result<Arc<Vec<(String, PageMetadata)>>, BundleStorageError> {
+        if let Some(pages) = self.all_pages.read().get(slug) {
+            return Ok(pages.clone());
+        }
+        let dirpath = self.manifest.data_root.join(slug);
+        if !self.index.is_dir(&dirpath) {
+            return Err(BundleStorageError::DataNotFound(format!("{dirpath:?}")));
+        }
+        let files = self.index.list(&dirpath);
+        let page_files = PageFiles::new(
+            files.iter().map(|(rel, _)| *rel),
+            &self.manifest.supported_lang,
+        );
+        let mut pages = vec![];
+        for (rel, path) in files {
+            if page_files.is_page(rel) {
+                let (mut metadata, _) = self.load_content(Path::new(path))?;
+                metadata.bundle = is_bundle_index(rel);
+                metadata.lang = page_file_lang(rel, &self.manifest.supported_lang);
+                pages.push((path.clone(), metadata));
+            }
+        }
+        log::debug!("Registered {} pages in {slug}", pages.len());
+        check_id_collisions(slug, pages.iter());
+        let schedules = PageSchedules::new(pages.iter().map(|(_, m)| m));
+        self.schedules.write().insert(slug.clone(), schedules);
+        let pages = Arc::new(pages);
+        self.all_pages.write().insert(slug.clone(), pages.clone());
+        Ok(pages)
+    }
+
+    fn load_css(&self, css: &str) -> Result<Vec<u8>, BundleStorageError> {
+        let Some(scss_files) = self.manifest.scss.get(css) else {
+            return Err(BundleStorageError::CssNotFound(css.to_string()));
+        };
+
+        let fs = BundleFs(&self.index);
+        let mut css_content = String::new();
+        for scss_file in scss_files {
+            css_content += format!("\n/* {scss_file:?} */\n").as_str();
+            css_content +=
+                compile_scss_from(&self.manifest.scss_root.join(scss_file), &fs)?.as_str();
+            css_content += "\n";
+        }
+
+        #[cfg(feature = "css_minify")]
+        {
+            css_content = minifier::css::minify(&css_content)
+                .map_err(|e| ScssError::MinificationError(e.to_string()))?
+                .to_string();
+        }
+        Ok(css_content.into_bytes())
+    }
+
+    fn load_static_file(&self, f: &str) -> Result<StorageData, BundleStorageError> {
+        let fpath = normalize_relative_path(f).map_err(|e| match e {
+            RelativePathError::NoFileName => {
+                BundleStorageError::BadRequest("no file name".to_string())
+            }
+            RelativePathError::IllegalName => {
+                BundleStorageError::BadRequest("illegal characters in file name".to_string())
+            }
+            RelativePathError::Traversal => {
+                log::error!("Possible directory traversal attack spotted");
+                log::error!("Got a request for static file {f:?}");
+                BundleStorageError::AttackSuspected(
+                    "bundle-storage::static-file::directory-traversal".to_string(),
+                )
+            }
+        })?;
+        let fpath = Path::new(&fpath);
+
+        for inc in self.manifest.include_assets.iter() {
+            if let Ok(data) = self.index.get(&inc.join(fpath)) {
+                return Ok(StorageData::StaticFileData(data.clone()));
+            }
+        }
+        if fpath.extension().is_some_and(|ext| ext == "css") {
+            let fname = fpath.file_name().unwrap().to_string_lossy();
+            return Ok(StorageData::StaticFileData(self.load_css(&fname)?));
+        }
+        Err(BundleStorageError::DataNotFound(format!("{fpath:?}")))
+    }
+
+    pub fn dispatch(&self, qry: StorageQuery) -> Result<StorageData, BundleStorageError> {
+        let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
+            (sort_key, rev)
+        } else {
+            (&self.manifest.default_sort.0, self.manifest.default_sort.1)
+        };
+        let lang = self.select_lang(&qry);
+        let limit = if qry.limit == 0 {
+            usize::MAX
+        } else {
+            qry.limit
+        };
+        let slug = &qry.storage_slug;
+        let now = timestamp_now();
+        let drafts = qry.drafts;
+
+        match qry.method {
+            StorageQueryMethod::NoOp => {
+                log::debug!("Bundle storage No Op");
+                Ok(StorageData::Nothing)
+            }
+
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                let (path, bundle, lang) = self.page_path(&qry, name);
+                let (mut metadata, body) = self.load_content(&path)?;
+                if !metadata.is_visible(now, drafts) {
+                    return Err(BundleStorageError::DataNotFound(format!("{path:?}")));
+                }
+                metadata.bundle = bundle;
+                metadata.translations = self.page_translations(slug, name);
+                Ok(StorageData::PageContent {
+                    metadata,
+                    body,
+                    lang,
+                })
+            }
+
+            StorageQueryMethod::ContentNumId(id) | StorageQueryMethod::ContentLegacyId(id) => {
+                let pages = self.all_pages(slug)?;
+                // An id shared by several pages is refused, whichever of them is visible
+                let mut matches = pages.iter().filter(|(_, m)| qry.method.matches_id(m));
+                let Some((path, page)) = matches.next() else {
+                    return Err(BundleStorageError::NoMatch(format!("id = {id}")));
+                };
+                let other_matches = matches.count();
+                if other_matches > 0 {
+                    return Err(BundleStorageError::TooManyMatches(other_matches, 1));
+                }
+                if !page.is_visible(now, drafts) {
+                    return Err(BundleStorageError::NoMatch(format!("id = {id}")));
+                }
+                let (mut metadata, body) = self.load_content(Path::new(path))?;
+                metadata.bundle = page.bundle;
+                metadata.lang = page.lang.clone();
+                let dir = self.manifest.data_root.join(slug);
+                let rel = Path::new(path)
+                    .strip_prefix(&dir)
+                    .unwrap_or(Path::new(path));
+                let name = page_file_name(&rel.to_string_lossy(), &self.manifest.supported_lang);
+                metadata.translations = self.page_translations(slug, &name);
+                Ok(StorageData::PageContent {
+                    lang: metadata.lang.clone(),
+                    metadata,
+                    body,
+                })
+            }
+
+            StorageQueryMethod::RecentPages => {
+                let pages = self.all_pages(slug)?;
+                let mut results = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m))
+                    .collect::<Vec<&PageMetadata>>();
+                results.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    results.reverse();
+                }
+                let total = results.len();
+                let results = results.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::RecentPages(results.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
+                let pages = self.all_pages(slug)?;
+                let mut matches = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && match (m.get_metadata(keys), val.as_ref()) {
+                                (Some(md), Some(val)) => compare_similar_md(md, val),
+                                (Some(_), None) | (None, Some(_)) => false,
+                                (None, None) => true,
+                            }
+                    })
+                    .collect::<Vec<&PageMetadata>>();
+                matches.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    matches.reverse();
+                }
+                let total = matches.len();
+                let matches = matches.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::SimilarPages(matches.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
+                let pages = self.all_pages(slug)?;
+                let matches = pages
+                    .iter()
+                    .filter(|(_, m)| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && m.get_metadata(keys) == val.as_ref()
+                    })
+                    .filter_map(|(_, m)| m.get_metadata(query).cloned())
+                    .collect::<Vec<serde_json::Value>>();
+                Ok(StorageData::QueryMetadata(matches))
+            }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                let pages = self.all_pages(slug)?;
+                let groups = agg.apply(
+                    pages
+                        .iter()
+                        .map(|(_, m)| m)
+                        .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m)),
+                    sort_key,
+                );
+                Ok(StorageData::Aggregates(groups))
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                // The first language of the query or of its fallbacks the context is in
+                for lang in lang_candidates(&qry, &self.manifest.supported_lang) {
+                    let path = self.content_path(slug, name, lang.as_ref(), "toml");
+                    let data = match self.index.get_text(&path) {
+                        Ok(data) => data,
+                        Err(e) if e.is_not_found() => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let ctxt: toml::Value = toml::from_str(data)
+                        .map_err(|e| BundleStorageError::TomlDecode(format!("{path:?}: {e:?}")))?;
+                    return Ok(StorageData::Context(ctxt));
+                }
+                let path = self.content_path(slug, name, lang.as_ref(), "toml");
+                Err(BundleStorageError::DataNotFound(format!("{path:?}")))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                let root = &self.manifest.template_root;
+                if !self.index.is_dir(root) {
+                    return Err(BundleStorageError::DataNotFound(format!("{root:?}")));
+                }
+                let mut templates = HashMap::new();
+                for (name, path) in self.index.list(root) {
+                    let Ok(content) = self.index.get_text(Path::new(path)) else {
+                        log::warn!("Cannot load template {name}: not UTF-8");
+                        continue;
+                    };
+                    templates.insert(name.to_string(), content.to_string());
+                }
+                Ok(StorageData::Templates(templates))
+            }
+
+            StorageQueryMethod::StaticFile(ref f) => self.load_static_file(f),
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                let fpath = normalize_relative_path(asset).map_err(|e| {
+                    log::error!("Possible directory traversal attack spotted");
+                    log::error!("Got a request for asset {asset:?} of page {name:?}: {e:?}");
+                    BundleStorageError::AttackSuspected(
+                        "bundle-storage::page-asset::directory-traversal".to_string(),
+                    )
+                })?;
+                let (index, bundle, _) = self.page_path(&qry, name);
+                // Safe to unwrap, a page is always in the directory of its storage
+                let path = index.parent().unwrap().join(&fpath);
+                if !bundle
+                    || is_bundle_index(&fpath)
+                    || !self.load_content(&index)?.0.is_visible(now, drafts)
+                {
+                    return Err(BundleStorageError::DataNotFound(format!("{path:?}")));
+                }
+                Ok(StorageData::StaticFileData(self.index.get(&path)?.clone()))
+            }
+        }
+    }
+
+    pub fn select_lang(&self, qry: &StorageQuery) -> Option<String> {
+        let lang = qry.lang_pref.as_ref()?;
+        lang.iter()
+            .find_map(|l| supported_lang_of(l, &self.manifest.supported_lang))
+            .cloned()
+    }
+}
+
+impl StorageBackend for BundleStorage {
+    type Error = BundleStorageError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        self.open(config)?;
+        log::debug!(
+            "Initialized bundle storage from {:?}, {} files",
+            self.archive,
+            self.index.files.len()
+        );
+        log::debug!("Supported langs: {:?}", self.manifest.supported_lang);
+        Ok(())
+    }
+
+    // The archive is only read at startup
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        false
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        match self.dispatch(qry) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{e:?}");
+                StorageData::Error(e.into())
+            }
+        }
+    }
+
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        log::error!("Unable to write {wrt:?}: bundle storage is read-only");
+        Err(BundleStorageError::ReadOnly)
+    }
+
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        self.all_pages(&qry.storage_slug).ok()?;
+        self.schedules.read().get(&qry.storage_slug)?.next(now)
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.manifest.supported_lang.clone()
+    }
+}
+
+/// Lets the SCSS compiler resolve imports from the files of the bundle
+#[derive(Debug)]
+struct BundleFs<'a>(&'a BundleIndex);
+
+impl BundleFs<'_> {
+    // Path inside the bundle, None if it goes above its root
+    fn bundle_path(path: &Path) -> Option<PathBuf> {
+        let mut res = PathBuf::new();
+        for comp in path.components() {
+            match comp {
+                Component::Normal(part) => res.push(part),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !res.pop() {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(res)
+    }
+}
+
+impl grass::Fs for BundleFs<'_> {
+    fn is_dir(&self, path: &Path) -> bool {
+        Self::bundle_path(path).is_some_and(|p| self.0.is_dir(&p))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        Self::bundle_path(path).is_some_and(|p| self.0.get(&p).is_ok())
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        Self::bundle_path(path)
+            .and_then(|p| self.0.get(&p).ok().cloned())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+    }
+}
+
+enum BundleWriter {
+    TarZstd(tar::Builder<zstd::stream::AutoFinishEncoder<'static, std::fs::File>>),
+    Zip(Box<zip::ZipWriter<std::fs::File>>),
+}
+
+impl BundleWriter {
+    fn create(path: &Path) -> Result<BundleWriter, BundleStorageError> {
+        let format = ArchiveFormat::of(path)?;
+        let file = std::fs::File::create(path)
+            .map_err(|e| BundleStorageError::WriteArchive(format!("{path:?}: {e:?}")))?;
+        Ok(match format {
+            ArchiveFormat::TarZstd => {
+                let encoder = zstd::Encoder::new(file, 19)
+                    .map_err(|e| BundleStorageError::WriteArchive(format!("{e:?}")))?;
+                BundleWriter::TarZstd(tar::Builder::new(encoder.auto_finish()))
+            }
+            ArchiveFormat::Zip => BundleWriter::Zip(Box::new(zip::ZipWriter::new(file))),
+        })
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<(), BundleStorageError> {
+        let write_err =
+            |e: &dyn std::fmt::Debug| BundleStorageError::WriteArchive(format!("{name}: {e:?}"));
+        match self {
+            BundleWriter::TarZstd(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, name, data)
+                    .map_err(|e| write_err(&e))
+            }
+            BundleWriter::Zip(writer) => {
+                writer
+                    .start_file(name, zip::write::SimpleFileOptions::default())
+                    .map_err(|e| write_err(&e))?;
+                writer.write_all(data).map_err(|e| write_err(&e))
+            }
+        }
+    }
+
+    fn add_dir(&mut self, root: &Path, name: &str) -> Result<(), BundleStorageError> {
+        let dir = root.join(name);
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| BundleStorageError::ReadSource(format!("{dir:?}: {e:?}")))?;
+        let mut entries = entries
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| BundleStorageError::ReadSource(format!("{dir:?}: {e:?}")))?;
+        entries.sort();
+
+        for fname in entries {
+            let Some(fname) = fname.to_str() else {
+                log::warn!("Skipping {fname:?} in {dir:?}: illegal filename");
+                continue;
+            };
+            let entry_name = format!("{name}/{fname}");
+            let path = root.join(&entry_name);
+            if path.is_dir() {
+                self.add_dir(root, &entry_name)?;
+            } else {
+                let data = std::fs::read(&path)
+                    .map_err(|e| BundleStorageError::ReadSource(format!("{path:?}: {e:?}")))?;
+                self.add(&entry_name, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), BundleStorageError> {
+        let write_err =
+            |e: &dyn std::fmt::Debug| BundleStorageError::WriteArchive(format!("{e:?}"));
+        match self {
+            BundleWriter::TarZstd(builder) => {
+                builder.into_inner().map_err(|e| write_err(&e))?;
+            }
+            BundleWriter::Zip(writer) => {
+                writer.finish().map_err(|e| write_err(&e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Packs the files of the LocalStorage from the configuration into a bundle
+#[cfg(feature = "storage-local")]
+pub fn pack(config: &Config, output: &Path) -> Result<(), BundleStorageError> {
+    let super::Backend::Local(ref local) = config.storage else {
+        return Err(BundleStorageError::NotLocalStorage);
+    };
+
+    // Paths are kept as written in the config, they have to stay inside its root
+    let mut names = vec![];
+    for dir in local.source_dirs() {
+        let name = normalize_relative_path(&dir.to_string_lossy())
+            .map_err(|_| BundleStorageError::BadSourceDir(dir.clone()))?;
+        names.push(name);
+    }
+    names.sort();
+    // Directories inside another one are already packed with it
+    let mut dirs: Vec<String> = vec![];
+    for name in names {
+        if !dirs
+            .iter()
+            .any(|d| *d == name || name.starts_with(&format!("{d}/")))
+        {
+            dirs.push(name);
+        }
+    }
+    let manifest =
+        toml::to_string(local).map_err(|e| BundleStorageError::TomlEncode(format!("{e:?}")))?;
+
+    let mut writer = BundleWriter::create(output)?;
+    writer.add(BUNDLE_MANIFEST, manifest.as_bytes())?;
+    for dir in dirs {
+        log::debug!("Packing {dir} into {output:?}");
+        writer.add_dir(&config.root, &dir)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(toml: &str) -> Result<BundleManifest, BundleStorageError> {
+        let mut manifest: BundleManifest = toml::from_str(toml).unwrap();
+        manifest.normalize_paths()?;
+        Ok(manifest)
+    }
+
+    #[test]
+    fn refuses_to_serve_the_archive_root() {
+        let roots = "data_root = \"./data\"\ntemplate_root = \"templates/\"";
+        let ok = manifest(&format!("{roots}\ninclude_assets = [\"assets\"]")).unwrap();
+        assert_eq!(ok.data_root, PathBuf::from("data"));
+        assert_eq!(ok.template_root, PathBuf::from("templates"));
+        assert_eq!(ok.scss_root, PathBuf::new());
+
+        for assets in ["\"\"", "\".\"", "\"/\"", "\"a/..\""] {
+            let res = manifest(&format!("{roots}\ninclude_assets = [{assets}]"));
+            assert!(
+                matches!(res, Err(BundleStorageError::BadSourceDir(_))),
+                "{assets}"
+            );
+        }
+        for roots in [
+            "template_root = \"t\"",
+            "data_root = \".\"\ntemplate_root = \"t\"",
+        ] {
+            let res = manifest(roots);
+            assert!(
+                matches!(res, Err(BundleStorageError::BadSourceDir(_))),
+                "{roots}"
+            );
+        }
+    }
+}
@@ -0,0 +1,971 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use git2::{Commit, DiffOptions, ObjectType, Oid, Repository, Sort, Tree, TreeWalkMode};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::frontmatter::{parse_page_content, FrontmatterError};
+use crate::page::{compare_similar_md, timestamp_now, PageMetadata};
+use crate::scss::{compile_scss_from, ScssError};
+use crate::storage::query::StorageQueryMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
+
+use super::{
+    check_id_collisions, is_bundle_index, lang_candidates, normalize_relative_path,
+    page_file_candidates, page_file_id_path, page_file_lang, page_file_name, supported_lang_of,
+    PageFiles, PageSchedules, RelativePathError, StorageBackend,
+};
+
+// Metadata key under which the last commit of a page is exposed
+const LAST_COMMIT_KEY: &str = "last_commit";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum GitStorageError {
+    OpenRepository(String),
+    NotInitialized,
+    Git(String),
+
+    RefNotFound(String),
+    RefNotPreviewable(String),
+
+    DataNotFound(String),
+    NotUtf8(String),
+    Frontmatter(FrontmatterError),
+
+    NoMatch(String),
+    TooManyMatches(usize, usize),
+
+    TomlDecode(String),
+
+    CssNotFound(String),
+    ScssProcess(ScssError),
+
+    BadRequest(String),
+    AttackSuspected(String),
+
+    ReadOnly,
+}
+
+impl From<GitStorageError> for HttpResponseBuilder {
+    fn from(val: GitStorageError) -> Self {
+        match val {
+            // Don't disclose which refs exist in the repository
+            GitStorageError::DataNotFound(_)
+            | GitStorageError::NoMatch(_)
+            | GitStorageError::RefNotPreviewable(_) => HttpResponse::NotFound(),
+            _ => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+impl GitStorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            GitStorageError::DataNotFound(_)
+                | GitStorageError::NoMatch(_)
+                | GitStorageError::CssNotFound(_)
+        )
+    }
+}
+
+impl From<FrontmatterError> for GitStorageError {
+    fn from(value: FrontmatterError) -> Self {
+        GitStorageError::Frontmatter(value)
+    }
+}
+
+impl From<git2::Error> for GitStorageError {
+    fn from(value: git2::Error) -> Self {
+        GitStorageError::Git(format!("{value:?}"))
+    }
+}
+
+impl From<ScssError> for GitStorageError {
+    fn from(value: ScssError) -> Self {
+        GitStorageError::ScssProcess(value)
+    }
+}
+
+// git2::Repository is neither Debug nor Sync
+#[derive(Default)]
+struct RepoHandle(Mutex<Option<Repository>>);
+
+impl std::fmt::Debug for RepoHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RepoHandle")
+    }
+}
+
+/// Last commit that modified a file, exposed in the page metadata
+#[derive(Debug, Clone, Serialize)]
+struct CommitInfo {
+    commit: String,
+    date: i64,
+    author: String,
+    email: String,
+}
+
+impl CommitInfo {
+    fn of(commit: &Commit) -> CommitInfo {
+        let author = commit.author();
+        CommitInfo {
+            commit: commit.id().to_string(),
+            date: commit.time().seconds(),
+            author: author.name().unwrap_or_default().to_string(),
+            email: author.email().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+// Pages of a storage slug at the commit a ref pointed to when they were listed,
+// with the dates they get published or expire at
+type PagesIndex = HashMap<(String, String), (Oid, Arc<Vec<(String, PageMetadata)>>, PageSchedules)>;
+
+// Last commit of every page file at the commit a ref pointed to
+type PagesHistory = HashMap<String, (Oid, Arc<HashMap<String, CommitInfo>>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitStorage {
+    #[serde(skip)]
+    repo: Arc<RepoHandle>,
+    #[serde(skip)]
+    indexes: Arc<Mutex<PagesIndex>>,
+    #[serde(skip)]
+    history: Arc<Mutex<PagesHistory>>,
+    // Commit of the ref each query was answered from
+    #[serde(skip)]
+    answered: Arc<Mutex<HashMap<StorageQuery, Oid>>>,
+
+    repository: PathBuf,
+    // Branch, tag or any revision git understands
+    #[serde(default = "default_reference")]
+    reference: String,
+    // Other refs that can be previewed using "?preview=<ref>"
+    #[serde(default)]
+    preview_refs: Vec<String>,
+
+    // Data, paths are relative to the root of the repository
+    #[serde(default)]
+    data_root: PathBuf,
+    #[serde(default)]
+    supported_lang: Vec<String>,
+    #[serde(default)]
+    default_sort: (Vec<String>, bool),
+
+    // Templates
+    #[serde(default)]
+    template_root: PathBuf,
+
+    // Assets
+    #[serde(default)]
+    include_assets: Vec<PathBuf>,
+
+    // CSS
+    #[serde(default)]
+    scss: HashMap<String, Vec<PathBuf>>,
+    #[serde(default)]
+    scss_root: PathBuf,
+}
+
+fn default_reference() -> String {
+    "HEAD".to_string()
+}
+
+impl GitStorage {
+    pub fn open(&mut self, config: &Config) -> Result<(), GitStorageError> {
+        self.repository = config.root.join(&self.repository);
+        let repo = Repository::open(&self.repository).map_err(|e| {
+            GitStorageError::OpenRepository(format!("{:?}: {e:?}", self.repository))
+        })?;
+        resolve_ref(&repo, &self.reference)?;
+        *self.repo.0.lock() = Some(repo);
+        Ok(())
+    }
+
+    fn with_repo<F, R>(&self, f: F) -> Result<R, GitStorageError>
+    where
+        F: FnOnce(&Repository) -> Result<R, GitStorageError>,
+    {
+        let repo = self.repo.0.lock();
+        let Some(ref repo) = *repo else {
+            return Err(GitStorageError::NotInitialized);
+        };
+        f(repo)
+    }
+
+    // Ref to read the data of a query from, only configured refs can be previewed
+    fn query_ref<'a>(&'a self, qry: &'a StorageQuery) -> Result<&'a String, GitStorageError> {
+        match qry.revision {
+            None => Ok(&self.reference),
+            Some(ref rev) if self.preview_refs.contains(rev) => Ok(rev),
+            Some(ref rev) => Err(GitStorageError::RefNotPreviewable(rev.clone())),
+        }
+    }
+
+    fn content_path(&self, slug: &str, name: &str, lang: Option<&String>, ext: &str) -> PathBuf {
+        let mut path = self.data_root.join(slug);
+        if let Some(lang) = lang {
+            path.push(lang);
+        }
+        path.push(format!("{name}.{ext}"));
+        path
+    }
+
+    // Path of a page in a language, as the first of its candidate files found in the tree,
+    // with whether it is a page bundle
+    fn find_page_path(
+        &self,
+        tree: &Tree,
+        slug: &str,
+        name: &str,
+        lang: Option<&String>,
+    ) -> Option<(PathBuf, bool)> {
+        let mut dir = self.data_root.join(slug);
+        if let Some(lang) = lang {
+            dir.push(lang);
+        }
+        page_file_candidates(name)
+            .into_iter()
+            .map(|(fname, bundle)| (dir.join(fname), bundle))
+            .find(|(path, _)| tree.get_path(path).is_ok())
+    }
+
+    // Path of a page in the first language of the query it is written in,
+    // with whether it is a page bundle and the language found
+    fn page_path(
+        &self,
+        tree: &Tree,
+        qry: &StorageQuery,
+        name: &str,
+    ) -> (PathBuf, bool, Option<String>) {
+        let slug = &qry.storage_slug;
+        for lang in lang_candidates(qry, &self.supported_lang) {
+            if let Some((path, bundle)) = self.find_page_path(tree, slug, name, lang.as_ref()) {
+                return (path, bundle, lang);
+            }
+        }
+        let lang = self.select_lang(qry);
+        let mut dir = self.data_root.join(slug);
+        if let Some(ref lang) = lang {
+            dir.push(lang);
+        }
+        (dir.join(&page_file_candidates(name)[0].0), false, lang)
+    }
+
+    // Languages a page is written in
+    fn page_translations(&self, tree: &Tree, slug: &str, name: &str) -> Vec<String> {
+        self.supported_lang
+            .iter()
+            .filter(|lang| self.find_page_path(tree, slug, name, Some(lang)).is_some())
+            .cloned()
+            .collect()
+    }
+
+    fn load_content(
+        &self,
+        repo: &Repository,
+        tree: &Tree,
+        history: &HashMap<String, CommitInfo>,
+        path: &Path,
+    ) -> Result<(PageMetadata, String), GitStorageError> {
+        let content = read_text(repo, tree, path)?;
+        let (mut metadata, body) = parse_page_content(&path.to_string_lossy(), &content)?;
+        let rel = path.strip_prefix(&self.data_root).unwrap_or(path);
+        metadata.update_id(page_file_id_path(&rel.to_string_lossy()));
+        metadata.set_default_format(&path.to_string_lossy());
+        // Added after the id is computed, so a new commit doesn't change it
+        if let Some(info) = history.get(path.to_string_lossy().as_ref()) {
+            let info = serde_json::to_value(info).unwrap_or_default();
+            metadata.metadata.insert(LAST_COMMIT_KEY.to_string(), info);
+        }
+        Ok((metadata, body))
+    }
+
+    // Last commit of every page of the ref, computed once per commit of the ref
+    fn pages_history(
+        &self,
+        repo: &Repository,
+        refname: &str,
+        oid: Oid,
+        tree: &Tree,
+    ) -> Result<Arc<HashMap<String, CommitInfo>>, GitStorageError> {
+        if let Some((hist_oid, hist)) = self.history.lock().get(refname) {
+            if *hist_oid == oid {
+                return Ok(hist.clone());
+            }
+        }
+
+        let paths = list_files(repo, tree, &self.data_root)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<HashSet<String>>();
+        let hist = Arc::new(last_commits(repo, oid, &self.data_root, paths)?);
+        self.history
+            .lock()
+            .insert(refname.to_string(), (oid, hist.clone()));
+        Ok(hist)
+    }
+
+    fn pages_index(
+        &self,
+        repo: &Repository,
+        refname: &str,
+        oid: Oid,
+        tree: &Tree,
+        slug: &String,
+    ) -> Result<Arc<Vec<(String, PageMetadata)>>, GitStorageError> {
+        let key = (refname.to_string(), slug.clone());
+        if let Some((idx_oid, pages, _)) = self.indexes.lock().get(&key) {
+            if *idx_oid == oid {
+                return Ok(pages.clone());
+            }
+        }
+
+        let dirpath = self.data_root.join(slug);
+        if subtree(repo, tree, &dirpath)?.is_none() {
+            return Err(GitStorageError::DataNotFound(format!("{dirpath:?}")));
+        }
+        let history = self.pages_history(repo, refname, oid, tree)?;
+        let files = list_files(repo, tree, &dirpath)?
+            .into_iter()
+            .filter_map(|(path, _)| {
+                let rel = Path::new(&path).strip_prefix(&dirpath).ok()?;
+                Some((rel.to_string_lossy().to_string(), path))
+            })
+            .collect::<Vec<(String, String)>>();
+        let page_files = PageFiles::new(
+            files.iter().map(|(rel, _)| rel.as_str()),
+            &self.supported_lang,
+        );
+        let mut pages = vec![];
+        for (rel, path) in files {
+            if page_files.is_page(&rel) {
+                let (mut metadata, _) =
+                    self.load_content(repo, tree, &history, Path::new(&path))?;
+                metadata.bundle = is_bundle_index(&rel);
+                metadata.lang = page_file_lang(&rel, &self.supported_lang);
+                pages.push((path, metadata));
+            }
+        }
+        log::debug!("Registered {} pages in {slug} at {refname}", pages.len());
+        check_id_collisions(slug, pages.iter());
+        let schedules = PageSchedules::new(pages.iter().map(|(_, m)| m));
+        let pages = Arc::new(pages);
+        self.indexes
+            .lock()
+            .insert(key, (oid, pages.clone(), schedules));
+        Ok(pages)
+    }
+
+    fn load_css(
+        &self,
+        repo: &Repository,
+        tree: &Tree,
+        css: &str,
+    ) -> Result<Vec<u8>, GitStorageError> {
+        let Some(scss_files) = self.scss.get(css) else {
+            return Err(GitStorageError::CssNotFound(css.to_string()));
+        };
+
+        let fs = TreeFs { repo, tree };
+        let mut css_content = String::new();
+        for scss_file in scss_files {
+            css_content += format!("\n/* {scss_file:?} */\n").as_str();
+            css_content += compile_scss_from(&self.scss_root.join(scss_file), &fs)?.as_str();
+            css_content += "\n";
+        }
+
+        #[cfg(feature = "css_minify")]
+        {
+            css_content = minifier::css::minify(&css_content)
+                .map_err(|e| ScssError::MinificationError(e.to_string()))?
+                .to_string();
+        }
+        Ok(css_content.into_bytes())
+    }
+
+    fn load_static_file(
+        &self,
+        repo: &Repository,
+        tree: &Tree,
+        f: &str,
+    ) -> Result<StorageData, GitStorageError> {
+        let fpath = normalize_relative_path(f).map_err(|e| match e {
+            RelativePathError::NoFileName => {
+                GitStorageError::BadRequest("no file name".to_string())
+            }
+            RelativePathError::IllegalName => {
+                GitStorageError::BadRequest("illegal characters in file name".to_string())
+            }
+            RelativePathError::Traversal => {
+                log::error!("Possible directory traversal attack spotted");
+                log::error!("Got a request for static file {f:?}");
+                GitStorageError::AttackSuspected(
+                    "git-storage::static-file::directory-traversal".to_string(),
+                )
+            }
+        })?;
+        let fpath = Path::new(&fpath);
+
+        for inc in self.include_assets.iter() {
+            match read_blob(repo, tree, &inc.join(fpath)) {
+                Ok(data) => return Ok(StorageData::StaticFileData(data)),
+                Err(GitStorageError::DataNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if fpath.extension().is_some_and(|ext| ext == "css") {
+            let fname = fpath.file_name().unwrap().to_string_lossy();
+            return Ok(StorageData::StaticFileData(
+                self.load_css(repo, tree, &fname)?,
+            ));
+        }
+        Err(GitStorageError::DataNotFound(format!("{fpath:?}")))
+    }
+
+    pub fn dispatch(
+        &self,
+        repo: &Repository,
+        qry: StorageQuery,
+    ) -> Result<StorageData, GitStorageError> {
+        let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
+            (sort_key, rev)
+        } else {
+            (&self.default_sort.0, self.default_sort.1)
+        };
+        let lang = self.select_lang(&qry);
+        let limit = if qry.limit == 0 {
+            usize::MAX
+        } else {
+            qry.limit
+        };
+        let refname = self.query_ref(&qry)?;
+        let commit = resolve_ref(repo, refname)?;
+        let tree = commit.tree()?;
+        let slug = &qry.storage_slug;
+        let now = timestamp_now();
+        let drafts = qry.drafts;
+
+        match qry.method {
+            StorageQueryMethod::NoOp => {
+                log::debug!("Git storage No Op");
+                Ok(StorageData::Nothing)
+            }
+
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                let (path, bundle, lang) = self.page_path(&tree, &qry, name);
+                let history = self.pages_history(repo, refname, commit.id(), &tree)?;
+                let (mut metadata, body) = self.load_content(repo, &tree, &history, &path)?;
+                if !metadata.is_visible(now, drafts) {
+                    return Err(GitStorageError::DataNotFound(format!("{path:?}")));
+                }
+                metadata.bundle = bundle;
+                metadata.translations = self.page_translations(&tree, slug, name);
+                Ok(StorageData::PageContent {
+                    metadata,
+                    body,
+                    lang,
+                })
+            }
+
+            StorageQueryMethod::ContentNumId(id) | StorageQueryMethod::ContentLegacyId(id) => {
+                let pages = self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+                // An id shared by several pages is refused, whichever of them is visible
+                let mut matches = pages.iter().filter(|(_, m)| qry.method.matches_id(m));
+                let Some((path, page)) = matches.next() else {
+                    return Err(GitStorageError::NoMatch(format!("id = {id}")));
+                };
+                let other_matches = matches.count();
+                if other_matches > 0 {
+                    return Err(GitStorageError::TooManyMatches(other_matches, 1));
+                }
+                if !page.is_visible(now, drafts) {
+                    return Err(GitStorageError::NoMatch(format!("id = {id}")));
+                }
+                let history = self.pages_history(repo, refname, commit.id(), &tree)?;
+                let (mut metadata, body) =
+                    self.load_content(repo, &tree, &history, Path::new(path))?;
+                metadata.bundle = page.bundle;
+                metadata.lang = page.lang.clone();
+                let dir = self.data_root.join(slug);
+                let rel = Path::new(path)
+                    .strip_prefix(&dir)
+                    .unwrap_or(Path::new(path));
+                let name = page_file_name(&rel.to_string_lossy(), &self.supported_lang);
+                metadata.translations = self.page_translations(&tree, slug, &name);
+                Ok(StorageData::PageContent {
+                    lang: metadata.lang.clone(),
+                    metadata,
+                    body,
+                })
+            }
+
+            StorageQueryMethod::RecentPages => {
+                let pages = self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+                let mut results = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m))
+                    .collect::<Vec<&PageMetadata>>();
+                results.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    results.reverse();
+                }
+                let total = results.len();
+                let results = results.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::RecentPages(results.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
+                let pages = self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+                let mut matches = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && match (m.get_metadata(keys), val.as_ref()) {
+                                (Some(md), Some(val)) => compare_similar_md(md, val),
+                                (Some(_), None) | (None, Some(_)) => false,
+                                (None, None) => true,
+                            }
+                    })
+                    .collect::<Vec<&PageMetadata>>();
+                matches.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    matches.reverse();
+                }
+                let total = matches.len();
+                let matches = matches.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::SimilarPages(matches.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
+                let pages = self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+                let matches = pages
+                    .iter()
+                    .filter(|(_, m)| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && m.get_metadata(keys) == val.as_ref()
+                    })
+                    .filter_map(|(_, m)| m.get_metadata(query).cloned())
+                    .collect::<Vec<serde_json::Value>>();
+                Ok(StorageData::QueryMetadata(matches))
+            }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                let pages = self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+                let groups = agg.apply(
+                    pages
+                        .iter()
+                        .map(|(_, m)| m)
+                        .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m)),
+                    sort_key,
+                );
+                Ok(StorageData::Aggregates(groups))
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                // The first language of the query or of its fallbacks the context is in
+                for lang in lang_candidates(&qry, &self.supported_lang) {
+                    let path = self.content_path(slug, name, lang.as_ref(), "toml");
+                    let data = match read_text(repo, &tree, &path) {
+                        Ok(data) => data,
+                        Err(e) if e.is_not_found() => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let ctxt: toml::Value = toml::from_str(&data)
+                        .map_err(|e| GitStorageError::TomlDecode(format!("{path:?}: {e:?}")))?;
+                    return Ok(StorageData::Context(ctxt));
+                }
+                let path = self.content_path(slug, name, lang.as_ref(), "toml");
+                Err(GitStorageError::DataNotFound(format!("{path:?}")))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                let mut templates = HashMap::new();
+                let Some(root) = subtree(repo, &tree, &self.template_root)? else {
+                    return Err(GitStorageError::DataNotFound(format!(
+                        "{:?}",
+                        self.template_root
+                    )));
+                };
+                for (name, oid) in list_blobs(&root)? {
+                    let blob = repo.find_blob(oid)?;
+                    let Ok(content) = std::str::from_utf8(blob.content()) else {
+                        log::warn!("Cannot load template {name}: not UTF-8");
+                        continue;
+                    };
+                    templates.insert(name, content.to_string());
+                }
+                Ok(StorageData::Templates(templates))
+            }
+
+            StorageQueryMethod::StaticFile(ref f) => self.load_static_file(repo, &tree, f),
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                let fpath = normalize_relative_path(asset).map_err(|e| {
+                    log::error!("Possible directory traversal attack spotted");
+                    log::error!("Got a request for asset {asset:?} of page {name:?}: {e:?}");
+                    GitStorageError::AttackSuspected(
+                        "git-storage::page-asset::directory-traversal".to_string(),
+                    )
+                })?;
+                let (index, bundle, _) = self.page_path(&tree, &qry, name);
+                // Safe to unwrap, a page is always in the directory of its storage
+                let path = index.parent().unwrap().join(&fpath);
+                if !bundle || is_bundle_index(&fpath) {
+                    return Err(GitStorageError::DataNotFound(format!("{path:?}")));
+                }
+                let history = self.pages_history(repo, refname, commit.id(), &tree)?;
+                let (metadata, _) = self.load_content(repo, &tree, &history, &index)?;
+                if !metadata.is_visible(now, drafts) {
+                    return Err(GitStorageError::DataNotFound(format!("{path:?}")));
+                }
+                Ok(StorageData::StaticFileData(read_blob(repo, &tree, &path)?))
+            }
+        }
+    }
+
+    pub fn select_lang(&self, qry: &StorageQuery) -> Option<String> {
+        let lang = qry.lang_pref.as_ref()?;
+        lang.iter()
+            .find_map(|l| supported_lang_of(l, &self.supported_lang))
+            .cloned()
+    }
+}
+
+impl StorageBackend for GitStorage {
+    type Error = GitStorageError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        self.open(config)?;
+        log::debug!(
+            "Initialized git storage from {:?} at {}",
+            self.repository,
+            self.reference
+        );
+        log::debug!("Supported langs: {:?}", self.supported_lang);
+        Ok(())
+    }
+
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        let Some(answered) = self.answered.lock().get(qry).copied() else {
+            return true;
+        };
+        let current = self.with_repo(|repo| Ok(resolve_ref(repo, self.query_ref(qry)?)?.id()));
+        match current {
+            Ok(current) => current != answered,
+            Err(e) => {
+                log::error!("{e:?}");
+                true
+            }
+        }
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        let res = self.with_repo(|repo| {
+            // Errors are cached as well, so the commit is recorded in any case
+            if let Ok(commit) = resolve_ref(repo, self.query_ref(&qry)?) {
+                self.answered.lock().insert(qry.clone(), commit.id());
+            }
+            self.dispatch(repo, qry)
+        });
+        match res {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{e:?}");
+                StorageData::Error(e.into())
+            }
+        }
+    }
+
+    // Content is changed by pushing commits to the repository
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        log::error!("Unable to write {wrt:?}: git storage is read-only");
+        Err(GitStorageError::ReadOnly)
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        self.answered.lock().remove(qry);
+    }
+
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        let res = self.with_repo(|repo| {
+            let refname = self.query_ref(qry)?;
+            let commit = resolve_ref(repo, refname)?;
+            let tree = commit.tree()?;
+            let slug = &qry.storage_slug;
+            self.pages_index(repo, refname, commit.id(), &tree, slug)?;
+            let key = (refname.to_string(), slug.clone());
+            Ok(self
+                .indexes
+                .lock()
+                .get(&key)
+                .and_then(|(_, _, s)| s.next(now)))
+        });
+        res.inspect_err(|e| log::error!("{e:?}")).ok().flatten()
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.supported_lang.clone()
+    }
+}
+
+fn resolve_ref<'r>(repo: &'r Repository, refname: &str) -> Result<Commit<'r>, GitStorageError> {
+    repo.revparse_single(refname)
+        .and_then(|obj| obj.peel_to_commit())
+        .map_err(|e| GitStorageError::RefNotFound(format!("{refname}: {e:?}")))
+}
+
+fn subtree<'r>(
+    repo: &'r Repository,
+    tree: &Tree<'r>,
+    path: &Path,
+) -> Result<Option<Tree<'r>>, GitStorageError> {
+    if path.as_os_str().is_empty() {
+        return Ok(Some(tree.clone()));
+    }
+    let Ok(entry) = tree.get_path(path) else {
+        return Ok(None);
+    };
+    if entry.kind() != Some(ObjectType::Tree) {
+        return Ok(None);
+    }
+    Ok(Some(entry.to_object(repo)?.peel_to_tree()?))
+}
+
+// Every file of the tree, with its path relative to the tree
+fn list_blobs(tree: &Tree) -> Result<Vec<(String, Oid)>, GitStorageError> {
+    let mut blobs = vec![];
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                blobs.push((format!("{dir}{name}"), entry.id()));
+            }
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    Ok(blobs)
+}
+
+// Every file under a directory, with its path from the root of the repository
+fn list_files(
+    repo: &Repository,
+    tree: &Tree,
+    root: &Path,
+) -> Result<Vec<(String, Oid)>, GitStorageError> {
+    let Some(dir) = subtree(repo, tree, root)? else {
+        return Ok(vec![]);
+    };
+    Ok(list_blobs(&dir)?
+        .into_iter()
+        .map(|(path, oid)| (root.join(path).to_string_lossy().to_string(), oid))
+        .collect())
+}
+
+fn read_blob(repo: &Repository, tree: &Tree, path: &Path) -> Result<Vec<u8>, GitStorageError> {
+    let entry = tree
+        .get_path(path)
+        .map_err(|_| GitStorageError::DataNotFound(format!("{path:?}")))?;
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Err(GitStorageError::DataNotFound(format!("{path:?}")));
+    }
+    Ok(repo.find_blob(entry.id())?.content().to_vec())
+}
+
+fn read_text(repo: &Repository, tree: &Tree, path: &Path) -> Result<String, GitStorageError> {
+    String::from_utf8(read_blob(repo, tree, path)?)
+        .map_err(|_| GitStorageError::NotUtf8(format!("{path:?}")))
+}
+
+// Walks the history from the head until the last commit of every path is found
+fn last_commits(
+    repo: &Repository,
+    head: Oid,
+    root: &Path,
+    paths: HashSet<String>,
+) -> Result<HashMap<String, CommitInfo>, GitStorageError> {
+    let mut found = HashMap::new();
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(head)?;
+
+    let mut opts = DiffOptions::new();
+    if !root.as_os_str().is_empty() {
+        opts.pathspec(root.to_string_lossy().as_ref());
+    }
+    for oid in walk {
+        if found.len() == paths.len() {
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut opts))?;
+        for delta in diff.deltas() {
+            let Some(path) = delta.new_file().path().and_then(|p| p.to_str()) else {
+                continue;
+            };
+            if paths.contains(path) && !found.contains_key(path) {
+                found.insert(path.to_string(), CommitInfo::of(&commit));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Lets the SCSS compiler resolve imports from the tree of a commit
+struct TreeFs<'a> {
+    repo: &'a Repository,
+    tree: &'a Tree<'a>,
+}
+
+impl std::fmt::Debug for TreeFs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TreeFs({})", self.tree.id())
+    }
+}
+
+impl TreeFs<'_> {
+    // Path inside the tree, None if it goes above its root
+    fn tree_path(path: &Path) -> Option<PathBuf> {
+        let mut res = PathBuf::new();
+        for comp in path.components() {
+            match comp {
+                Component::Normal(part) => res.push(part),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !res.pop() {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(res)
+    }
+
+    fn kind(&self, path: &Path) -> Option<ObjectType> {
+        let path = Self::tree_path(path)?;
+        if path.as_os_str().is_empty() {
+            return Some(ObjectType::Tree);
+        }
+        self.tree.get_path(&path).ok()?.kind()
+    }
+}
+
+impl grass::Fs for TreeFs<'_> {
+    fn is_dir(&self, path: &Path) -> bool {
+        self.kind(path) == Some(ObjectType::Tree)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.kind(path) == Some(ObjectType::Blob)
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        let not_found = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        let path = Self::tree_path(path).ok_or_else(not_found)?;
+        read_blob(self.repo, self.tree, &path).map_err(|_| not_found())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use git2::Signature;
+
+    use super::*;
+    use crate::storage::backend::Backend;
+    use crate::storage::{QueryListOptions, StorageQueryMethod};
+
+    // Tree holding the files, given with their path and content
+    fn write_tree(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+        let mut builder = repo.treebuilder(None).unwrap();
+        let mut dirs: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+        for (path, content) in files {
+            match path.split_once('/') {
+                Some((dir, rest)) => dirs.entry(dir).or_default().push((rest, content)),
+                None => {
+                    let blob = repo.blob(content.as_bytes()).unwrap();
+                    builder.insert(path, blob, 0o100644).unwrap();
+                }
+            }
+        }
+        for (dir, files) in dirs {
+            let tree = write_tree(repo, &files);
+            builder.insert(dir, tree, 0o040000).unwrap();
+        }
+        builder.write().unwrap()
+    }
+
+    fn commit(repo: &Repository, files: &[(&str, &str)]) {
+        let tree = repo.find_tree(write_tree(repo, files)).unwrap();
+        let sig = Signature::now("Author", "author@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents = parent.iter().collect::<Vec<&Commit>>();
+        repo.commit(Some("HEAD"), &sig, &sig, "update", &tree, &parents)
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn serves_a_bare_repository() {
+        let root = std::env::temp_dir().join(format!("ecoweb-git-{:x}", rand::random::<u64>()));
+        let repo = Repository::init_bare(&root).unwrap();
+        let mut files = vec![
+            ("templates/page.html", "{{ page_content }}"),
+            (
+                "data/blog/hello.md",
+                "+++\n[metadata]\ntitle = \"Hello\"\n+++\nHi",
+            ),
+        ];
+        commit(&repo, &files);
+
+        let mut storage: GitStorage = toml::from_str(
+            "repository = \".\"\ndata_root = \"data\"\ntemplate_root = \"templates\"",
+        )
+        .unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Git(storage.clone())))
+            .unwrap();
+
+        let slug = "blog".to_string();
+        let qry = StorageQueryMethod::ContentFromName("hello".to_string()).build_query(&slug);
+        let (_, metadata, body) = storage.query(qry.clone()).await.page_content().unwrap();
+        assert_eq!(
+            (metadata.metadata["title"].as_str(), body.as_str()),
+            (Some("Hello"), "Hi")
+        );
+        let templates = storage.query(StorageQuery::templates()).await;
+        assert!(templates
+            .base_templates()
+            .unwrap()
+            .contains_key("page.html"));
+
+        let listing = StorageQuery::recent_pages(&slug, &QueryListOptions::default());
+        let (_, total) = storage.query(listing.clone()).await.recent_pages().unwrap();
+        assert_eq!(total, 1);
+        assert!(!storage.has_changed(&listing).await);
+
+        files.push(("data/blog/other.md", "Other"));
+        commit(&repo, &files);
+        assert!(storage.has_changed(&listing).await);
+        assert!(storage.has_changed(&qry).await);
+        let (_, total) = storage.query(listing).await.recent_pages().unwrap();
+        assert_eq!(total, 2);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use actix_web::{HttpResponse, HttpResponseBuilder};
@@ -8,14 +9,19 @@ use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
-use crate::page::PageMetadata;
-use crate::scss::{compile_scss, ScssError};
+use crate::frontmatter::{format_page_content, parse_page_content, FrontmatterError};
+use crate::page::{compare_similar_md, timestamp_now, PageMetadata};
+use crate::scss::{compile_scss, scss_dependencies, ScssError};
 use crate::storage::query::StorageQueryMethod;
-use crate::storage::{StorageData, StorageQuery};
+use crate::storage::write::StorageWriteMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
 
-use super::StorageBackend;
-
-const SPLIT_PAT: &str = "---";
+use super::stamps::FileStamps;
+use super::{
+    check_id_collisions, is_bundle_index, lang_candidates, normalize_relative_path,
+    page_file_candidates, page_file_id_path, page_file_lang, page_file_name, supported_lang_of,
+    PageFiles, PageSchedules, StorageBackend, StorageChange, BUNDLE_INDEX,
+};
 
 fn canonicalize_to_root(path: &mut PathBuf, root: &Path) -> Result<(), LocalStorageError> {
     *path = root
@@ -38,7 +44,7 @@ pub enum LocalStorageError {
     TooManyMatches(usize, usize),
 
     TomlDecode(String),
-    NoMetadataSplit,
+    Frontmatter(FrontmatterError),
 
     BadRequest(String),
 
@@ -52,17 +58,42 @@ pub enum LocalStorageError {
     ScssProcess(ScssError),
 
     AttackSuspected(String),
+
+    WriteFile(String),
+    DeleteFile(String),
+    TomlEncode(String),
+    NoAssetsDir,
 }
 
 impl From<LocalStorageError> for HttpResponseBuilder {
     fn from(val: LocalStorageError) -> Self {
         match val {
-            LocalStorageError::DataNotFound(_) => HttpResponse::NotFound(),
+            LocalStorageError::DataNotFound(_) | LocalStorageError::NoMatch(_) => {
+                HttpResponse::NotFound()
+            }
             _ => HttpResponse::InternalServerError(),
         }
     }
 }
 
+impl LocalStorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            LocalStorageError::DataNotFound(_)
+                | LocalStorageError::NoMatch(_)
+                | LocalStorageError::NotDataDir(_)
+                | LocalStorageError::CssNotFound(_)
+        )
+    }
+}
+
+impl From<FrontmatterError> for LocalStorageError {
+    fn from(value: FrontmatterError) -> Self {
+        LocalStorageError::Frontmatter(value)
+    }
+}
+
 impl From<ScssError> for LocalStorageError {
     fn from(value: ScssError) -> Self {
         LocalStorageError::ScssProcess(value)
@@ -72,24 +103,54 @@ impl From<ScssError> for LocalStorageError {
 //                        Storage    Filepath   Metadata
 type PageCache = HashMap<String, Vec<(PathBuf, PageMetadata)>>;
 
+//                         Storage    Generation  Files listed
+type IndexStamps = HashMap<String, (usize, FileStamps)>;
+
+// What the data returned for a query was loaded from
+#[derive(Debug, Clone)]
+enum Dependencies {
+    Nothing,
+    Files(FileStamps),
+    PagesIndex(String, usize),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocalStorage {
     #[serde(skip)]
     all_pages: Arc<RwLock<PageCache>>,
+    #[serde(skip)]
+    index_stamps: Arc<RwLock<IndexStamps>>,
+    // Next publish and expire dates of the pages of each index
+    #[serde(skip)]
+    schedules: Arc<RwLock<HashMap<String, PageSchedules>>>,
+    #[serde(skip)]
+    query_deps: Arc<RwLock<HashMap<StorageQuery, Dependencies>>>,
+    // Changes are notified by a file watcher rather than checked on each query
+    #[serde(skip)]
+    watched: Arc<AtomicBool>,
+    #[serde(skip)]
+    dev_mode: bool,
 
     // Data
+    #[serde(default)]
     data_root: PathBuf,
+    #[serde(default)]
     supported_lang: Vec<String>,
+    #[serde(default)]
     default_sort: (Vec<String>, bool),
 
     // Templates
+    #[serde(default)]
     template_root: PathBuf,
 
     // Assets
+    #[serde(default)]
     include_assets: Vec<PathBuf>,
 
     // CSS
+    #[serde(default)]
     scss: HashMap<String, Vec<PathBuf>>,
+    #[serde(default)]
     scss_root: PathBuf,
 }
 
@@ -108,6 +169,13 @@ impl LocalStorage {
         Ok(())
     }
 
+    // Directories the data is read from, as written in the config
+    pub fn source_dirs(&self) -> Vec<&PathBuf> {
+        let mut dirs = vec![&self.data_root, &self.template_root, &self.scss_root];
+        dirs.extend(self.include_assets.iter());
+        dirs
+    }
+
     pub fn load_css(&self, css: &str) -> Result<StorageData, LocalStorageError> {
         let mut css_content = String::new();
         let Some(scss_files) = self.scss.get(css) else {
@@ -144,6 +212,22 @@ impl LocalStorage {
         Ok(StorageData::StaticFileData(data))
     }
 
+    // Files a context may be written in, in the order of the languages of the query
+    // and their fallbacks, the one outside of the language directories last
+    fn context_paths(&self, qry: &StorageQuery, name: &str) -> Vec<PathBuf> {
+        let root = self.data_root.join(&qry.storage_slug);
+        lang_candidates(qry, &self.supported_lang)
+            .into_iter()
+            .map(|lang| {
+                let dir = match lang {
+                    Some(lang) => root.join(lang),
+                    None => root.clone(),
+                };
+                with_extension_added(dir.join(name), "toml")
+            })
+            .collect()
+    }
+
     pub fn get_content_path(
         &self,
         qry: &StorageQuery,
@@ -159,11 +243,93 @@ impl LocalStorage {
             path.push(name);
         }
         if let Some(ext) = ext {
-            path.set_extension(ext);
+            path = with_extension_added(path, ext);
         }
         Ok(path)
     }
 
+    // Path of a page in a language, as the first of its candidate files existing on disk,
+    // with whether it is a page bundle
+    fn find_page_path(
+        &self,
+        slug: &str,
+        name: &str,
+        lang: Option<&String>,
+    ) -> Option<(PathBuf, bool)> {
+        let mut dir = self.data_root.join(slug);
+        if let Some(lang) = lang {
+            dir.push(lang);
+        }
+        page_file_candidates(name)
+            .into_iter()
+            .map(|(fname, bundle)| (dir.join(fname), bundle))
+            .find(|(path, _)| path.is_file())
+    }
+
+    // Path of a page in the first language of the query it is written in,
+    // with whether it is a page bundle and the language found
+    pub fn get_page_path(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+    ) -> Result<(PathBuf, bool, Option<String>), LocalStorageError> {
+        let slug = &qry.storage_slug;
+        for lang in lang_candidates(qry, &self.supported_lang) {
+            if let Some((path, bundle)) = self.find_page_path(slug, name, lang.as_ref()) {
+                return Ok((path, bundle, lang));
+            }
+        }
+        let dir = self.get_content_path(qry, None, None, None)?;
+        let candidates = page_file_candidates(name);
+        Ok((dir.join(&candidates[0].0), false, self.select_lang(qry)?))
+    }
+
+    // Languages a page is written in
+    fn page_translations(&self, slug: &str, name: &str) -> Vec<String> {
+        self.supported_lang
+            .iter()
+            .filter(|lang| self.find_page_path(slug, name, Some(lang)).is_some())
+            .cloned()
+            .collect()
+    }
+
+    fn load_page(&self, qry: &StorageQuery, name: &str) -> Result<StorageData, LocalStorageError> {
+        let (path, bundle, lang) = self.get_page_path(qry, name)?;
+        let (mut metadata, body) = self.load_content(&path)?;
+        if !metadata.is_visible(timestamp_now(), qry.drafts) {
+            return Err(LocalStorageError::DataNotFound(path));
+        }
+        metadata.bundle = bundle;
+        metadata.translations = self.page_translations(&qry.storage_slug, name);
+        Ok(StorageData::PageContent {
+            metadata,
+            body,
+            lang,
+        })
+    }
+
+    fn get_page_asset_path(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+        asset: &str,
+    ) -> Result<PathBuf, LocalStorageError> {
+        let asset = normalize_relative_path(asset).map_err(|e| {
+            log::error!("Possible directory traversal attack spotted");
+            log::error!("Got a request for asset {asset:?} of page {name:?}: {e:?}");
+            LocalStorageError::AttackSuspected(
+                "local-storage::page-asset::directory-traversal".to_string(),
+            )
+        })?;
+        // Assets are stored along with the page, in the language it was found in
+        let (index, _, _) = self.get_page_path(qry, name)?;
+        let path = index.parent().unwrap_or(&self.data_root).to_path_buf();
+        if is_bundle_index(&asset) {
+            return Err(LocalStorageError::DataNotFound(path.join(asset)));
+        }
+        Ok(path.join(asset))
+    }
+
     pub fn load_content(&self, path: &Path) -> Result<(PageMetadata, String), LocalStorageError> {
         if !path.exists() {
             return Err(LocalStorageError::DataNotFound(path.to_path_buf()));
@@ -172,46 +338,37 @@ impl LocalStorage {
         let content = std::fs::read_to_string(path)
             .map_err(|e| LocalStorageError::LoadContent(format!("{e:?}")))?;
 
-        if !content.contains(SPLIT_PAT) {
-            return Err(LocalStorageError::LoadContent(format!(
-                "Split {SPLIT_PAT} not found in {path:?}"
-            )));
-        }
-
-        let mut split = content.split(SPLIT_PAT);
-        let metadata = split.next().unwrap();
-
-        let body = split.collect::<Vec<&str>>().join(SPLIT_PAT).to_string();
-
-        let mut metadata: PageMetadata = toml::from_str(metadata)
-            .map_err(|e| LocalStorageError::TomlDecode(format!("{e:?}")))?;
-        if metadata.id == 0 {
-            metadata.update_id(path.to_string_lossy().to_string());
-        }
+        let (mut metadata, body) = parse_page_content(&path.to_string_lossy(), &content)?;
+        let rel = path.strip_prefix(&self.data_root).unwrap_or(path);
+        metadata.update_id(page_file_id_path(&rel.to_string_lossy()));
+        metadata.set_default_format(&path.to_string_lossy());
 
         Ok((metadata, body))
     }
 
-    fn all_pages_in_dir(
+    // Only the directories are stamped: their mtime changes whenever a file is added,
+    // removed or renamed into them, as editors and atomic_write do when saving a file
+    fn all_files_in_dir(
         &self,
         dirpath: &Path,
-    ) -> Result<Vec<(PathBuf, PageMetadata)>, LocalStorageError> {
+        stamps: &mut FileStamps,
+    ) -> Result<Vec<PathBuf>, LocalStorageError> {
+        stamps.add(dirpath);
         let all_paths = std::fs::read_dir(dirpath)
             .map_err(|e| LocalStorageError::ListFiles(format!("{e:?}")))?;
 
-        let mut all_pages = vec![];
+        let mut all_files = vec![];
         for path in all_paths {
             let path = path
                 .map_err(|e| LocalStorageError::ListFilesPathUnwrap(format!("{e:?}")))?
                 .path();
             if path.is_file() {
-                let (metadata, _) = self.load_content(&path)?;
-                all_pages.push((path, metadata));
+                all_files.push(path);
             } else if path.is_dir() {
-                all_pages.extend(self.all_pages_in_dir(&path)?);
+                all_files.extend(self.all_files_in_dir(&path, stamps)?);
             }
         }
-        Ok(all_pages)
+        Ok(all_files)
     }
 
     pub fn register_all_pages(&self, slug: &String) -> Result<(), LocalStorageError> {
@@ -219,26 +376,125 @@ impl LocalStorage {
         if !dirpath.is_dir() {
             return Err(LocalStorageError::NotDataDir(dirpath));
         }
-        let all_pages = self.all_pages_in_dir(&dirpath)?;
+        let mut stamps = FileStamps::new();
+        let all_files = self
+            .all_files_in_dir(&dirpath, &mut stamps)?
+            .into_iter()
+            .filter_map(|path| {
+                let rel = path.strip_prefix(&dirpath).ok()?.to_str()?.to_string();
+                Some((rel, path))
+            })
+            .collect::<Vec<(String, PathBuf)>>();
+        let page_files = PageFiles::new(
+            all_files.iter().map(|(rel, _)| rel.as_str()),
+            &self.supported_lang,
+        );
+        let mut all_pages = vec![];
+        for (rel, path) in all_files {
+            if page_files.is_page(&rel) {
+                let (mut metadata, _) = self.load_content(&path)?;
+                metadata.bundle = is_bundle_index(&rel);
+                metadata.lang = page_file_lang(&rel, &self.supported_lang);
+                all_pages.push((path, metadata));
+            }
+        }
         log::debug!("Registered {} pages in {slug}", all_pages.len());
+        check_id_collisions(slug, all_pages.iter());
+        let schedules = PageSchedules::new(all_pages.iter().map(|(_, m)| m));
+        self.schedules.write().insert(slug.clone(), schedules);
         self.all_pages.write().insert(slug.clone(), all_pages);
+
+        let mut index_stamps = self.index_stamps.write();
+        let gen = index_stamps.get(slug).map(|(gen, _)| gen + 1).unwrap_or(0);
+        index_stamps.insert(slug.clone(), (gen, stamps));
         Ok(())
     }
 
+    // Whether the pages index of the slug is outdated, or was registered again since
+    // the generation passed as parameter. Once watched, the index is registered again
+    // by file_changed and its directories aren't checked anymore.
+    fn pages_index_changed(&self, slug: &String, since_gen: Option<usize>) -> bool {
+        let index_stamps = self.index_stamps.read();
+        let Some((gen, stamps)) = index_stamps.get(slug) else {
+            return self.data_root.join(slug).is_dir();
+        };
+        since_gen.is_some_and(|since| since != *gen)
+            || (!self.watched.load(Ordering::Relaxed) && stamps.has_changed())
+    }
+
     pub fn ensure_all_pages_loaded(&self, slug: &String) -> Result<(), LocalStorageError> {
         let pages_reg = self.all_pages.read().contains_key(slug);
-        let hot_reload = false;
-
-        #[cfg(feature = "hot-reloading")]
-        let hot_reload = true;
-
-        if !pages_reg || hot_reload {
+        if !pages_reg || self.dev_mode || self.pages_index_changed(slug, None) {
             self.register_all_pages(slug)?;
         }
 
         Ok(())
     }
 
+    fn dependencies(&self, qry: &StorageQuery) -> Dependencies {
+        let mut files = FileStamps::new();
+        match qry.method {
+            StorageQueryMethod::NoOp => return Dependencies::Nothing,
+
+            StorageQueryMethod::ContentNumId(_)
+            | StorageQueryMethod::ContentLegacyId(_)
+            | StorageQueryMethod::RecentPages
+            | StorageQueryMethod::GetSimilarPages(_)
+            | StorageQueryMethod::QueryMetadata(..)
+            | StorageQueryMethod::AggregateMetadata(_) => {
+                let slug = &qry.storage_slug;
+                let gen = self.index_stamps.read().get(slug).map(|(gen, _)| *gen);
+                return Dependencies::PagesIndex(slug.clone(), gen.unwrap_or(usize::MAX));
+            }
+
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                // The page may appear in any language, or in the ones it falls back to
+                let slug = self.data_root.join(&qry.storage_slug);
+                let dirs = self.supported_lang.iter().map(|lang| slug.join(lang));
+                for dir in dirs.chain([slug.clone()]) {
+                    for (fname, _) in page_file_candidates(name) {
+                        files.add(&dir.join(fname));
+                    }
+                }
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                for path in self.context_paths(qry, name) {
+                    files.add(&path);
+                }
+            }
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                if let Ok(path) = self.get_page_asset_path(qry, name, asset) {
+                    files.add(&path);
+                }
+                // The asset is served depending on the visibility of its page
+                if let Ok((index, _, _)) = self.get_page_path(qry, name) {
+                    files.add(&index);
+                }
+            }
+
+            StorageQueryMethod::QueryTemplates => files.add_tree(&self.template_root),
+
+            StorageQueryMethod::StaticFile(ref f) => {
+                let fpath = PathBuf::from(f.trim_start_matches('/'));
+                for inc in self.include_assets.iter() {
+                    files.add(&inc.join(&fpath));
+                }
+                let fname = fpath.file_name().and_then(|f| f.to_str());
+                if let Some(scss_files) = fname.and_then(|f| self.scss.get(f)) {
+                    let mut deps = vec![];
+                    for scss_file in scss_files {
+                        scss_dependencies(&self.scss_root.join(scss_file), &mut deps);
+                    }
+                    deps.iter().for_each(|d| files.add(d));
+                }
+            }
+        }
+        Dependencies::Files(files)
+    }
+
     // TODO Create separate functions for each
     pub fn dispatch(&self, qry: StorageQuery) -> Result<StorageData, LocalStorageError> {
         let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
@@ -247,6 +503,8 @@ impl LocalStorage {
             (&self.default_sort.0, self.default_sort.1)
         };
         let lang = self.select_lang(&qry)?;
+        let now = timestamp_now();
+        let drafts = qry.drafts;
 
         match qry.method {
             StorageQueryMethod::NoOp => {
@@ -254,45 +512,39 @@ impl LocalStorage {
                 Ok(StorageData::Nothing)
             }
 
-            StorageQueryMethod::ContentFromName(ref name) => {
-                let path = self.get_content_path(&qry, Some(name), lang.as_ref(), Some("md"))?;
-                let (metadata, body) = self.load_content(&path)?;
-                Ok(StorageData::PageContent {
-                    metadata,
-                    body,
-                    lang,
-                })
-            }
+            StorageQueryMethod::ContentFromName(ref name) => self.load_page(&qry, name),
 
-            StorageQueryMethod::ContentNumId(id) => {
+            StorageQueryMethod::ContentNumId(id) | StorageQueryMethod::ContentLegacyId(id) => {
                 self.ensure_all_pages_loaded(&qry.storage_slug)?;
                 let all_pages = self.all_pages.read();
                 let pages = all_pages.get(&qry.storage_slug).unwrap();
-                let mut matches = pages.iter().filter(|(_, m)| m.id == id).map(|(p, _)| p);
-                let Some(fpath) = matches.next() else {
+                // An id shared by several pages is refused, whichever of them is visible
+                let mut matches = pages.iter().filter(|(_, m)| qry.method.matches_id(m));
+                let Some((fpath, page)) = matches.next() else {
                     return Err(LocalStorageError::NoMatch(format!("id = {id}")));
                 };
                 let other_matches = matches.count();
                 if other_matches > 0 {
                     return Err(LocalStorageError::TooManyMatches(other_matches, 1));
                 }
-                let (metadata, body) = self.load_content(fpath)?;
+                if !page.is_visible(now, drafts) {
+                    return Err(LocalStorageError::NoMatch(format!("id = {id}")));
+                }
+                let (mut metadata, body) = self.load_content(fpath)?;
+                metadata.bundle = page.bundle;
+                metadata.lang = page.lang.clone();
+                let rel = fpath.strip_prefix(self.data_root.join(&qry.storage_slug));
+                let rel = rel.unwrap_or(fpath).to_string_lossy();
+                let name = page_file_name(&rel, &self.supported_lang);
+                metadata.translations = self.page_translations(&qry.storage_slug, &name);
                 Ok(StorageData::PageContent {
+                    lang: metadata.lang.clone(),
                     metadata,
                     body,
-                    lang,
                 })
             }
 
-            StorageQueryMethod::ContentSlug(ref name) => {
-                let path = self.get_content_path(&qry, Some(name), lang.as_ref(), Some("md"))?;
-                let (metadata, body) = self.load_content(&path)?;
-                Ok(StorageData::PageContent {
-                    metadata,
-                    body,
-                    lang,
-                })
-            }
+            StorageQueryMethod::ContentSlug(ref name) => self.load_page(&qry, name),
 
             StorageQueryMethod::RecentPages => {
                 self.ensure_all_pages_loaded(&qry.storage_slug)?;
@@ -301,7 +553,7 @@ impl LocalStorage {
                     .get(&qry.storage_slug)
                     .unwrap()
                     .iter()
-                    .filter(|(_, m)| !m.hidden)
+                    .filter(|(_, m)| m.is_listed(now, drafts) && qry.matches_filter(m))
                     .collect::<Vec<&(PathBuf, PageMetadata)>>();
 
                 results.sort_by(|(_, a), (_, b)| a.compare_md(sort_key, b));
@@ -309,17 +561,19 @@ impl LocalStorage {
                     results.reverse();
                 }
 
+                let total = results.len();
                 let results = results
                     .into_iter()
                     .cloned()
                     .map(|(p, m)| m)
+                    .skip(qry.offset)
                     .take(if qry.limit == 0 {
                         usize::MAX
                     } else {
                         qry.limit
                     })
                     .collect();
-                Ok(StorageData::RecentPages(results))
+                Ok(StorageData::RecentPages(results, total))
             }
 
             StorageQueryMethod::QueryTemplates => {
@@ -367,15 +621,15 @@ impl LocalStorage {
                 Err(LocalStorageError::DataNotFound(fpath))
             }
 
-            StorageQueryMethod::GetSimilarPages((keys, val)) => {
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
                 self.ensure_all_pages_loaded(&qry.storage_slug)?;
                 let all_pages = self.all_pages.read();
                 let pages = all_pages.get(&qry.storage_slug).unwrap();
                 let mut matches = pages
                     .iter()
                     .filter(|(_, m)| {
-                        !m.hidden && {
-                            let valcmp = m.get_metadata(&keys);
+                        m.is_listed(now, drafts) && qry.matches_filter(m) && {
+                            let valcmp = m.get_metadata(keys);
                             match (valcmp, val.as_ref()) {
                                 (Some(md), Some(val)) => compare_similar_md(md, val),
                                 (Some(_), None) | (None, Some(_)) => false,
@@ -391,8 +645,10 @@ impl LocalStorage {
                     matches.reverse();
                 }
 
+                let total = matches.len();
                 let matches = matches
                     .into_iter()
+                    .skip(qry.offset)
                     .take(if qry.limit == 0 {
                         usize::MAX
                     } else {
@@ -400,24 +656,60 @@ impl LocalStorage {
                     })
                     .cloned()
                     .collect::<Vec<PageMetadata>>();
-                Ok(StorageData::SimilarPages(matches))
+                Ok(StorageData::SimilarPages(matches, total))
             }
 
-            StorageQueryMethod::QueryMetadata((keys, val), query) => {
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
                 self.ensure_all_pages_loaded(&qry.storage_slug)?;
                 let pages = self.all_pages.read().get(&qry.storage_slug).unwrap();
                 let all_pages = self.all_pages.read();
                 let pages = all_pages.get(&qry.storage_slug).unwrap();
                 let matches = pages
                     .iter()
-                    .filter(|(_, m)| m.get_metadata(&keys) == val.as_ref())
-                    .filter_map(|(_, m)| m.get_metadata(&query).cloned())
+                    .filter(|(_, m)| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && m.get_metadata(keys) == val.as_ref()
+                    })
+                    .filter_map(|(_, m)| m.get_metadata(query).cloned())
                     .collect::<Vec<serde_json::Value>>();
                 Ok(StorageData::QueryMetadata(matches))
             }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                self.ensure_all_pages_loaded(&qry.storage_slug)?;
+                let all_pages = self.all_pages.read();
+                let pages = all_pages.get(&qry.storage_slug).unwrap();
+                let groups = agg.apply(
+                    pages
+                        .iter()
+                        .map(|(_, m)| m)
+                        .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m)),
+                    sort_key,
+                );
+                Ok(StorageData::Aggregates(groups))
+            }
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                let path = self.get_page_asset_path(&qry, name, asset)?;
+                let (index, bundle, _) = self.get_page_path(&qry, name)?;
+                if !bundle
+                    || !path.is_file()
+                    || !self.load_content(&index)?.0.is_visible(now, drafts)
+                {
+                    return Err(LocalStorageError::DataNotFound(path));
+                }
+                self.load_static_file(path)
+            }
+
             StorageQueryMethod::QueryContext(ref name) => {
-                let path = self.get_content_path(&qry, Some(name), lang.as_ref(), Some("toml"))?;
-                let data = std::fs::read_to_string(&path)
+                let paths = self.context_paths(&qry, name);
+                let Some(path) = paths.iter().find(|path| path.is_file()) else {
+                    return Err(LocalStorageError::DataNotFound(
+                        paths[paths.len() - 1].clone(),
+                    ));
+                };
+                let data = std::fs::read_to_string(path)
                     .map_err(|e| LocalStorageError::LoadContext(format!("{path:?}: {e:?}")))?;
                 let ctxt: toml::Value = toml::from_str(&data)
                     .map_err(|e| LocalStorageError::TomlDecode(format!("{path:?}: {e:?}")))?;
@@ -426,6 +718,124 @@ impl LocalStorage {
         }
     }
 
+    // Resolve a path to write to, refusing anything that would escape the root dir
+    fn write_path(&self, root: &Path, fpath: &str) -> Result<PathBuf, LocalStorageError> {
+        let fpath = PathBuf::from(fpath.trim_start_matches('/'));
+        let path = root.join(&fpath);
+        let path = path
+            .absolutize()
+            .map_err(|e| LocalStorageError::BadRequest(format!("Absolutize {fpath:?}: {e:?}")))?;
+        if !path.starts_with(root) || path == root {
+            log::error!("Possible directory traversal attack spotted");
+            log::error!("Got a write request for {fpath:?}");
+            return Err(LocalStorageError::AttackSuspected(
+                "local-storage::write::directory-traversal".to_string(),
+            ));
+        }
+        Ok(path.into_owned())
+    }
+
+    fn content_write_path(
+        &self,
+        wrt: &StorageWrite,
+        name: &str,
+        ext: &str,
+    ) -> Result<PathBuf, LocalStorageError> {
+        let mut root = self.data_root.join(&wrt.storage_slug);
+        if let Some(ref lang) = wrt.lang {
+            root.push(lang);
+        }
+        let path = self.write_path(&root, name)?;
+        Ok(with_extension_added(path, ext))
+    }
+
+    // A page already stored as a bundle is written to its index
+    fn page_write_path(
+        &self,
+        wrt: &StorageWrite,
+        name: &str,
+    ) -> Result<(PathBuf, bool), LocalStorageError> {
+        let path = self.content_write_path(wrt, name, "md")?;
+        let index = path.with_extension("").join(format!("{BUNDLE_INDEX}.md"));
+        if !path.is_file() && index.is_file() {
+            Ok((index, true))
+        } else {
+            Ok((path, false))
+        }
+    }
+
+    fn save_page(
+        &self,
+        wrt: &StorageWrite,
+        name: &str,
+        metadata: &PageMetadata,
+        body: &str,
+    ) -> Result<(), LocalStorageError> {
+        let (path, bundle) = self.page_write_path(wrt, name)?;
+        let content = format_page_content(metadata, body)
+            .map_err(|e| LocalStorageError::TomlEncode(format!("{path:?}: {e}")))?;
+        atomic_write(&path, content.as_bytes())?;
+
+        let (mut metadata, _) = self.load_content(&path)?;
+        metadata.bundle = bundle;
+        metadata.lang = wrt.lang.clone();
+        if let Some(pages) = self.all_pages.write().get_mut(&wrt.storage_slug) {
+            pages.retain(|(p, _)| p != &path);
+            pages.push((path, metadata));
+            self.update_schedules(&wrt.storage_slug, pages);
+        }
+        Ok(())
+    }
+
+    fn delete_page(&self, wrt: &StorageWrite, name: &str) -> Result<(), LocalStorageError> {
+        let (path, _) = self.page_write_path(wrt, name)?;
+        delete_file(&path)?;
+        if let Some(pages) = self.all_pages.write().get_mut(&wrt.storage_slug) {
+            pages.retain(|(p, _)| p != &path);
+            self.update_schedules(&wrt.storage_slug, pages);
+        }
+        Ok(())
+    }
+
+    fn update_schedules(&self, slug: &str, pages: &[(PathBuf, PageMetadata)]) {
+        let schedules = PageSchedules::new(pages.iter().map(|(_, m)| m));
+        self.schedules.write().insert(slug.to_string(), schedules);
+    }
+
+    fn assets_root(&self) -> Result<&PathBuf, LocalStorageError> {
+        self.include_assets
+            .first()
+            .ok_or(LocalStorageError::NoAssetsDir)
+    }
+
+    pub fn write_data(&self, wrt: StorageWrite) -> Result<(), LocalStorageError> {
+        match wrt.method {
+            StorageWriteMethod::SavePage {
+                ref name,
+                ref metadata,
+                ref body,
+            } => self.save_page(&wrt, name, metadata, body),
+            StorageWriteMethod::DeletePage(ref name) => self.delete_page(&wrt, name),
+
+            StorageWriteMethod::SaveContext(ref name, ref data) => {
+                let path = self.content_write_path(&wrt, name, "toml")?;
+                let data = toml::to_string(data)
+                    .map_err(|e| LocalStorageError::TomlEncode(format!("{path:?}: {e:?}")))?;
+                atomic_write(&path, data.as_bytes())
+            }
+            StorageWriteMethod::DeleteContext(ref name) => {
+                delete_file(&self.content_write_path(&wrt, name, "toml")?)
+            }
+
+            StorageWriteMethod::SaveStaticFile(ref fname, ref data) => {
+                atomic_write(&self.write_path(self.assets_root()?, fname)?, data)
+            }
+            StorageWriteMethod::DeleteStaticFile(ref fname) => {
+                delete_file(&self.write_path(self.assets_root()?, fname)?)
+            }
+        }
+    }
+
     pub fn select_lang(&self, qry: &StorageQuery) -> Result<Option<String>, LocalStorageError> {
         if let Some(ref lang) = qry.lang_pref {
             log::trace!(
@@ -433,12 +843,10 @@ impl LocalStorage {
                 lang,
                 self.supported_lang,
             );
-            for l in lang.iter() {
-                if self.supported_lang.contains(l) {
-                    return Ok(Some(l.clone()));
-                }
-            }
-            Ok(None)
+            Ok(lang
+                .iter()
+                .find_map(|l| supported_lang_of(l, &self.supported_lang))
+                .cloned())
         } else {
             Ok(None)
         }
@@ -448,50 +856,151 @@ impl LocalStorage {
 impl StorageBackend for LocalStorage {
     type Error = LocalStorageError;
 
-    fn init(config: &Config) -> Result<Self, Self::Error>
-    where
-        Self: Sized,
-    {
-        let mut storage = config.local_storage.clone();
-        storage.canonicalize_paths(config)?;
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        self.canonicalize_paths(config)?;
+        self.dev_mode = config.dev_mode;
         log::debug!("Initialized local storage");
-        log::debug!("Supported langs: {:?}", storage.supported_lang);
-        Ok(storage)
+        log::debug!("Supported langs: {:?}", self.supported_lang);
+        Ok(())
     }
 
-    // TODO Put a time argument to check for updates
     async fn has_changed(&self, qry: &StorageQuery) -> bool {
-        // TODO    Find a way to know when posts are changed or not
-        false
+        if self.watched.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Some(deps) = self.query_deps.read().get(qry).cloned() else {
+            return true;
+        };
+        match deps {
+            Dependencies::Nothing => false,
+            Dependencies::Files(stamps) => stamps.has_changed(),
+            Dependencies::PagesIndex(slug, gen) => self.pages_index_changed(&slug, Some(gen)),
+        }
     }
 
     async fn query(&self, qry: StorageQuery) -> StorageData {
-        match self.dispatch(qry) {
+        // Files are stamped before being read, so a change during the query is caught
+        let deps = self.dependencies(&qry);
+        let res = self.dispatch(qry.clone());
+        let deps = match deps {
+            // The index may have been registered while answering the query
+            Dependencies::PagesIndex(..) => self.dependencies(&qry),
+            deps => deps,
+        };
+        self.query_deps.write().insert(qry, deps);
+
+        match res {
             Ok(data) => data,
             Err(e) => {
                 log::error!("{e:?}");
-                StorageData::Error(e)
+                StorageData::Error(e.into())
             }
         }
     }
-}
 
-// TODO    Use this function once minify-js is fixed
-pub fn minify_js(data: Vec<u8>) -> Vec<u8> {
-    data
-}
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        self.write_data(wrt).inspect_err(|e| log::error!("{e:?}"))
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        self.query_deps.write().remove(qry);
+    }
+
+    // Taken from the last index of the slug, checking its files is left to has_changed
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        let slug = &qry.storage_slug;
+        if !self.schedules.read().contains_key(slug) {
+            self.register_all_pages(slug).ok()?;
+        }
+        self.schedules.read().get(slug)?.next(now)
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.supported_lang.clone()
+    }
 
-fn compare_similar_md(a: &serde_json::Value, b: &serde_json::Value) -> bool {
-    if a.is_array() && b.is_array() {
-        return a == b;
+    fn watch(&self) -> Vec<PathBuf> {
+        self.watched.store(true, Ordering::Relaxed);
+        let mut paths = vec![
+            self.data_root.clone(),
+            self.template_root.clone(),
+            self.scss_root.clone(),
+        ];
+        paths.extend(self.include_assets.iter().cloned());
+        paths
     }
-    if a.is_array() {
-        return a.as_array().unwrap().contains(b);
+
+    // Each slug is registered again once, whatever the number of its files changed
+    fn files_changed(&self, paths: &[PathBuf]) -> Vec<StorageChange> {
+        let mut slugs = paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(&self.data_root).ok()?.components().next())
+            .map(|slug| slug.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        slugs.sort();
+        slugs.dedup();
+        for slug in slugs.iter() {
+            if self.all_pages.read().contains_key(slug) {
+                if let Err(e) = self.register_all_pages(slug) {
+                    log::warn!("Unable to register pages of {slug} again: {e:?}");
+                    self.all_pages.write().remove(slug);
+                    self.index_stamps.write().remove(slug);
+                    self.schedules.write().remove(slug);
+                }
+            }
+        }
+
+        let mut changes = slugs
+            .into_iter()
+            .map(StorageChange::Pages)
+            .collect::<Vec<StorageChange>>();
+        if paths
+            .iter()
+            .any(|path| path.starts_with(&self.template_root))
+        {
+            changes.push(StorageChange::Templates);
+        }
+        if paths.iter().any(|path| {
+            path.starts_with(&self.scss_root)
+                || self.include_assets.iter().any(|inc| path.starts_with(inc))
+        }) {
+            changes.push(StorageChange::StaticFiles);
+        }
+        changes
     }
-    if b.is_array() {
-        return b.as_array().unwrap().contains(a);
+}
+
+// Write to a temporary file first, so readers never see a partially written file
+fn atomic_write(path: &Path, data: &[u8]) -> Result<(), LocalStorageError> {
+    let Some(parent) = path.parent() else {
+        return Err(LocalStorageError::WriteFile(format!(
+            "{path:?}: no parent dir"
+        )));
+    };
+    std::fs::create_dir_all(parent)
+        .map_err(|e| LocalStorageError::CreateDir(format!("{parent:?}: {e:?}")))?;
+
+    let fname = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = parent.join(format!(".{fname}.{:x}.tmp", rand::random::<u32>()));
+    std::fs::write(&tmp_path, data)
+        .map_err(|e| LocalStorageError::WriteFile(format!("{tmp_path:?}: {e:?}")))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = std::fs::remove_file(&tmp_path);
+        LocalStorageError::WriteFile(format!("{path:?}: {e:?}"))
+    })
+}
+
+fn delete_file(path: &Path) -> Result<(), LocalStorageError> {
+    if !path.is_file() {
+        return Err(LocalStorageError::DataNotFound(path.to_path_buf()));
     }
-    a == b
+    std::fs::remove_file(path)
+        .map_err(|e| LocalStorageError::DeleteFile(format!("{path:?}: {e:?}")))
+}
+
+// TODO    Use this function once minify-js is fixed
+pub fn minify_js(data: Vec<u8>) -> Vec<u8> {
+    data
 }
 
 fn load_all_templates_from_dir(
@@ -508,7 +1017,7 @@ fn load_all_templates_from_dir(
             path.map_err(|e| LocalStorageError::TemplateLoading(format!("Get path entry: {e:?}")))?;
         let path = path.path();
         if path.is_dir() {
-            let Some(dname) = path.components().last().unwrap().as_os_str().to_str() else {
+            let Some(dname) = path.components().next_back().unwrap().as_os_str().to_str() else {
                 log::warn!("Cannot load dir {path:?}: illegal dirname");
                 continue;
             };
@@ -534,3 +1043,128 @@ fn load_all_templates_from_dir(
 
     Ok(())
 }
+
+// Names may hold dots, as in "v1.2-release", which aren't the start of an extension
+fn with_extension_added(path: PathBuf, ext: &str) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".");
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::Backend;
+    use crate::storage::QueryListOptions;
+
+    #[actix_web::test]
+    async fn registers_each_changed_slug_once() {
+        let root = std::env::temp_dir().join(format!("ecoweb-local-{:x}", rand::random::<u64>()));
+        let blog = root.join("data").join("blog");
+        std::fs::create_dir_all(&blog).unwrap();
+        std::fs::write(blog.join("hello.md"), "Hello").unwrap();
+        std::fs::create_dir_all(root.join("templates")).unwrap();
+
+        let mut storage: LocalStorage = toml::from_str(
+            "data_root = \"data\"\ntemplate_root = \"templates\"\nscss_root = \"templates\"",
+        )
+        .unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Local(storage.clone())))
+            .unwrap();
+        let slug = "blog".to_string();
+        let listing = StorageQuery::recent_pages(&slug, &QueryListOptions::default());
+        let (_, total) = storage.query(listing.clone()).await.recent_pages().unwrap();
+        assert_eq!(total, 1);
+        storage.watch();
+
+        let root = root.canonicalize().unwrap();
+        let blog = root.join("data").join("blog");
+        let paths = ["a.md", "b.md", "c.md"]
+            .map(|f| blog.join(f))
+            .into_iter()
+            .inspect(|path| std::fs::write(path, "Page").unwrap())
+            .collect::<Vec<PathBuf>>();
+        let changes = storage.files_changed(&paths);
+        assert_eq!(changes, [StorageChange::Pages(slug.clone())]);
+        assert_eq!(storage.index_stamps.read().get(&slug).unwrap().0, 1);
+        let (_, total) = storage.query(listing).await.recent_pages().unwrap();
+        assert_eq!(total, 4);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn saves_pages_with_dotted_names_in_their_language() {
+        let root = std::env::temp_dir().join(format!("ecoweb-local-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("data").join("blog").join("fr")).unwrap();
+        std::fs::create_dir_all(root.join("templates")).unwrap();
+
+        let mut storage: LocalStorage = toml::from_str(
+            "data_root = \"data\"\ntemplate_root = \"templates\"\nscss_root = \"templates\"\n\
+            supported_lang = [\"en\", \"fr\"]",
+        )
+        .unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Local(storage.clone())))
+            .unwrap();
+        let slug = "blog".to_string();
+        let mut listing = StorageQuery::recent_pages(&slug, &QueryListOptions::default());
+        listing.set_lang(vec!["fr".to_string()]);
+        let (pages, _) = storage.query(listing.clone()).await.recent_pages().unwrap();
+        assert!(pages.is_empty());
+        storage.watch();
+
+        let name = "v1.2-release".to_string();
+        let mut wrt = StorageWrite::save_page(&slug, name, PageMetadata::default(), "Notes".into());
+        wrt.set_lang("fr".to_string());
+        storage.write_data(wrt).unwrap();
+
+        let root = root.canonicalize().unwrap();
+        let blog = root.join("data").join("blog").join("fr");
+        assert!(blog.join("v1.2-release.md").is_file());
+        assert!(!blog.join("v1.md").exists());
+        let (pages, _) = storage.query(listing).await.recent_pages().unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].lang.as_deref(), Some("fr"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn falls_back_to_contexts_of_other_languages() {
+        let root = std::env::temp_dir().join(format!("ecoweb-local-{:x}", rand::random::<u64>()));
+        let blog = root.join("data").join("blog");
+        std::fs::create_dir_all(blog.join("en")).unwrap();
+        std::fs::write(blog.join("en").join("site.toml"), "name = \"Blog\"").unwrap();
+        std::fs::write(blog.join("menu.v2.toml"), "items = 2").unwrap();
+        std::fs::create_dir_all(root.join("templates")).unwrap();
+
+        let mut storage: LocalStorage = toml::from_str(
+            "data_root = \"data\"\ntemplate_root = \"templates\"\nscss_root = \"templates\"\n\
+            supported_lang = [\"en\", \"fr\"]",
+        )
+        .unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Local(storage.clone())))
+            .unwrap();
+        let slug = "blog".to_string();
+        let context = |name: &str, langs: &[&str]| {
+            let mut qry = StorageQuery::query_context(&slug, name.to_string());
+            qry.set_lang(langs.iter().map(|l| l.to_string()).collect());
+            qry
+        };
+        let data = storage.query(context("site", &["fr", "en"])).await;
+        assert_eq!(data.context().unwrap()["name"].as_str(), Some("Blog"));
+        let data = storage.query(context("menu.v2", &["fr", "en"])).await;
+        assert_eq!(data.context().unwrap()["items"].as_integer(), Some(2));
+        assert!(storage
+            .query(context("site", &["fr"]))
+            .await
+            .context()
+            .is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
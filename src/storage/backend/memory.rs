@@ -0,0 +1,752 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::frontmatter::{parse_page_content, FrontmatterError};
+use crate::page::{compare_similar_md, page_id_path, timestamp_now, PageMetadata, PAGE_EXTENSIONS};
+use crate::storage::query::StorageQueryMethod;
+use crate::storage::write::StorageWriteMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
+
+use super::{
+    is_bundle_index, lang_candidates, normalize_relative_path, supported_lang_of, PageFiles,
+    PageSchedules, RelativePathError, StorageBackend,
+};
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum MemoryStorageError {
+    LoadFixtures(String),
+    Frontmatter(FrontmatterError),
+
+    DataNotFound(String),
+
+    NoMatch(String),
+    TooManyMatches(usize, usize),
+
+    TomlDecode(String),
+
+    BadRequest(String),
+    AttackSuspected(String),
+}
+
+impl From<MemoryStorageError> for HttpResponseBuilder {
+    fn from(val: MemoryStorageError) -> Self {
+        match val {
+            MemoryStorageError::DataNotFound(_) | MemoryStorageError::NoMatch(_) => {
+                HttpResponse::NotFound()
+            }
+            _ => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+impl MemoryStorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            MemoryStorageError::DataNotFound(_) | MemoryStorageError::NoMatch(_)
+        )
+    }
+}
+
+impl From<FrontmatterError> for MemoryStorageError {
+    fn from(value: FrontmatterError) -> Self {
+        MemoryStorageError::Frontmatter(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MemoryPage {
+    lang: Option<String>,
+    name: String,
+    metadata: PageMetadata,
+    body: String,
+}
+
+//                          Storage  Lang            Name
+type ContextKey = (String, Option<String>, String);
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    // Pages of each storage slug, in insertion order
+    pages: HashMap<String, Vec<MemoryPage>>,
+    // Next publish and expire dates of the pages of each storage slug
+    schedules: HashMap<String, PageSchedules>,
+    contexts: HashMap<ContextKey, toml::Value>,
+    templates: HashMap<String, String>,
+    static_files: HashMap<String, Vec<u8>>,
+    // Assets of the page bundles, under the location of the page
+    page_assets: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryData {
+    fn update_schedules(&mut self, slug: &str) {
+        let pages = self.pages.get(slug).into_iter().flatten();
+        let schedules = PageSchedules::new(pages.map(|p| &p.metadata));
+        self.schedules.insert(slug.to_string(), schedules);
+    }
+}
+
+/// Storage holding all its data in memory, filled from Rust values or from a fixture
+/// directory laid out like a LocalStorage (`data/`, `templates/` and `assets/`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MemoryStorage {
+    #[serde(skip)]
+    data: Arc<RwLock<MemoryData>>,
+    // Incremented on each change of the data
+    #[serde(skip)]
+    generation: Arc<AtomicUsize>,
+    #[serde(skip)]
+    answered: Arc<RwLock<HashMap<StorageQuery, usize>>>,
+
+    #[serde(default)]
+    fixtures: Option<PathBuf>,
+    #[serde(default)]
+    supported_lang: Vec<String>,
+    #[serde(default)]
+    default_sort: (Vec<String>, bool),
+}
+
+// All the files of a directory, with their path relative to it
+fn read_tree(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(String, Vec<u8>)>,
+) -> Result<(), MemoryStorageError> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| MemoryStorageError::LoadFixtures(format!("{dir:?}: {e:?}")))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| MemoryStorageError::LoadFixtures(format!("{dir:?}: {e:?}")))?
+            .path();
+        if path.is_dir() {
+            read_tree(root, &path, files)?;
+            continue;
+        }
+        let Some(rel) = path.strip_prefix(root).ok().and_then(|p| p.to_str()) else {
+            log::warn!("Cannot load fixture {path:?}: illegal filename");
+            continue;
+        };
+        let data = std::fs::read(&path)
+            .map_err(|e| MemoryStorageError::LoadFixtures(format!("{path:?}: {e:?}")))?;
+        files.push((rel.replace('\\', "/"), data));
+    }
+    Ok(())
+}
+
+impl MemoryStorage {
+    pub fn new(supported_lang: Vec<String>, default_sort: (Vec<String>, bool)) -> MemoryStorage {
+        MemoryStorage {
+            supported_lang,
+            default_sort,
+            ..Default::default()
+        }
+    }
+
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_page(
+        &self,
+        slug: &str,
+        lang: Option<&str>,
+        name: &str,
+        mut metadata: PageMetadata,
+        body: &str,
+    ) {
+        metadata.update_id(&page_id_path(slug, lang, name));
+        metadata.lang = lang.map(|l| l.to_string());
+        let page = MemoryPage {
+            lang: lang.map(|l| l.to_string()),
+            name: name.to_string(),
+            metadata,
+            body: body.to_string(),
+        };
+        let mut data = self.data.write();
+        let pages = data.pages.entry(slug.to_string()).or_default();
+        pages.retain(|p| p.lang != page.lang || p.name != page.name);
+        if let Some(other) = pages.iter().find(|p| p.metadata.id == page.metadata.id) {
+            log::error!(
+                "Pages {} and {} of {slug} have the same id {}",
+                page_key(slug, other.lang.as_deref(), &other.name),
+                page_key(slug, lang, name),
+                page.metadata.id
+            );
+        }
+        pages.push(page);
+        data.update_schedules(slug);
+        drop(data);
+        self.changed();
+    }
+
+    pub fn add_context(&self, slug: &str, lang: Option<&str>, name: &str, ctxt: toml::Value) {
+        let key = (
+            slug.to_string(),
+            lang.map(|l| l.to_string()),
+            name.to_string(),
+        );
+        self.data.write().contexts.insert(key, ctxt);
+        self.changed();
+    }
+
+    pub fn add_template(&self, name: &str, content: &str) {
+        self.data
+            .write()
+            .templates
+            .insert(name.to_string(), content.to_string());
+        self.changed();
+    }
+
+    pub fn add_static_file(&self, fname: &str, content: Vec<u8>) -> Result<(), MemoryStorageError> {
+        let fname = self.static_file_name(fname)?;
+        self.data.write().static_files.insert(fname, content);
+        self.changed();
+        Ok(())
+    }
+
+    // Asset of a page bundle, only served if the page is added with `bundle` set
+    pub fn add_page_asset(
+        &self,
+        slug: &str,
+        lang: Option<&str>,
+        name: &str,
+        asset: &str,
+        content: Vec<u8>,
+    ) -> Result<(), MemoryStorageError> {
+        let key = asset_key(slug, lang, name, &self.page_asset_name(name, asset)?);
+        self.data.write().page_assets.insert(key, content);
+        self.changed();
+        Ok(())
+    }
+
+    pub fn load_fixtures(&self, dir: &Path) -> Result<(), MemoryStorageError> {
+        let data_root = dir.join("data");
+        if data_root.is_dir() {
+            let mut files = vec![];
+            read_tree(&data_root, &data_root, &mut files)?;
+            let mut storages: HashMap<String, Vec<(String, Vec<u8>)>> = HashMap::new();
+            for (fpath, content) in files {
+                let Some((slug, fname)) = fpath.split_once('/') else {
+                    log::warn!("Ignoring fixture {fpath}: not in a storage dir");
+                    continue;
+                };
+                storages
+                    .entry(slug.to_string())
+                    .or_default()
+                    .push((fname.to_string(), content));
+            }
+            for (slug, files) in storages {
+                let page_files = PageFiles::new(
+                    files.iter().map(|(fname, _)| fname.as_str()),
+                    &self.supported_lang,
+                );
+                for (fname, content) in files {
+                    self.load_data_fixture(&slug, &fname, content, &page_files)?;
+                }
+            }
+        }
+
+        let template_root = dir.join("templates");
+        if template_root.is_dir() {
+            let mut files = vec![];
+            read_tree(&template_root, &template_root, &mut files)?;
+            for (fpath, content) in files {
+                let content = String::from_utf8(content).map_err(|_| {
+                    MemoryStorageError::LoadFixtures(format!("template {fpath} not UTF-8"))
+                })?;
+                self.add_template(&fpath, &content);
+            }
+        }
+
+        let assets_root = dir.join("assets");
+        if assets_root.is_dir() {
+            let mut files = vec![];
+            read_tree(&assets_root, &assets_root, &mut files)?;
+            for (fpath, content) in files {
+                self.add_static_file(&fpath, content)?;
+            }
+        }
+        Ok(())
+    }
+
+    // A data file is at {slug}/[{lang}/]{name}.md (or another page extension) for pages,
+    // .toml for contexts, a page bundle is at {slug}/[{lang}/]{name}/index.md with its assets
+    fn load_data_fixture(
+        &self,
+        slug: &str,
+        fname: &str,
+        content: Vec<u8>,
+        page_files: &PageFiles,
+    ) -> Result<(), MemoryStorageError> {
+        let fpath = format!("{slug}/{fname}");
+        let mut parts = fname.split('/').collect::<Vec<&str>>();
+        let lang = if parts.len() > 1 && self.supported_lang.iter().any(|l| l == parts[0]) {
+            Some(parts.remove(0))
+        } else {
+            None
+        };
+        let rel = parts.join("/");
+
+        if let Some((bundle, asset)) = page_files.bundle_of(fname) {
+            if !page_files.is_page(fname) {
+                let name = &bundle[lang.map(|l| l.len() + 1).unwrap_or(0)..];
+                return self.add_page_asset(slug, lang, name, asset, content);
+            }
+        }
+
+        let content = String::from_utf8(content)
+            .map_err(|_| MemoryStorageError::LoadFixtures(format!("{fpath} not UTF-8")))?;
+        let page_name = PAGE_EXTENSIONS
+            .iter()
+            .find_map(|ext| rel.strip_suffix(&format!(".{ext}")));
+        if let Some(name) = page_name {
+            let (mut metadata, body) = parse_page_content(&fpath, &content)?;
+            metadata.set_default_format(&fpath);
+            metadata.bundle = page_files.bundle_of(fname).is_some();
+            let name = match metadata.bundle {
+                // Safe to unwrap, the index of a bundle is in the directory of the page
+                true => rel.rsplit_once('/').unwrap().0,
+                false => name,
+            };
+            self.add_page(slug, lang, name, metadata, &body);
+        } else if let Some(name) = rel.strip_suffix(".toml") {
+            let ctxt: toml::Value = toml::from_str(&content)
+                .map_err(|e| MemoryStorageError::TomlDecode(format!("{fpath}: {e:?}")))?;
+            self.add_context(slug, lang, name, ctxt);
+        } else {
+            log::warn!("Ignoring fixture {fpath}: unknown extension");
+        }
+        Ok(())
+    }
+
+    fn static_file_name(&self, f: &str) -> Result<String, MemoryStorageError> {
+        normalize_relative_path(f).map_err(|e| match e {
+            RelativePathError::NoFileName => {
+                MemoryStorageError::BadRequest("no file name".to_string())
+            }
+            RelativePathError::IllegalName => {
+                MemoryStorageError::BadRequest("illegal characters in file name".to_string())
+            }
+            RelativePathError::Traversal => {
+                log::error!("Possible directory traversal attack spotted");
+                log::error!("Got a request for static file {f:?}");
+                MemoryStorageError::AttackSuspected(
+                    "memory-storage::static-file::directory-traversal".to_string(),
+                )
+            }
+        })
+    }
+
+    fn page_asset_name(&self, name: &str, asset: &str) -> Result<String, MemoryStorageError> {
+        normalize_relative_path(asset).map_err(|e| {
+            log::error!("Possible directory traversal attack spotted");
+            log::error!("Got a request for asset {asset:?} of page {name:?}: {e:?}");
+            MemoryStorageError::AttackSuspected(
+                "memory-storage::page-asset::directory-traversal".to_string(),
+            )
+        })
+    }
+
+    fn get_page(
+        &self,
+        slug: &str,
+        lang: Option<&String>,
+        name: &str,
+    ) -> Result<MemoryPage, MemoryStorageError> {
+        let data = self.data.read();
+        data.pages
+            .get(slug)
+            .and_then(|pages| {
+                pages
+                    .iter()
+                    .find(|p| p.lang.as_ref() == lang && p.name == name)
+            })
+            .cloned()
+            .ok_or_else(|| {
+                MemoryStorageError::DataNotFound(page_key(slug, lang.map(|l| l.as_str()), name))
+            })
+    }
+
+    // Languages a page is written in
+    fn page_translations(
+        &self,
+        slug: &String,
+        name: &str,
+    ) -> Result<Vec<String>, MemoryStorageError> {
+        self.pages_of(slug, |pages| {
+            self.supported_lang
+                .iter()
+                .filter(|l| {
+                    pages
+                        .iter()
+                        .any(|p| p.name == name && p.lang.as_ref() == Some(*l))
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    // Page in the first language of the query it is written in, with the language found
+    fn find_page(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+    ) -> Result<(MemoryPage, Option<String>), MemoryStorageError> {
+        let slug = &qry.storage_slug;
+        for lang in lang_candidates(qry, &self.supported_lang) {
+            if let Ok(mut page) = self.get_page(slug, lang.as_ref(), name) {
+                page.metadata.translations = self.page_translations(slug, name)?;
+                return Ok((page, lang));
+            }
+        }
+        let lang = self.select_lang(qry);
+        Err(MemoryStorageError::DataNotFound(page_key(
+            slug,
+            lang.as_deref(),
+            name,
+        )))
+    }
+
+    fn pages_of<F, R>(&self, slug: &String, f: F) -> Result<R, MemoryStorageError>
+    where
+        F: FnOnce(&Vec<MemoryPage>) -> R,
+    {
+        let data = self.data.read();
+        let Some(pages) = data.pages.get(slug) else {
+            return Err(MemoryStorageError::DataNotFound(slug.clone()));
+        };
+        Ok(f(pages))
+    }
+
+    pub fn dispatch(&self, qry: StorageQuery) -> Result<StorageData, MemoryStorageError> {
+        let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
+            (sort_key, rev)
+        } else {
+            (&self.default_sort.0, self.default_sort.1)
+        };
+        let lang = self.select_lang(&qry);
+        let limit = if qry.limit == 0 {
+            usize::MAX
+        } else {
+            qry.limit
+        };
+        let slug = &qry.storage_slug;
+        let now = timestamp_now();
+        let drafts = qry.drafts;
+
+        match qry.method {
+            StorageQueryMethod::NoOp => {
+                log::debug!("Memory storage No Op");
+                Ok(StorageData::Nothing)
+            }
+
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                let (page, lang) = self.find_page(&qry, name)?;
+                if !page.metadata.is_visible(now, drafts) {
+                    let key = page_key(slug, lang.as_deref(), name);
+                    return Err(MemoryStorageError::DataNotFound(key));
+                }
+                Ok(StorageData::PageContent {
+                    metadata: page.metadata,
+                    body: page.body,
+                    lang,
+                })
+            }
+
+            StorageQueryMethod::ContentNumId(id) | StorageQueryMethod::ContentLegacyId(id) => {
+                let page = self.pages_of(slug, |pages| {
+                    // An id shared by several pages is refused, whichever of them is visible
+                    let mut matches = pages.iter().filter(|p| qry.method.matches_id(&p.metadata));
+                    let Some(page) = matches.next() else {
+                        return Err(MemoryStorageError::NoMatch(format!("id = {id}")));
+                    };
+                    let other_matches = matches.count();
+                    if other_matches > 0 {
+                        return Err(MemoryStorageError::TooManyMatches(other_matches, 1));
+                    }
+                    if !page.metadata.is_visible(now, drafts) {
+                        return Err(MemoryStorageError::NoMatch(format!("id = {id}")));
+                    }
+                    Ok(page.clone())
+                })??;
+                let mut metadata = page.metadata;
+                metadata.translations = self.page_translations(slug, &page.name)?;
+                Ok(StorageData::PageContent {
+                    metadata,
+                    body: page.body,
+                    lang: page.lang,
+                })
+            }
+
+            StorageQueryMethod::RecentPages => {
+                let (results, total) = self.pages_of(slug, |pages| {
+                    let mut results = pages
+                        .iter()
+                        .map(|p| &p.metadata)
+                        .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m))
+                        .collect::<Vec<&PageMetadata>>();
+                    results.sort_by(|a, b| a.compare_md(sort_key, b));
+                    if rev {
+                        results.reverse();
+                    }
+                    let total = results.len();
+                    let results = results.into_iter().skip(qry.offset).take(limit);
+                    (results.cloned().collect(), total)
+                })?;
+                Ok(StorageData::RecentPages(results, total))
+            }
+
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
+                let (matches, total) = self.pages_of(slug, |pages| {
+                    let mut matches = pages
+                        .iter()
+                        .map(|p| &p.metadata)
+                        .filter(|m| {
+                            m.is_listed(now, drafts)
+                                && qry.matches_filter(m)
+                                && match (m.get_metadata(keys), val.as_ref()) {
+                                    (Some(md), Some(val)) => compare_similar_md(md, val),
+                                    (Some(_), None) | (None, Some(_)) => false,
+                                    (None, None) => true,
+                                }
+                        })
+                        .collect::<Vec<&PageMetadata>>();
+                    matches.sort_by(|a, b| a.compare_md(sort_key, b));
+                    if rev {
+                        matches.reverse();
+                    }
+                    let total = matches.len();
+                    let matches = matches.into_iter().skip(qry.offset).take(limit);
+                    (matches.cloned().collect(), total)
+                })?;
+                Ok(StorageData::SimilarPages(matches, total))
+            }
+
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
+                let matches = self.pages_of(slug, |pages| {
+                    pages
+                        .iter()
+                        .map(|p| &p.metadata)
+                        .filter(|m| {
+                            m.is_listed(now, drafts)
+                                && qry.matches_filter(m)
+                                && m.get_metadata(keys) == val.as_ref()
+                        })
+                        .filter_map(|m| m.get_metadata(query).cloned())
+                        .collect()
+                })?;
+                Ok(StorageData::QueryMetadata(matches))
+            }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                let groups = self.pages_of(slug, |pages| {
+                    agg.apply(
+                        pages
+                            .iter()
+                            .map(|p| &p.metadata)
+                            .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m)),
+                        sort_key,
+                    )
+                })?;
+                Ok(StorageData::Aggregates(groups))
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                let data = self.data.read();
+                // The first language of the query or of its fallbacks the context is in
+                for lang in lang_candidates(&qry, &self.supported_lang) {
+                    if let Some(ctxt) = data.contexts.get(&(slug.clone(), lang, name.clone())) {
+                        return Ok(StorageData::Context(ctxt.clone()));
+                    }
+                }
+                Err(MemoryStorageError::DataNotFound(format!(
+                    "{:?}",
+                    (slug, lang, name)
+                )))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                Ok(StorageData::Templates(self.data.read().templates.clone()))
+            }
+
+            StorageQueryMethod::StaticFile(ref f) => {
+                let fname = self.static_file_name(f)?;
+                let data = self.data.read();
+                let Some(content) = data.static_files.get(&fname) else {
+                    return Err(MemoryStorageError::DataNotFound(fname));
+                };
+                Ok(StorageData::StaticFileData(content.clone()))
+            }
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                let fname = self.page_asset_name(name, asset)?;
+                // Assets are stored along with the page, in the language it is found in
+                let (page, lang) = self.find_page(&qry, name)?;
+                let key = asset_key(slug, lang.as_deref(), name, &fname);
+                if is_bundle_index(&fname)
+                    || !page.metadata.bundle
+                    || !page.metadata.is_visible(now, drafts)
+                {
+                    return Err(MemoryStorageError::DataNotFound(key));
+                }
+                let data = self.data.read();
+                let Some(content) = data.page_assets.get(&key) else {
+                    return Err(MemoryStorageError::DataNotFound(key));
+                };
+                Ok(StorageData::StaticFileData(content.clone()))
+            }
+        }
+    }
+
+    pub fn write_data(&self, wrt: StorageWrite) -> Result<(), MemoryStorageError> {
+        let slug = &wrt.storage_slug;
+        let lang = wrt.lang.as_deref();
+        match wrt.method {
+            StorageWriteMethod::SavePage {
+                ref name,
+                ref metadata,
+                ref body,
+            } => {
+                self.add_page(slug, lang, &write_name(name)?, metadata.clone(), body);
+                Ok(())
+            }
+            StorageWriteMethod::DeletePage(ref name) => {
+                let name = &write_name(name)?;
+                let mut data = self.data.write();
+                let pages = data.pages.get_mut(slug);
+                let Some(pages) = pages else {
+                    return Err(MemoryStorageError::DataNotFound(page_key(slug, lang, name)));
+                };
+                let nb_pages = pages.len();
+                pages.retain(|p| p.lang.as_deref() != lang || p.name != *name);
+                if pages.len() == nb_pages {
+                    return Err(MemoryStorageError::DataNotFound(page_key(slug, lang, name)));
+                }
+                data.update_schedules(slug);
+                drop(data);
+                self.changed();
+                Ok(())
+            }
+
+            StorageWriteMethod::SaveContext(ref name, ref data) => {
+                self.add_context(slug, lang, &write_name(name)?, data.clone());
+                Ok(())
+            }
+            StorageWriteMethod::DeleteContext(ref name) => {
+                let key = (slug.clone(), wrt.lang.clone(), write_name(name)?);
+                if self.data.write().contexts.remove(&key).is_none() {
+                    return Err(MemoryStorageError::DataNotFound(format!("{key:?}")));
+                }
+                self.changed();
+                Ok(())
+            }
+
+            StorageWriteMethod::SaveStaticFile(ref fname, ref data) => {
+                self.add_static_file(fname, data.clone())
+            }
+            StorageWriteMethod::DeleteStaticFile(ref fname) => {
+                let fname = self.static_file_name(fname)?;
+                if self.data.write().static_files.remove(&fname).is_none() {
+                    return Err(MemoryStorageError::DataNotFound(fname));
+                }
+                self.changed();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn select_lang(&self, qry: &StorageQuery) -> Option<String> {
+        let lang = qry.lang_pref.as_ref()?;
+        lang.iter()
+            .find_map(|l| supported_lang_of(l, &self.supported_lang))
+            .cloned()
+    }
+}
+
+// Location of a page, as it would be laid out by a LocalStorage
+fn page_key(slug: &str, lang: Option<&str>, name: &str) -> String {
+    match lang {
+        Some(lang) => format!("{slug}/{lang}/{name}.md"),
+        None => format!("{slug}/{name}.md"),
+    }
+}
+
+// Location of the asset of a page bundle, as it would be laid out by a LocalStorage
+fn asset_key(slug: &str, lang: Option<&str>, name: &str, asset: &str) -> String {
+    match lang {
+        Some(lang) => format!("{slug}/{lang}/{name}/{asset}"),
+        None => format!("{slug}/{name}/{asset}"),
+    }
+}
+
+// Names written to are refused if LocalStorage would refuse them
+fn write_name(name: &str) -> Result<String, MemoryStorageError> {
+    normalize_relative_path(name).map_err(|e| {
+        log::error!("Refusing to write {name:?}: {e:?}");
+        MemoryStorageError::AttackSuspected(
+            "memory-storage::write::directory-traversal".to_string(),
+        )
+    })
+}
+
+impl StorageBackend for MemoryStorage {
+    type Error = MemoryStorageError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        if let Some(ref fixtures) = self.fixtures {
+            let fixtures = config.root.join(fixtures);
+            self.load_fixtures(&fixtures)?;
+            log::debug!("Loaded memory storage fixtures from {fixtures:?}");
+        }
+        log::debug!("Initialized memory storage");
+        log::debug!("Supported langs: {:?}", self.supported_lang);
+        Ok(())
+    }
+
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        let gen = self.generation.load(Ordering::Relaxed);
+        self.answered
+            .read()
+            .get(qry)
+            .is_none_or(|answered| *answered != gen)
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        let gen = self.generation.load(Ordering::Relaxed);
+        let res = self.dispatch(qry.clone());
+        self.answered.write().insert(qry, gen);
+
+        match res {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{e:?}");
+                StorageData::Error(e.into())
+            }
+        }
+    }
+
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        self.write_data(wrt).inspect_err(|e| log::error!("{e:?}"))
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        self.answered.write().remove(qry);
+    }
+
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        let data = self.data.read();
+        data.schedules.get(&qry.storage_slug)?.next(now)
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.supported_lang.clone()
+    }
+}
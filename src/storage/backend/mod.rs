@@ -1,18 +1,590 @@
-use actix_web::HttpResponseBuilder;
-use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::config::Config;
+use crate::page::{content_format_of, PageMetadata, PAGE_EXTENSIONS};
 
-use super::{StorageData, StorageQuery};
+use super::{StorageData, StorageQuery, StorageSlug, StorageWrite};
 
+#[cfg(feature = "storage-bundle")]
+pub mod bundle;
+#[cfg(feature = "storage-git")]
+pub mod git;
+#[cfg(feature = "storage-local")]
 pub mod local;
+#[cfg(feature = "storage-memory")]
+pub mod memory;
+#[cfg(feature = "storage-s3")]
+pub mod s3;
+#[cfg(feature = "storage-sqlite")]
+pub mod sqlite;
+#[cfg(feature = "storage-local")]
+mod stamps;
+
+#[cfg(not(any(
+    feature = "storage-local",
+    feature = "storage-sqlite",
+    feature = "storage-git",
+    feature = "storage-bundle",
+    feature = "storage-s3",
+    feature = "storage-memory"
+)))]
+compile_error!("At least one storage backend feature has to be enabled");
+
+/// Data affected by the change of a file watched by a backend
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StorageChange {
+    Pages(StorageSlug),
+    Templates,
+    StaticFiles,
+}
 
 #[allow(async_fn_in_trait)]
 pub trait StorageBackend {
     type Error: Into<HttpResponseBuilder> + Clone + Serialize + DeserializeOwned + std::fmt::Debug;
-    fn init(config: &Config) -> Result<Self, Self::Error>
-    where
-        Self: Sized;
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error>;
     async fn has_changed(&self, qry: &StorageQuery) -> bool;
     async fn query(&self, qry: StorageQuery) -> StorageData;
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error>;
+
+    // Next time one of the pages the query reads from gets published or expires, after now
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        None
+    }
+
+    // Drops what was kept to tell whether the data of a query changed, once it isn't cached
+    fn forget(&self, qry: &StorageQuery) {}
+
+    // Files to watch, once called the backend relies on files_changed to be notified
+    // instead of checking its files on every query
+    fn watch(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+    // Files changed together, such as the files saved at once by an editor
+    fn files_changed(&self, paths: &[PathBuf]) -> Vec<StorageChange> {
+        vec![]
+    }
+
+    // Languages the pages can be translated in
+    fn supported_lang(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Storage backend selected from the configuration file at startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend")]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[cfg(feature = "storage-local")]
+    Local(local::LocalStorage),
+    #[cfg(feature = "storage-sqlite")]
+    Sqlite(sqlite::SqliteStorage),
+    #[cfg(feature = "storage-git")]
+    Git(git::GitStorage),
+    #[cfg(feature = "storage-bundle")]
+    Bundle(bundle::BundleStorage),
+    #[cfg(feature = "storage-memory")]
+    Memory(memory::MemoryStorage),
+    #[cfg(feature = "storage-s3")]
+    S3(s3::S3Storage),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum BackendError {
+    #[cfg(feature = "storage-local")]
+    Local(local::LocalStorageError),
+    #[cfg(feature = "storage-sqlite")]
+    Sqlite(sqlite::SqliteStorageError),
+    #[cfg(feature = "storage-git")]
+    Git(git::GitStorageError),
+    #[cfg(feature = "storage-bundle")]
+    Bundle(bundle::BundleStorageError),
+    #[cfg(feature = "storage-memory")]
+    Memory(memory::MemoryStorageError),
+    #[cfg(feature = "storage-s3")]
+    S3(s3::S3StorageError),
+
+    // Mount name, index of the backend in the mount, error
+    Mount(String, usize, Box<BackendError>),
+    EmptyMount(String),
+}
+
+impl BackendError {
+    /// Whether the next backend of a mount should be tried
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            #[cfg(feature = "storage-local")]
+            BackendError::Local(e) => e.is_not_found(),
+            #[cfg(feature = "storage-sqlite")]
+            BackendError::Sqlite(e) => e.is_not_found(),
+            #[cfg(feature = "storage-git")]
+            BackendError::Git(e) => e.is_not_found(),
+            #[cfg(feature = "storage-bundle")]
+            BackendError::Bundle(e) => e.is_not_found(),
+            #[cfg(feature = "storage-memory")]
+            BackendError::Memory(e) => e.is_not_found(),
+            #[cfg(feature = "storage-s3")]
+            BackendError::S3(e) => e.is_not_found(),
+            BackendError::Mount(_, _, e) => e.is_not_found(),
+            BackendError::EmptyMount(_) => false,
+        }
+    }
+}
+
+impl From<BackendError> for HttpResponseBuilder {
+    fn from(val: BackendError) -> Self {
+        match val {
+            #[cfg(feature = "storage-local")]
+            BackendError::Local(e) => e.into(),
+            #[cfg(feature = "storage-sqlite")]
+            BackendError::Sqlite(e) => e.into(),
+            #[cfg(feature = "storage-git")]
+            BackendError::Git(e) => e.into(),
+            #[cfg(feature = "storage-bundle")]
+            BackendError::Bundle(e) => e.into(),
+            #[cfg(feature = "storage-memory")]
+            BackendError::Memory(e) => e.into(),
+            #[cfg(feature = "storage-s3")]
+            BackendError::S3(e) => e.into(),
+            BackendError::Mount(_, _, e) => (*e).into(),
+            BackendError::EmptyMount(_) => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+#[cfg(feature = "storage-local")]
+impl From<local::LocalStorageError> for BackendError {
+    fn from(value: local::LocalStorageError) -> Self {
+        BackendError::Local(value)
+    }
+}
+
+#[cfg(feature = "storage-sqlite")]
+impl From<sqlite::SqliteStorageError> for BackendError {
+    fn from(value: sqlite::SqliteStorageError) -> Self {
+        BackendError::Sqlite(value)
+    }
+}
+
+#[cfg(feature = "storage-git")]
+impl From<git::GitStorageError> for BackendError {
+    fn from(value: git::GitStorageError) -> Self {
+        BackendError::Git(value)
+    }
+}
+
+#[cfg(feature = "storage-bundle")]
+impl From<bundle::BundleStorageError> for BackendError {
+    fn from(value: bundle::BundleStorageError) -> Self {
+        BackendError::Bundle(value)
+    }
+}
+
+#[cfg(feature = "storage-memory")]
+impl From<memory::MemoryStorageError> for BackendError {
+    fn from(value: memory::MemoryStorageError) -> Self {
+        BackendError::Memory(value)
+    }
+}
+
+#[cfg(feature = "storage-s3")]
+impl From<s3::S3StorageError> for BackendError {
+    fn from(value: s3::S3StorageError) -> Self {
+        BackendError::S3(value)
+    }
+}
+
+impl StorageBackend for Backend {
+    type Error = BackendError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => Ok(s.init(config)?),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => Ok(s.init(config)?),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => Ok(s.init(config)?),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => Ok(s.init(config)?),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => Ok(s.init(config)?),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => Ok(s.init(config)?),
+        }
+    }
+
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.has_changed(qry).await,
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.has_changed(qry).await,
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.has_changed(qry).await,
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.has_changed(qry).await,
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.has_changed(qry).await,
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.has_changed(qry).await,
+        }
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.query(qry).await,
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.query(qry).await,
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.query(qry).await,
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.query(qry).await,
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.query(qry).await,
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.query(qry).await,
+        }
+    }
+
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => Ok(s.write(wrt).await?),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => Ok(s.write(wrt).await?),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => Ok(s.write(wrt).await?),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => Ok(s.write(wrt).await?),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => Ok(s.write(wrt).await?),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => Ok(s.write(wrt).await?),
+        }
+    }
+
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.next_schedule(qry, now).await,
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.next_schedule(qry, now).await,
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.next_schedule(qry, now).await,
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.next_schedule(qry, now).await,
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.next_schedule(qry, now).await,
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.next_schedule(qry, now).await,
+        }
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.watch(),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.watch(),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.watch(),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.watch(),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.watch(),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.watch(),
+        }
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.forget(qry),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.forget(qry),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.forget(qry),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.forget(qry),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.forget(qry),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.forget(qry),
+        }
+    }
+
+    fn files_changed(&self, paths: &[PathBuf]) -> Vec<StorageChange> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.files_changed(paths),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.files_changed(paths),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.files_changed(paths),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.files_changed(paths),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.files_changed(paths),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.files_changed(paths),
+        }
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        match self {
+            #[cfg(feature = "storage-local")]
+            Backend::Local(s) => s.supported_lang(),
+            #[cfg(feature = "storage-sqlite")]
+            Backend::Sqlite(s) => s.supported_lang(),
+            #[cfg(feature = "storage-git")]
+            Backend::Git(s) => s.supported_lang(),
+            #[cfg(feature = "storage-bundle")]
+            Backend::Bundle(s) => s.supported_lang(),
+            #[cfg(feature = "storage-memory")]
+            Backend::Memory(s) => s.supported_lang(),
+            #[cfg(feature = "storage-s3")]
+            Backend::S3(s) => s.supported_lang(),
+        }
+    }
+}
+
+/// Why a requested file path was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelativePathError {
+    NoFileName,
+    IllegalName,
+    Traversal,
+}
+
+// Relative path of a file requested from a storage that isn't a directory on disk,
+// refusing the same requests LocalStorage would consider as an attack
+pub fn normalize_relative_path(f: &str) -> Result<String, RelativePathError> {
+    let path = Path::new(f.trim_start_matches('/'));
+    let mut parts = vec![];
+    for comp in path.components() {
+        match comp {
+            Component::Normal(part) => {
+                let Some(part) = part.to_str() else {
+                    return Err(RelativePathError::IllegalName);
+                };
+                parts.push(part);
+            }
+            Component::CurDir => {}
+            _ => return Err(RelativePathError::Traversal),
+        }
+    }
+    if parts.is_empty() {
+        return Err(RelativePathError::NoFileName);
+    }
+    Ok(parts.join("/"))
+}
+
+/// Name of the file holding a page bundle, in the directory named after the page
+pub const BUNDLE_INDEX: &str = "index";
+
+// Files a page can be stored in, relative to the directory of its storage,
+// with whether the page is a bundle, in the order they are looked for
+pub fn page_file_candidates(name: &str) -> Vec<(String, bool)> {
+    let mut candidates = PAGE_EXTENSIONS
+        .iter()
+        .map(|ext| (format!("{name}.{ext}"), false))
+        .collect::<Vec<(String, bool)>>();
+    candidates.extend(
+        PAGE_EXTENSIONS
+            .iter()
+            .map(|ext| (format!("{name}/{BUNDLE_INDEX}.{ext}"), true)),
+    );
+    candidates
+}
+
+// Languages a page is looked for in: the supported ones among the preferences of
+// the query, in order, then the root of the storage slug for untranslated pages
+pub fn lang_candidates(qry: &StorageQuery, supported_lang: &[String]) -> Vec<Option<String>> {
+    let mut langs = vec![];
+    for lang in qry.lang_pref.iter().flatten() {
+        let Some(lang) = supported_lang_of(lang, supported_lang) else {
+            continue;
+        };
+        if !langs.contains(&Some(lang.clone())) {
+            langs.push(Some(lang.clone()));
+        }
+    }
+    langs.push(None);
+    langs
+}
+
+// Supported language matching a language tag, in the spelling of the configuration as tags
+// are compared in any case ("en-US" for the "en-us" of a header)
+pub fn supported_lang_of<'a>(lang: &str, supported_lang: &'a [String]) -> Option<&'a String> {
+    supported_lang.iter().find(|l| l.eq_ignore_ascii_case(lang))
+}
+
+// Language of a page, from its file relative to the directory of its storage
+pub fn page_file_lang(file: &str, supported_lang: &[String]) -> Option<String> {
+    let (dir, _) = file.split_once('/')?;
+    supported_lang.iter().find(|lang| *lang == dir).cloned()
+}
+
+// Name of the page stored in a file relative to the directory of its storage, without
+// its language directory, its extension nor the index of its bundle
+pub fn page_file_name(file: &str, supported_lang: &[String]) -> String {
+    let file = match page_file_lang(file, supported_lang) {
+        Some(lang) => &file[lang.len() + 1..],
+        None => file,
+    };
+    let path = match file.rsplit_once('.') {
+        Some((path, ext)) if PAGE_EXTENSIONS.contains(&ext) => path,
+        _ => file,
+    };
+    match path
+        .strip_suffix(BUNDLE_INDEX)
+        .and_then(|dir| dir.strip_suffix('/'))
+    {
+        Some(dir) if !dir.is_empty() => dir.to_string(),
+        _ => path.to_string(),
+    }
+}
+
+// Whether a file is the page of a bundle rather than one of its assets
+pub fn is_bundle_index(fname: &str) -> bool {
+    let fname = fname.rsplit('/').next().unwrap_or(fname);
+    PAGE_EXTENSIONS
+        .iter()
+        .any(|ext| fname == format!("{BUNDLE_INDEX}.{ext}"))
+}
+
+// Path of the page stored in a file relative to the data root, without its extension
+// nor the index of its bundle, so changing its format or adding assets keeps its id
+pub fn page_file_id_path(file: &str) -> &str {
+    let path = match file.rsplit_once('.') {
+        Some((path, ext)) if PAGE_EXTENSIONS.contains(&ext) => path,
+        _ => file,
+    };
+    match path.strip_suffix(BUNDLE_INDEX) {
+        Some(dir) if dir.ends_with('/') && dir[..dir.len() - 1].contains('/') => {
+            &dir[..dir.len() - 1]
+        }
+        _ => path,
+    }
+}
+
+// Pages sharing an id can't be told apart from it, logged when indexing the storage.
+// Looking the id up is then refused by the backends.
+pub fn check_id_collisions<'a, P: std::fmt::Debug + 'a>(
+    slug: &str,
+    pages: impl IntoIterator<Item = &'a (P, PageMetadata)>,
+) {
+    let mut ids = HashMap::new();
+    for (path, metadata) in pages {
+        if let Some(other) = ids.insert(metadata.id.value(), path) {
+            log::error!(
+                "Pages {other:?} and {path:?} of {slug} have the same id {}",
+                metadata.id
+            );
+        }
+    }
+}
+
+/// Dates the pages of an index get published or expire at, kept with the index so the
+/// next one is found without going through the pages on every query
+#[derive(Debug, Clone, Default)]
+pub struct PageSchedules(Vec<i64>);
+
+impl PageSchedules {
+    pub fn new<'a>(pages: impl IntoIterator<Item = &'a PageMetadata>) -> PageSchedules {
+        let mut dates = pages
+            .into_iter()
+            .flat_map(|m| [m.publish_date, m.expire_date])
+            .flatten()
+            .collect::<Vec<i64>>();
+        dates.sort_unstable();
+        dates.dedup();
+        PageSchedules(dates)
+    }
+
+    // First date strictly after now
+    pub fn next(&self, now: i64) -> Option<i64> {
+        let pos = self.0.partition_point(|date| *date <= now);
+        self.0.get(pos).copied()
+    }
+}
+
+/// Pages among the files of a storage. A directory holding an index page is a page bundle,
+/// the other files in it are the assets of the page.
+pub struct PageFiles {
+    bundles: HashSet<String>,
+}
+
+impl PageFiles {
+    // Files are relative to the directory of the storage
+    pub fn new<'a>(files: impl Iterator<Item = &'a str>, supported_lang: &[String]) -> PageFiles {
+        let bundles = files
+            .filter(|f| is_bundle_index(f))
+            .filter_map(|f| f.rsplit_once('/').map(|(dir, _)| dir.to_string()))
+            // A language directory holding a page named "index" isn't a bundle
+            .filter(|dir| !supported_lang.contains(dir))
+            .collect();
+        PageFiles { bundles }
+    }
+
+    pub fn is_page(&self, file: &str) -> bool {
+        if content_format_of(file).is_none() {
+            return false;
+        }
+        match self.bundle_of(file) {
+            Some((_, fname)) => !fname.contains('/') && is_bundle_index(fname),
+            None => true,
+        }
+    }
+
+    // Outermost bundle holding a file, with the path of the file in it
+    pub fn bundle_of<'a>(&self, file: &'a str) -> Option<(&'a str, &'a str)> {
+        let mut found = None;
+        let mut dir = file;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            if self.bundles.contains(parent) {
+                found = Some((parent, &file[parent.len() + 1..]));
+            }
+            dir = parent;
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_pages_from_their_file() {
+        let langs = ["en".to_string(), "fr".to_string()];
+        assert_eq!(page_file_name("hello.md", &langs), "hello");
+        assert_eq!(page_file_name("fr/hello.md", &langs), "hello");
+        assert_eq!(page_file_name("fr/hello/index.md", &langs), "hello");
+        assert_eq!(page_file_name("en/index.md", &langs), "index");
+        assert_eq!(page_file_name("index.html", &langs), "index");
+        assert_eq!(page_file_name("de/v1.2.md", &langs), "de/v1.2");
+    }
+
+    #[test]
+    fn matches_supported_languages_in_any_case() {
+        let langs = ["en-US".to_string(), "fr".to_string()];
+        let mut qry = StorageQuery::query_context(&"site".to_string(), "site".to_string());
+        qry.set_lang(vec![
+            "en-us".to_string(),
+            "FR".to_string(),
+            "en".to_string(),
+        ]);
+        assert_eq!(
+            lang_candidates(&qry, &langs),
+            [Some("en-US".to_string()), Some("fr".to_string()), None]
+        );
+    }
 }
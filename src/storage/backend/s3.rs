@@ -0,0 +1,908 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectMeta, ObjectStore, PutPayload};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::frontmatter::{format_page_content, parse_page_content, FrontmatterError};
+use crate::page::{compare_similar_md, timestamp_now, PageMetadata, PAGE_EXTENSIONS};
+use crate::storage::data::FileStream;
+use crate::storage::query::StorageQueryMethod;
+use crate::storage::write::StorageWriteMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
+
+use super::{
+    check_id_collisions, is_bundle_index, lang_candidates, normalize_relative_path,
+    page_file_candidates, page_file_id_path, page_file_lang, page_file_name, supported_lang_of,
+    PageFiles, PageSchedules, RelativePathError, StorageBackend,
+};
+
+// Pages fetched at the same time when building the index of a storage slug
+const CONCURRENT_FETCHES: usize = 16;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum S3StorageError {
+    InitClient(String),
+    NotInitialized,
+    ObjectStore(String),
+
+    DataNotFound(String),
+    NotUtf8(String),
+    Frontmatter(FrontmatterError),
+
+    NoMatch(String),
+    TooManyMatches(usize, usize),
+
+    TomlDecode(String),
+    TomlEncode(String),
+
+    BadRequest(String),
+    AttackSuspected(String),
+    NoAssetsPrefix,
+}
+
+impl From<S3StorageError> for HttpResponseBuilder {
+    fn from(val: S3StorageError) -> Self {
+        match val {
+            S3StorageError::DataNotFound(_) | S3StorageError::NoMatch(_) => {
+                HttpResponse::NotFound()
+            }
+            _ => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+impl S3StorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            S3StorageError::DataNotFound(_) | S3StorageError::NoMatch(_)
+        )
+    }
+}
+
+impl From<FrontmatterError> for S3StorageError {
+    fn from(value: FrontmatterError) -> Self {
+        S3StorageError::Frontmatter(value)
+    }
+}
+
+impl From<object_store::Error> for S3StorageError {
+    fn from(value: object_store::Error) -> Self {
+        match value {
+            object_store::Error::NotFound { path, .. } => S3StorageError::DataNotFound(path),
+            e => S3StorageError::ObjectStore(format!("{e:?}")),
+        }
+    }
+}
+
+// What the data returned for a query was read from
+#[derive(Debug, Clone)]
+enum Source {
+    Nothing,
+    Object(ObjectPath),
+    // Any of the objects, like a page looked up with each page extension
+    Objects(Vec<ObjectPath>),
+    Listing(ObjectPath),
+}
+
+//                    Object key      ETag    Metadata
+type PageCache = HashMap<String, (Option<String>, PageMetadata)>;
+
+//                    Storage     Listing version   Object key Metadata     Publish/expire dates
+type PagesIndex = HashMap<String, (String, Arc<Vec<(String, PageMetadata)>>, PageSchedules)>;
+
+// Source of each query, with its version when it was answered
+type AnsweredQueries = HashMap<StorageQuery, (Source, Option<String>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Storage {
+    #[serde(skip)]
+    store: Option<Arc<AmazonS3>>,
+    #[serde(skip)]
+    pages: Arc<RwLock<PageCache>>,
+    #[serde(skip)]
+    indexes: Arc<RwLock<PagesIndex>>,
+    #[serde(skip)]
+    answered: Arc<RwLock<AnsweredQueries>>,
+
+    bucket: String,
+    #[serde(default)]
+    region: Option<String>,
+    // Set to use an S3-compatible service, like MinIO
+    #[serde(default)]
+    endpoint: Option<String>,
+    // Read from the AWS_* environment variables if not set
+    #[serde(default)]
+    access_key_id: Option<String>,
+    #[serde(default)]
+    secret_access_key: Option<String>,
+
+    // Data, the storage slug is appended to the prefix
+    #[serde(default)]
+    data_prefix: String,
+    #[serde(default)]
+    supported_lang: Vec<String>,
+    #[serde(default)]
+    default_sort: (Vec<String>, bool),
+
+    // Templates
+    #[serde(default)]
+    template_prefix: String,
+
+    // Assets, SCSS has to be compiled before being uploaded
+    #[serde(default)]
+    include_assets: Vec<String>,
+}
+
+// Key of an object, made of slash separated parts
+fn object_key(parts: &[&str]) -> ObjectPath {
+    ObjectPath::from_iter(
+        parts
+            .iter()
+            .flat_map(|p| p.split('/'))
+            .filter(|p| !p.is_empty()),
+    )
+}
+
+fn listing_version(objects: &[ObjectMeta]) -> String {
+    let mut s = DefaultHasher::new();
+    for obj in objects {
+        s.write(obj.location.as_ref().as_bytes());
+        s.write(obj.e_tag.as_deref().unwrap_or_default().as_bytes());
+    }
+    format!("{:x}", s.finish())
+}
+
+impl S3Storage {
+    pub fn connect(&mut self) -> Result<(), S3StorageError> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&self.bucket);
+        if let Some(ref region) = self.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(ref endpoint) = self.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(ref key_id) = self.access_key_id {
+            builder = builder.with_access_key_id(key_id);
+        }
+        if let Some(ref secret) = self.secret_access_key {
+            builder = builder.with_secret_access_key(secret);
+        }
+        let store = builder
+            .build()
+            .map_err(|e| S3StorageError::InitClient(format!("{e:?}")))?;
+        self.store = Some(Arc::new(store));
+        Ok(())
+    }
+
+    fn store(&self) -> Result<&AmazonS3, S3StorageError> {
+        self.store.as_deref().ok_or(S3StorageError::NotInitialized)
+    }
+
+    fn content_key(&self, slug: &str, name: &str, lang: Option<&String>, ext: &str) -> ObjectPath {
+        let fname = format!("{name}.{ext}");
+        match lang {
+            Some(lang) => object_key(&[&self.data_prefix, slug, lang, &fname]),
+            None => object_key(&[&self.data_prefix, slug, &fname]),
+        }
+    }
+
+    // Keys a page can be stored at, with whether the page is a bundle
+    fn page_keys(&self, slug: &str, name: &str, lang: Option<&String>) -> Vec<(ObjectPath, bool)> {
+        page_file_candidates(name)
+            .into_iter()
+            .map(|(fname, bundle)| {
+                let key = match lang {
+                    Some(lang) => object_key(&[&self.data_prefix, slug, lang, &fname]),
+                    None => object_key(&[&self.data_prefix, slug, &fname]),
+                };
+                (key, bundle)
+            })
+            .collect()
+    }
+
+    fn source(&self, qry: &StorageQuery) -> Source {
+        let lang = self.select_lang(qry);
+        let slug = &qry.storage_slug;
+        match qry.method {
+            StorageQueryMethod::NoOp
+            | StorageQueryMethod::StaticFile(_)
+            | StorageQueryMethod::PageAsset(..) => Source::Nothing,
+
+            // The page may be found in any language, or in the ones it falls back to
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                let langs = self.supported_lang.iter().map(Some).chain([None]);
+                Source::Objects(
+                    langs
+                        .flat_map(|lang| self.page_keys(slug, name, lang))
+                        .map(|(key, _)| key)
+                        .collect(),
+                )
+            }
+            StorageQueryMethod::QueryContext(ref name) => Source::Objects(
+                lang_candidates(qry, &self.supported_lang)
+                    .into_iter()
+                    .map(|lang| self.content_key(slug, name, lang.as_ref(), "toml"))
+                    .collect(),
+            ),
+
+            StorageQueryMethod::ContentNumId(_)
+            | StorageQueryMethod::ContentLegacyId(_)
+            | StorageQueryMethod::RecentPages
+            | StorageQueryMethod::GetSimilarPages(_)
+            | StorageQueryMethod::QueryMetadata(..)
+            | StorageQueryMethod::AggregateMetadata(_) => {
+                Source::Listing(object_key(&[&self.data_prefix, slug]))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                Source::Listing(object_key(&[&self.template_prefix]))
+            }
+        }
+    }
+
+    // ETag of an object, or a hash of the ETags of the objects listed, None if missing
+    async fn version(&self, source: &Source) -> Result<Option<String>, S3StorageError> {
+        match source {
+            Source::Nothing => Ok(None),
+            Source::Object(key) => self.etag(key).await,
+            Source::Objects(keys) => {
+                let versions = futures_util::stream::iter(keys)
+                    .map(|key| self.etag(key))
+                    .buffered(CONCURRENT_FETCHES)
+                    .try_collect::<Vec<Option<String>>>()
+                    .await?;
+                let versions = versions.into_iter().map(|v| v.unwrap_or_default());
+                Ok(Some(versions.collect::<Vec<String>>().join(",")))
+            }
+            Source::Listing(prefix) => Ok(Some(listing_version(&self.list(prefix).await?))),
+        }
+    }
+
+    async fn etag(&self, key: &ObjectPath) -> Result<Option<String>, S3StorageError> {
+        match self.store()?.head(key).await {
+            Ok(meta) => Ok(meta.e_tag),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &ObjectPath) -> Result<Vec<ObjectMeta>, S3StorageError> {
+        let mut objects = self
+            .store()?
+            .list(Some(prefix))
+            .try_collect::<Vec<ObjectMeta>>()
+            .await?;
+        objects.sort_by(|a, b| a.location.cmp(&b.location));
+        Ok(objects)
+    }
+
+    async fn read(&self, key: &ObjectPath) -> Result<(Option<String>, String), S3StorageError> {
+        let res = self.store()?.get(key).await?;
+        let etag = res.meta.e_tag.clone();
+        let data = res.bytes().await?;
+        let text = String::from_utf8(data.to_vec())
+            .map_err(|_| S3StorageError::NotUtf8(key.to_string()))?;
+        Ok((etag, text))
+    }
+
+    fn parse_content(
+        &self,
+        key: &ObjectPath,
+        content: &str,
+    ) -> Result<(PageMetadata, String), S3StorageError> {
+        let (mut metadata, body) = parse_page_content(key.as_ref(), content)?;
+        let root = object_key(&[&self.data_prefix]);
+        let rel = match key.prefix_match(&root) {
+            Some(parts) => parts.map(|p| p.as_ref().to_string()).collect::<Vec<_>>(),
+            None => key.parts().map(|p| p.as_ref().to_string()).collect(),
+        };
+        metadata.update_id(page_file_id_path(&rel.join("/")));
+        metadata.set_default_format(key.as_ref());
+        Ok((metadata, body))
+    }
+
+    async fn load_content(
+        &self,
+        key: &ObjectPath,
+    ) -> Result<(PageMetadata, String), S3StorageError> {
+        let (etag, content) = self.read(key).await?;
+        let (metadata, body) = self.parse_content(key, &content)?;
+        self.pages
+            .write()
+            .insert(key.to_string(), (etag, metadata.clone()));
+        Ok((metadata, body))
+    }
+
+    // Metadata of a listed page, only fetched again if its ETag changed
+    async fn page_metadata(&self, obj: &ObjectMeta) -> Result<PageMetadata, S3StorageError> {
+        let key = obj.location.to_string();
+        if let Some((etag, metadata)) = self.pages.read().get(&key) {
+            if obj.e_tag.is_some() && *etag == obj.e_tag {
+                return Ok(metadata.clone());
+            }
+        }
+        Ok(self.load_content(&obj.location).await?.0)
+    }
+
+    async fn pages_index(
+        &self,
+        slug: &String,
+    ) -> Result<Arc<Vec<(String, PageMetadata)>>, S3StorageError> {
+        let prefix = object_key(&[&self.data_prefix, slug]);
+        let objects = self.list(&prefix).await?;
+        if objects.is_empty() {
+            return Err(S3StorageError::DataNotFound(prefix.to_string()));
+        }
+        let version = listing_version(&objects);
+        if let Some((idx_version, pages, _)) = self.indexes.read().get(slug) {
+            if *idx_version == version {
+                return Ok(pages.clone());
+            }
+        }
+
+        let files = objects
+            .iter()
+            .filter_map(|obj| {
+                let rel = obj.location.prefix_match(&prefix)?;
+                Some((
+                    obj,
+                    rel.map(|p| p.as_ref().to_string())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                ))
+            })
+            .collect::<Vec<(&ObjectMeta, String)>>();
+        let page_files = PageFiles::new(
+            files.iter().map(|(_, rel)| rel.as_str()),
+            &self.supported_lang,
+        );
+        let pages = futures_util::stream::iter(files.iter())
+            .filter(|(_, rel)| std::future::ready(page_files.is_page(rel)))
+            .map(|(obj, rel)| async move {
+                let mut metadata = self.page_metadata(obj).await?;
+                metadata.bundle = is_bundle_index(rel);
+                metadata.lang = page_file_lang(rel, &self.supported_lang);
+                Ok::<_, S3StorageError>((obj.location.to_string(), metadata))
+            })
+            .buffered(CONCURRENT_FETCHES)
+            .try_collect::<Vec<(String, PageMetadata)>>()
+            .await?;
+        log::debug!("Registered {} pages in {slug}", pages.len());
+        check_id_collisions(slug, pages.iter());
+        let schedules = PageSchedules::new(pages.iter().map(|(_, m)| m));
+        let pages = Arc::new(pages);
+        self.indexes
+            .write()
+            .insert(slug.clone(), (version, pages.clone(), schedules));
+        Ok(pages)
+    }
+
+    async fn load_static_file(&self, f: &str) -> Result<StorageData, S3StorageError> {
+        let fpath = normalize_relative_path(f).map_err(|e| match e {
+            RelativePathError::NoFileName => S3StorageError::BadRequest("no file name".to_string()),
+            RelativePathError::IllegalName => {
+                S3StorageError::BadRequest("illegal characters in file name".to_string())
+            }
+            RelativePathError::Traversal => {
+                log::error!("Possible directory traversal attack spotted");
+                log::error!("Got a request for static file {f:?}");
+                S3StorageError::AttackSuspected(
+                    "s3-storage::static-file::directory-traversal".to_string(),
+                )
+            }
+        })?;
+
+        for inc in self.include_assets.iter() {
+            match self.store()?.get(&object_key(&[inc, &fpath])).await {
+                Ok(res) => {
+                    let stream = res.into_stream().map_err(std::io::Error::other);
+                    return Ok(StorageData::StaticFileStream(FileStream::new(Box::pin(
+                        stream,
+                    ))));
+                }
+                Err(object_store::Error::NotFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(S3StorageError::DataNotFound(fpath))
+    }
+
+    pub async fn dispatch(&self, qry: StorageQuery) -> Result<StorageData, S3StorageError> {
+        let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
+            (sort_key, rev)
+        } else {
+            (&self.default_sort.0, self.default_sort.1)
+        };
+        let lang = self.select_lang(&qry);
+        let limit = if qry.limit == 0 {
+            usize::MAX
+        } else {
+            qry.limit
+        };
+        let slug = &qry.storage_slug;
+        let now = timestamp_now();
+        let drafts = qry.drafts;
+
+        match qry.method {
+            StorageQueryMethod::NoOp => {
+                log::debug!("S3 storage No Op");
+                Ok(StorageData::Nothing)
+            }
+
+            StorageQueryMethod::ContentFromName(ref name)
+            | StorageQueryMethod::ContentSlug(ref name) => {
+                for lang in lang_candidates(&qry, &self.supported_lang) {
+                    for (key, bundle) in self.page_keys(slug, name, lang.as_ref()) {
+                        match self.load_content(&key).await {
+                            Ok((metadata, _)) if !metadata.is_visible(now, drafts) => {
+                                return Err(S3StorageError::DataNotFound(key.to_string()));
+                            }
+                            Ok((mut metadata, body)) => {
+                                metadata.bundle = bundle;
+                                metadata.translations = self.page_translations(slug, name).await?;
+                                return Ok(StorageData::PageContent {
+                                    metadata,
+                                    body,
+                                    lang,
+                                });
+                            }
+                            Err(S3StorageError::DataNotFound(_)) => continue,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                let key = self.content_key(slug, name, lang.as_ref(), PAGE_EXTENSIONS[0]);
+                Err(S3StorageError::DataNotFound(key.to_string()))
+            }
+
+            StorageQueryMethod::ContentNumId(id) | StorageQueryMethod::ContentLegacyId(id) => {
+                let pages = self.pages_index(slug).await?;
+                // An id shared by several pages is refused, whichever of them is visible
+                let mut matches = pages.iter().filter(|(_, m)| qry.method.matches_id(m));
+                let Some((key, page)) = matches.next() else {
+                    return Err(S3StorageError::NoMatch(format!("id = {id}")));
+                };
+                let other_matches = matches.count();
+                if other_matches > 0 {
+                    return Err(S3StorageError::TooManyMatches(other_matches, 1));
+                }
+                if !page.is_visible(now, drafts) {
+                    return Err(S3StorageError::NoMatch(format!("id = {id}")));
+                }
+                let (mut metadata, body) =
+                    self.load_content(&ObjectPath::from(key.as_str())).await?;
+                metadata.bundle = page.bundle;
+                metadata.lang = page.lang.clone();
+                let prefix = format!("{}/", object_key(&[&self.data_prefix, slug]));
+                let rel = key.strip_prefix(&prefix).unwrap_or(key);
+                let name = page_file_name(rel, &self.supported_lang);
+                metadata.translations = self.page_translations(slug, &name).await?;
+                Ok(StorageData::PageContent {
+                    lang: metadata.lang.clone(),
+                    metadata,
+                    body,
+                })
+            }
+
+            StorageQueryMethod::RecentPages => {
+                let pages = self.pages_index(slug).await?;
+                let mut results = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m))
+                    .collect::<Vec<&PageMetadata>>();
+                results.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    results.reverse();
+                }
+                let total = results.len();
+                let results = results.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::RecentPages(results.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
+                let pages = self.pages_index(slug).await?;
+                let mut matches = pages
+                    .iter()
+                    .map(|(_, m)| m)
+                    .filter(|m| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && match (m.get_metadata(keys), val.as_ref()) {
+                                (Some(md), Some(val)) => compare_similar_md(md, val),
+                                (Some(_), None) | (None, Some(_)) => false,
+                                (None, None) => true,
+                            }
+                    })
+                    .collect::<Vec<&PageMetadata>>();
+                matches.sort_by(|a, b| a.compare_md(sort_key, b));
+                if rev {
+                    matches.reverse();
+                }
+                let total = matches.len();
+                let matches = matches.into_iter().skip(qry.offset).take(limit);
+                Ok(StorageData::SimilarPages(matches.cloned().collect(), total))
+            }
+
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
+                let pages = self.pages_index(slug).await?;
+                let matches = pages
+                    .iter()
+                    .filter(|(_, m)| {
+                        m.is_listed(now, drafts)
+                            && qry.matches_filter(m)
+                            && m.get_metadata(keys) == val.as_ref()
+                    })
+                    .filter_map(|(_, m)| m.get_metadata(query).cloned())
+                    .collect::<Vec<serde_json::Value>>();
+                Ok(StorageData::QueryMetadata(matches))
+            }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                let pages = self.pages_index(slug).await?;
+                let groups = agg.apply(
+                    pages
+                        .iter()
+                        .map(|(_, m)| m)
+                        .filter(|m| m.is_listed(now, drafts) && qry.matches_filter(m)),
+                    sort_key,
+                );
+                Ok(StorageData::Aggregates(groups))
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                // The first language of the query or of its fallbacks the context is in
+                for lang in lang_candidates(&qry, &self.supported_lang) {
+                    let key = self.content_key(slug, name, lang.as_ref(), "toml");
+                    let data = match self.read(&key).await {
+                        Ok((_, data)) => data,
+                        Err(e) if e.is_not_found() => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let ctxt: toml::Value = toml::from_str(&data)
+                        .map_err(|e| S3StorageError::TomlDecode(format!("{key}: {e:?}")))?;
+                    return Ok(StorageData::Context(ctxt));
+                }
+                let key = self.content_key(slug, name, lang.as_ref(), "toml");
+                Err(S3StorageError::DataNotFound(key.to_string()))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                let prefix = object_key(&[&self.template_prefix]);
+                let mut templates = HashMap::new();
+                for obj in self.list(&prefix).await? {
+                    let Some(name) = obj.location.prefix_match(&prefix) else {
+                        continue;
+                    };
+                    let name = name.map(|p| p.as_ref().to_string()).collect::<Vec<_>>();
+                    let (_, content) = self.read(&obj.location).await?;
+                    templates.insert(name.join("/"), content);
+                }
+                if templates.is_empty() {
+                    return Err(S3StorageError::DataNotFound(prefix.to_string()));
+                }
+                Ok(StorageData::Templates(templates))
+            }
+
+            StorageQueryMethod::StaticFile(ref f) => self.load_static_file(f).await,
+
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                self.load_page_asset(&qry, name, asset).await
+            }
+        }
+    }
+
+    // Languages a page is written in, from a listing of its storage slug
+    async fn page_translations(
+        &self,
+        slug: &str,
+        name: &str,
+    ) -> Result<Vec<String>, S3StorageError> {
+        let objects = self.list(&object_key(&[&self.data_prefix, slug])).await?;
+        Ok(self
+            .supported_lang
+            .iter()
+            .filter(|lang| {
+                self.page_keys(slug, name, Some(lang))
+                    .iter()
+                    .any(|(key, _)| objects.iter().any(|obj| obj.location == *key))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn load_page_asset(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+        asset: &str,
+    ) -> Result<StorageData, S3StorageError> {
+        let slug = &qry.storage_slug;
+        let fpath = normalize_relative_path(asset).map_err(|e| {
+            log::error!("Possible directory traversal attack spotted");
+            log::error!("Got a request for asset {asset:?} of page {name:?}: {e:?}");
+            S3StorageError::AttackSuspected(
+                "s3-storage::page-asset::directory-traversal".to_string(),
+            )
+        })?;
+        let asset_key = |lang: Option<&String>| match lang {
+            Some(lang) => object_key(&[&self.data_prefix, slug, lang, name, &fpath]),
+            None => object_key(&[&self.data_prefix, slug, name, &fpath]),
+        };
+        if is_bundle_index(&fpath) {
+            let key = asset_key(self.select_lang(qry).as_ref());
+            return Err(S3StorageError::DataNotFound(key.to_string()));
+        }
+        // Assets are stored along with the page, in the language it is found in
+        let mut page = None;
+        'langs: for lang in lang_candidates(qry, &self.supported_lang) {
+            for (page_key, bundle) in self.page_keys(slug, name, lang.as_ref()) {
+                match self.store()?.head(&page_key).await {
+                    Ok(meta) => {
+                        page = Some((meta, bundle, lang));
+                        break 'langs;
+                    }
+                    Err(object_store::Error::NotFound { .. }) => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        // Assets are served along with their page only
+        let Some((meta, true, lang)) = page else {
+            let key = asset_key(self.select_lang(qry).as_ref());
+            return Err(S3StorageError::DataNotFound(key.to_string()));
+        };
+        let key = asset_key(lang.as_ref());
+        if !self
+            .page_metadata(&meta)
+            .await?
+            .is_visible(timestamp_now(), qry.drafts)
+        {
+            return Err(S3StorageError::DataNotFound(key.to_string()));
+        }
+        match self.store()?.get(&key).await {
+            Ok(res) => {
+                let stream = res.into_stream().map_err(std::io::Error::other);
+                Ok(StorageData::StaticFileStream(FileStream::new(Box::pin(
+                    stream,
+                ))))
+            }
+            Err(object_store::Error::NotFound { .. }) => {
+                Err(S3StorageError::DataNotFound(key.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_key(
+        &self,
+        wrt: &StorageWrite,
+        name: &str,
+        ext: &str,
+    ) -> Result<ObjectPath, S3StorageError> {
+        let name = self.write_name(name)?;
+        Ok(self.content_key(&wrt.storage_slug, &name, wrt.lang.as_ref(), ext))
+    }
+
+    // Keys have no directory to escape from, but we refuse what LocalStorage would refuse
+    fn write_name(&self, name: &str) -> Result<String, S3StorageError> {
+        normalize_relative_path(name).map_err(|e| {
+            log::error!("Refusing to write {name:?}: {e:?}");
+            S3StorageError::AttackSuspected("s3-storage::write::directory-traversal".to_string())
+        })
+    }
+
+    fn assets_prefix(&self) -> Result<&String, S3StorageError> {
+        self.include_assets
+            .first()
+            .ok_or(S3StorageError::NoAssetsPrefix)
+    }
+
+    async fn put(&self, key: &ObjectPath, data: Vec<u8>) -> Result<(), S3StorageError> {
+        self.store()?.put(key, PutPayload::from(data)).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &ObjectPath) -> Result<(), S3StorageError> {
+        // Deleting a missing object isn't an error for S3
+        self.store()?.head(key).await?;
+        self.store()?.delete(key).await?;
+        Ok(())
+    }
+
+    pub async fn write_data(&self, wrt: StorageWrite) -> Result<(), S3StorageError> {
+        match wrt.method {
+            StorageWriteMethod::SavePage {
+                ref name,
+                ref metadata,
+                ref body,
+            } => {
+                let key = self.write_key(&wrt, name, "md")?;
+                let content = format_page_content(metadata, body)
+                    .map_err(|e| S3StorageError::TomlEncode(format!("{key}: {e}")))?;
+                self.put(&key, content.into_bytes()).await
+            }
+            StorageWriteMethod::DeletePage(ref name) => {
+                self.delete(&self.write_key(&wrt, name, "md")?).await
+            }
+
+            StorageWriteMethod::SaveContext(ref name, ref data) => {
+                let key = self.write_key(&wrt, name, "toml")?;
+                let data = toml::to_string(data)
+                    .map_err(|e| S3StorageError::TomlEncode(format!("{key}: {e:?}")))?;
+                self.put(&key, data.into_bytes()).await
+            }
+            StorageWriteMethod::DeleteContext(ref name) => {
+                self.delete(&self.write_key(&wrt, name, "toml")?).await
+            }
+
+            StorageWriteMethod::SaveStaticFile(ref fname, ref data) => {
+                let key = object_key(&[self.assets_prefix()?, &self.write_name(fname)?]);
+                self.put(&key, data.clone()).await
+            }
+            StorageWriteMethod::DeleteStaticFile(ref fname) => {
+                let key = object_key(&[self.assets_prefix()?, &self.write_name(fname)?]);
+                self.delete(&key).await
+            }
+        }
+    }
+
+    pub fn select_lang(&self, qry: &StorageQuery) -> Option<String> {
+        let lang = qry.lang_pref.as_ref()?;
+        lang.iter()
+            .find_map(|l| supported_lang_of(l, &self.supported_lang))
+            .cloned()
+    }
+}
+
+impl StorageBackend for S3Storage {
+    type Error = S3StorageError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        self.connect()?;
+        log::debug!("Initialized S3 storage on bucket {}", self.bucket);
+        log::debug!("Supported langs: {:?}", self.supported_lang);
+        Ok(())
+    }
+
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        let Some((source, answered)) = self.answered.read().get(qry).cloned() else {
+            return true;
+        };
+        match self.version(&source).await {
+            Ok(current) => current != answered,
+            Err(e) => {
+                log::error!("{e:?}");
+                true
+            }
+        }
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        // Versions are read before the data, so a change during the query is caught
+        let source = self.source(&qry);
+        match self.version(&source).await {
+            Ok(version) => {
+                self.answered.write().insert(qry.clone(), (source, version));
+            }
+            Err(e) => log::warn!("Unable to get the version of {source:?}: {e:?}"),
+        }
+
+        match self.dispatch(qry).await {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{e:?}");
+                StorageData::Error(e.into())
+            }
+        }
+    }
+
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        self.write_data(wrt)
+            .await
+            .inspect_err(|e| log::error!("{e:?}"))
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        self.answered.write().remove(qry);
+    }
+
+    // Taken from the last index of the slug without listing it again, a newer listing
+    // being detected by has_changed
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        let slug = &qry.storage_slug;
+        if !self.indexes.read().contains_key(slug) {
+            self.pages_index(slug).await.ok()?;
+        }
+        self.indexes.read().get(slug)?.2.next(now)
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.supported_lang.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::Backend;
+    use crate::storage::QueryListOptions;
+
+    // Runs against an S3-compatible service like MinIO, only when its endpoint is given in
+    // ECOWEB_TEST_S3_ENDPOINT, the bucket in ECOWEB_TEST_S3_BUCKET (ecoweb by default)
+    // and the credentials in the AWS_* variables
+    #[actix_web::test]
+    async fn serves_a_bucket() {
+        let Ok(endpoint) = std::env::var("ECOWEB_TEST_S3_ENDPOINT") else {
+            return;
+        };
+        let bucket = std::env::var("ECOWEB_TEST_S3_BUCKET").unwrap_or("ecoweb".to_string());
+        let prefix = format!("ecoweb-test-{:x}", rand::random::<u64>());
+        let mut storage: S3Storage = toml::from_str(&format!(
+            "bucket = {bucket:?}\nendpoint = {endpoint:?}\n\
+            data_prefix = \"{prefix}/data\"\ntemplate_prefix = \"{prefix}/templates\""
+        ))
+        .unwrap();
+        storage
+            .init(&Config::test(
+                std::path::Path::new("."),
+                Backend::S3(storage.clone()),
+            ))
+            .unwrap();
+        let put = |key: &str, data: &str| {
+            let key = object_key(&[&prefix, key]);
+            let storage = &storage;
+            let data = data.as_bytes().to_vec();
+            async move { storage.put(&key, data).await.unwrap() }
+        };
+        put("templates/page.html", "{{ page_content }}").await;
+        put(
+            "data/blog/hello.md",
+            "+++\n[metadata]\ntitle = \"Hello\"\n+++\nHi",
+        )
+        .await;
+
+        let slug = "blog".to_string();
+        let qry = StorageQueryMethod::ContentFromName("hello".to_string()).build_query(&slug);
+        let (_, metadata, body) = storage.query(qry.clone()).await.page_content().unwrap();
+        assert_eq!(
+            (metadata.metadata["title"].as_str(), body.as_str()),
+            (Some("Hello"), "Hi")
+        );
+        let templates = storage.query(StorageQuery::templates()).await;
+        assert!(templates
+            .base_templates()
+            .unwrap()
+            .contains_key("page.html"));
+
+        let listing = StorageQuery::recent_pages(&slug, &QueryListOptions::default());
+        let (_, total) = storage.query(listing.clone()).await.recent_pages().unwrap();
+        assert_eq!(total, 1);
+        assert!(!storage.has_changed(&qry).await);
+        assert!(!storage.has_changed(&listing).await);
+
+        put("data/blog/other.md", "Other").await;
+        assert!(!storage.has_changed(&qry).await);
+        assert!(storage.has_changed(&listing).await);
+        put("data/blog/hello.md", "Hello again").await;
+        assert!(storage.has_changed(&qry).await);
+
+        let objects = storage.list(&object_key(&[&prefix])).await.unwrap();
+        for obj in objects {
+            storage.delete(&obj.location).await.unwrap();
+        }
+    }
+}
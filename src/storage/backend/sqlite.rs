@@ -0,0 +1,1049 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::page::{
+    compare_similar_md, page_id_path, timestamp_now, PageId, PageMetadata, Visibility,
+};
+use crate::storage::query::StorageQueryMethod;
+use crate::storage::write::StorageWriteMethod;
+use crate::storage::{StorageData, StorageQuery, StorageWrite};
+
+use super::{
+    lang_candidates, normalize_relative_path, supported_lang_of, RelativePathError, StorageBackend,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pages (
+    storage TEXT NOT NULL,
+    lang TEXT NOT NULL DEFAULT '',
+    name TEXT NOT NULL,
+    id INTEGER NOT NULL DEFAULT 0,
+    hidden INTEGER NOT NULL DEFAULT 0,
+    metadata TEXT NOT NULL DEFAULT '{}',
+    body TEXT NOT NULL DEFAULT '',
+    visibility TEXT,
+    publish_date INTEGER,
+    expire_date INTEGER,
+    PRIMARY KEY (storage, lang, name)
+);
+CREATE INDEX IF NOT EXISTS pages_by_id ON pages (storage, id);
+
+CREATE TABLE IF NOT EXISTS contexts (
+    storage TEXT NOT NULL,
+    lang TEXT NOT NULL DEFAULT '',
+    name TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (storage, lang, name)
+);
+
+CREATE TABLE IF NOT EXISTS templates (
+    name TEXT NOT NULL PRIMARY KEY,
+    content TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS static_files (
+    path TEXT NOT NULL PRIMARY KEY,
+    data BLOB NOT NULL
+);
+";
+
+// Created once the columns of the pages are migrated
+const INDEXES: &str = "
+DROP INDEX IF EXISTS pages_listed;
+CREATE INDEX IF NOT EXISTS pages_publish ON pages (storage, publish_date);
+CREATE INDEX IF NOT EXISTS pages_expire ON pages (storage, expire_date);
+";
+
+// Pages of the slug ?1 shown in the listings, drafts included if ?2, at the time ?3
+const LISTED: &str = "storage = ?1
+    AND (visibility = 'public' OR (visibility = 'draft' AND ?2))
+    AND (publish_date IS NULL OR publish_date <= ?3)
+    AND (expire_date IS NULL OR ?3 < expire_date)";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum SqliteStorageError {
+    OpenDatabase(String),
+    InitSchema(String),
+    NotInitialized,
+
+    Sql(String),
+    DataNotFound(String),
+
+    NoMatch(String),
+    TooManyMatches(usize, usize),
+
+    JsonDecode(String),
+    TomlDecode(String),
+
+    BadRequest(String),
+    AttackSuspected(String),
+
+    JsonEncode(String),
+    TomlEncode(String),
+}
+
+impl From<SqliteStorageError> for HttpResponseBuilder {
+    fn from(val: SqliteStorageError) -> Self {
+        match val {
+            SqliteStorageError::DataNotFound(_) | SqliteStorageError::NoMatch(_) => {
+                HttpResponse::NotFound()
+            }
+            _ => HttpResponse::InternalServerError(),
+        }
+    }
+}
+
+impl SqliteStorageError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            SqliteStorageError::DataNotFound(_) | SqliteStorageError::NoMatch(_)
+        )
+    }
+}
+
+impl From<rusqlite::Error> for SqliteStorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        SqliteStorageError::Sql(format!("{value:?}"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqliteStorage {
+    #[serde(skip)]
+    conn: Arc<Mutex<Option<Connection>>>,
+
+    // Value of "PRAGMA data_version" when each query was last answered
+    #[serde(skip)]
+    versions: Arc<Mutex<HashMap<StorageQuery, i64>>>,
+
+    database: PathBuf,
+    #[serde(default)]
+    supported_lang: Vec<String>,
+    #[serde(default)]
+    default_sort: (Vec<String>, bool),
+}
+
+impl SqliteStorage {
+    pub fn open(&mut self, config: &Config) -> Result<(), SqliteStorageError> {
+        self.database = config.root.join(&self.database);
+        let conn = Connection::open_with_flags(
+            &self.database,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|e| SqliteStorageError::OpenDatabase(format!("{:?}: {e:?}", self.database)))?;
+        migrate_schema(&conn, &self.default_sort.0)
+            .map_err(|e| SqliteStorageError::InitSchema(format!("{e:?}")))?;
+        *self.conn.lock() = Some(conn);
+        self.assign_missing_ids()?;
+        self.fill_listing_columns()?;
+        self.check_id_collisions()?;
+        Ok(())
+    }
+
+    fn with_conn<F, R>(&self, f: F) -> Result<R, SqliteStorageError>
+    where
+        F: FnOnce(&Connection) -> Result<R, SqliteStorageError>,
+    {
+        let conn = self.conn.lock();
+        let Some(ref conn) = *conn else {
+            return Err(SqliteStorageError::NotInitialized);
+        };
+        f(conn)
+    }
+
+    // Pages inserted without an explicit id get one derived from their location,
+    // the same way LocalStorage derives it from the file path
+    fn assign_missing_ids(&self) -> Result<(), SqliteStorageError> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT storage, lang, name, metadata FROM pages WHERE id = 0")?;
+            let missing = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for (storage, lang, name, metadata) in missing {
+                let mut md = decode_metadata(&metadata)?;
+                let page_lang = Some(lang.as_str()).filter(|l| !l.is_empty());
+                md.update_id(&page_id_path(&storage, page_lang, &name));
+                conn.execute(
+                    "UPDATE pages SET id = ?1 WHERE storage = ?2 AND lang = ?3 AND name = ?4",
+                    params![md.id.value() as i64, storage, lang, name],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    // Pages inserted without their listing columns, or by earlier versions, get them
+    // from their metadata
+    fn fill_listing_columns(&self) -> Result<(), SqliteStorageError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT storage, lang, name, hidden, metadata FROM pages WHERE visibility IS NULL",
+            )?;
+            let missing = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, bool>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for (storage, lang, name, hidden, metadata) in missing {
+                let mut md = decode_metadata(&metadata)?;
+                md.hidden = hidden;
+                conn.execute(
+                    "UPDATE pages SET visibility = ?1, publish_date = ?2, expire_date = ?3
+                    WHERE storage = ?4 AND lang = ?5 AND name = ?6",
+                    params![
+                        visibility_column(&md),
+                        md.publish_date,
+                        md.expire_date,
+                        storage,
+                        lang,
+                        name
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    // Pages sharing an id can't be told apart from it
+    fn check_id_collisions(&self) -> Result<(), SqliteStorageError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT storage, id, group_concat(lang || '/' || name, ', ') FROM pages
+                GROUP BY storage, id HAVING COUNT(*) > 1",
+            )?;
+            let collisions = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (storage, id, pages) in collisions {
+                log::error!("Pages {pages} of {storage} have the same id {}", id as u64);
+            }
+            Ok(())
+        })
+    }
+
+    fn data_version(&self) -> Result<i64, SqliteStorageError> {
+        self.with_conn(|conn| Ok(conn.query_row("PRAGMA data_version", [], |row| row.get(0))?))
+    }
+
+    // Languages a page is stored in, the root of its storage being the empty one
+    fn page_langs(&self, slug: &str, name: &str) -> Result<Vec<String>, SqliteStorageError> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare_cached("SELECT lang FROM pages WHERE storage = ?1 AND name = ?2")?;
+            let rows = stmt
+                .query_map(params![slug, name], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+    }
+
+    // Page in the first language of the query it is stored in
+    fn load_translated_page(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+    ) -> Result<StorageData, SqliteStorageError> {
+        let langs = self.page_langs(&qry.storage_slug, name)?;
+        let lang = lang_candidates(qry, &self.supported_lang)
+            .into_iter()
+            .find(|lang| langs.contains(&lang.clone().unwrap_or_default()))
+            .unwrap_or_else(|| self.select_lang(qry));
+        self.load_page(qry, name, lang)
+    }
+
+    fn load_page(
+        &self,
+        qry: &StorageQuery,
+        name: &str,
+        lang: Option<String>,
+    ) -> Result<StorageData, SqliteStorageError> {
+        let lang_col = lang.clone().unwrap_or_default();
+        let row = self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT id, hidden, metadata, body FROM pages
+                    WHERE storage = ?1 AND lang = ?2 AND name = ?3",
+                    params![qry.storage_slug, lang_col, name],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, bool>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                        ))
+                    },
+                )
+                .optional()?)
+        })?;
+        let not_found =
+            || SqliteStorageError::DataNotFound(format!("{}/{lang_col}/{name}", qry.storage_slug));
+        let Some((id, hidden, metadata, body)) = row else {
+            return Err(not_found());
+        };
+        let mut metadata = decode_metadata(&metadata)?;
+        // The id column is the one looked up, the metadata may hold its written form
+        if metadata.id != id as u64 {
+            metadata.id = PageId::from(id as u64);
+        }
+        metadata.hidden = hidden;
+        metadata.lang = lang.clone();
+        if !metadata.is_visible(timestamp_now(), qry.drafts) {
+            return Err(not_found());
+        }
+        let langs = self.page_langs(&qry.storage_slug, name)?;
+        metadata.translations = self
+            .supported_lang
+            .iter()
+            .filter(|lang| langs.contains(lang))
+            .cloned()
+            .collect();
+        Ok(StorageData::PageContent {
+            metadata,
+            body,
+            lang,
+        })
+    }
+
+    // Pages of a slug shown in the listings, in the order of the sort keys if given, with their
+    // count. Those are sorted and paginated by the database, unless pages are left out once
+    // decoded by the filter of the query or by `keep`, or some hold arrays or objects in the
+    // sort keys, which `compare_md` orders its own way.
+    fn listed_pages(
+        &self,
+        qry: &StorageQuery,
+        sort: Option<(&[String], bool)>,
+        keep: Option<&dyn Fn(&PageMetadata) -> bool>,
+    ) -> Result<(Vec<PageMetadata>, usize), SqliteStorageError> {
+        let (slug, drafts, now) = (&qry.storage_slug, qry.drafts, timestamp_now());
+        let composite = match sort {
+            Some((keys, _)) if !keys.is_empty() => {
+                let (rank, _) = sort_exprs(keys)?;
+                self.with_conn(|conn| {
+                    let sql = format!(
+                        "SELECT EXISTS (SELECT 1 FROM pages WHERE {LISTED} AND {rank} > 4)"
+                    );
+                    let mut stmt = conn.prepare_cached(&sql)?;
+                    Ok(stmt.query_row(params![slug, drafts, now], |row| row.get::<_, bool>(0))?)
+                })?
+            }
+            _ => false,
+        };
+        let order = match sort {
+            // Ties keep the order of the names once sorted in place
+            Some(_) if composite => order_by(&[], false)?,
+            Some((keys, rev)) => order_by(keys, rev)?,
+            None => String::new(),
+        };
+        let sql = format!("SELECT id, hidden, metadata, lang FROM pages WHERE {LISTED}{order}");
+        if sort.is_some() && !composite && qry.filter.is_none() && keep.is_none() {
+            let limit = match qry.limit {
+                0 => -1,
+                limit => i64::try_from(limit).unwrap_or(-1),
+            };
+            let offset = i64::try_from(qry.offset).unwrap_or(i64::MAX);
+            let pages = self.pages_metadata(
+                &format!("{sql} LIMIT ?4 OFFSET ?5"),
+                params![slug, drafts, now, limit, offset],
+            )?;
+            let total = self.with_conn(|conn| {
+                let mut stmt =
+                    conn.prepare_cached(&format!("SELECT COUNT(*) FROM pages WHERE {LISTED}"))?;
+                Ok(stmt.query_row(params![slug, drafts, now], |row| row.get::<_, i64>(0))?)
+            })?;
+            return Ok((pages, total as usize));
+        }
+
+        let mut pages = self.pages_metadata(&sql, params![slug, drafts, now])?;
+        pages.retain(|m| qry.matches_filter(m) && keep.is_none_or(|keep| keep(m)));
+        if let (true, Some((keys, rev))) = (composite, sort) {
+            pages.sort_by(|a, b| a.compare_md(keys, b));
+            if rev {
+                pages.reverse();
+            }
+        }
+        let total = pages.len();
+        if sort.is_some() {
+            pages.drain(..qry.offset.min(total));
+            if qry.limit > 0 {
+                pages.truncate(qry.limit);
+            }
+        }
+        Ok((pages, total))
+    }
+
+    fn pages_metadata(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<PageMetadata>, SqliteStorageError> {
+        let rows = self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(sql)?;
+            let rows = stmt
+                .query_map(params, |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, bool>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })?;
+
+        rows.into_iter()
+            .map(|(id, hidden, md, lang)| {
+                let mut md = decode_metadata(&md)?;
+                md.lang = Some(lang).filter(|l| !l.is_empty());
+                if md.id != id as u64 {
+                    md.id = PageId::from(id as u64);
+                }
+                md.hidden = hidden;
+                Ok(md)
+            })
+            .collect()
+    }
+
+    pub fn dispatch(&self, qry: StorageQuery) -> Result<StorageData, SqliteStorageError> {
+        let (sort_key, rev) = if let Some((ref sort_key, rev)) = qry.sort_by {
+            (sort_key, rev)
+        } else {
+            (&self.default_sort.0, self.default_sort.1)
+        };
+        let lang = self.select_lang(&qry);
+
+        match qry.method {
+            StorageQueryMethod::NoOp => {
+                log::debug!("Sqlite storage No Op");
+                Ok(StorageData::Nothing)
+            }
+
+            StorageQueryMethod::ContentFromName(ref name) => self.load_translated_page(&qry, name),
+            StorageQueryMethod::ContentSlug(ref name) => self.load_translated_page(&qry, name),
+
+            StorageQueryMethod::ContentNumId(id) => {
+                let matches = self.with_conn(|conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT lang, name FROM pages WHERE storage = ?1 AND id = ?2",
+                    )?;
+                    let rows = stmt
+                        .query_map(params![qry.storage_slug, id as i64], |row| {
+                            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(rows)
+                })?;
+                let mut matches = matches.into_iter();
+                let Some((page_lang, name)) = matches.next() else {
+                    return Err(SqliteStorageError::NoMatch(format!("id = {id}")));
+                };
+                let other_matches = matches.count();
+                if other_matches > 0 {
+                    return Err(SqliteStorageError::TooManyMatches(other_matches, 1));
+                }
+                let page_lang = Some(page_lang).filter(|l| !l.is_empty());
+                self.load_page(&qry, &name, page_lang)
+            }
+
+            StorageQueryMethod::RecentPages => {
+                let (pages, total) = self.listed_pages(&qry, Some((sort_key, rev)), None)?;
+                Ok(StorageData::RecentPages(pages, total))
+            }
+
+            StorageQueryMethod::GetSimilarPages((ref keys, ref val)) => {
+                let similar = |m: &PageMetadata| match (m.get_metadata(keys), val.as_ref()) {
+                    (Some(md), Some(val)) => compare_similar_md(md, val),
+                    (Some(_), None) | (None, Some(_)) => false,
+                    (None, None) => true,
+                };
+                let (pages, total) =
+                    self.listed_pages(&qry, Some((sort_key, rev)), Some(&similar))?;
+                Ok(StorageData::SimilarPages(pages, total))
+            }
+
+            StorageQueryMethod::QueryMetadata((ref keys, ref val), ref query) => {
+                let matches = |m: &PageMetadata| m.get_metadata(keys) == val.as_ref();
+                let (pages, _) = self.listed_pages(&qry, None, Some(&matches))?;
+                let values = pages
+                    .iter()
+                    .filter_map(|m| m.get_metadata(query).cloned())
+                    .collect::<Vec<serde_json::Value>>();
+                Ok(StorageData::QueryMetadata(values))
+            }
+
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                let (pages, _) = self.listed_pages(&qry, None, None)?;
+                Ok(StorageData::Aggregates(agg.apply(pages.iter(), sort_key)))
+            }
+
+            StorageQueryMethod::QueryContext(ref name) => {
+                // The first language of the query or of its fallbacks the context is in
+                let langs = lang_candidates(&qry, &self.supported_lang);
+                let data = self.with_conn(|conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT data FROM contexts WHERE storage = ?1 AND lang = ?2 AND name = ?3",
+                    )?;
+                    for lang in langs.iter() {
+                        let lang_col = lang.as_deref().unwrap_or_default();
+                        let data = stmt
+                            .query_row(params![qry.storage_slug, lang_col, name], |row| {
+                                row.get::<_, String>(0)
+                            })
+                            .optional()?;
+                        if data.is_some() {
+                            return Ok(data);
+                        }
+                    }
+                    Ok(None)
+                })?;
+                let Some(data) = data else {
+                    let lang_col = lang.unwrap_or_default();
+                    return Err(SqliteStorageError::DataNotFound(format!(
+                        "context {}/{lang_col}/{name}",
+                        qry.storage_slug
+                    )));
+                };
+                let ctxt: toml::Value = toml::from_str(&data)
+                    .map_err(|e| SqliteStorageError::TomlDecode(format!("{name}: {e:?}")))?;
+                Ok(StorageData::Context(ctxt))
+            }
+
+            StorageQueryMethod::QueryTemplates => {
+                let templates = self.with_conn(|conn| {
+                    let mut stmt = conn.prepare("SELECT name, content FROM templates")?;
+                    let rows = stmt
+                        .query_map([], |row| {
+                            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                        })?
+                        .collect::<Result<HashMap<String, String>, _>>()?;
+                    Ok(rows)
+                })?;
+                Ok(StorageData::Templates(templates))
+            }
+
+            StorageQueryMethod::StaticFile(ref f) => {
+                let fpath = normalize_static_path(f)?;
+                let data = self.with_conn(|conn| {
+                    Ok(conn
+                        .query_row(
+                            "SELECT data FROM static_files WHERE path = ?1",
+                            params![fpath],
+                            |row| row.get::<_, Vec<u8>>(0),
+                        )
+                        .optional()?)
+                })?;
+                let Some(data) = data else {
+                    return Err(SqliteStorageError::DataNotFound(fpath));
+                };
+                Ok(StorageData::StaticFileData(data))
+            }
+
+            // Ids are stored along with the pages, so they never changed
+            StorageQueryMethod::ContentLegacyId(id) => {
+                Err(SqliteStorageError::NoMatch(format!("legacy id = {id}")))
+            }
+
+            // Pages stored in the database are never bundles
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                Err(SqliteStorageError::DataNotFound(format!("{name}/{asset}")))
+            }
+        }
+    }
+
+    fn execute_write(
+        &self,
+        sql: &str,
+        what: String,
+        params: impl rusqlite::Params,
+    ) -> Result<(), SqliteStorageError> {
+        let changed = self.with_conn(|conn| Ok(conn.execute(sql, params)?))?;
+        if changed == 0 {
+            return Err(SqliteStorageError::DataNotFound(what));
+        }
+        Ok(())
+    }
+
+    pub fn write_data(&self, wrt: StorageWrite) -> Result<(), SqliteStorageError> {
+        let slug = &wrt.storage_slug;
+        let lang = wrt.lang.clone().unwrap_or_default();
+        match wrt.method {
+            StorageWriteMethod::SavePage {
+                ref name,
+                ref metadata,
+                ref body,
+            } => {
+                let mut metadata = metadata.clone();
+                metadata.update_id(&page_id_path(slug, wrt.lang.as_deref(), name));
+                let encoded = serde_json::to_string(&metadata)
+                    .map_err(|e| SqliteStorageError::JsonEncode(format!("{e:?}")))?;
+                self.execute_write(
+                    "INSERT INTO pages (storage, lang, name, id, hidden, metadata, body,
+                        visibility, publish_date, expire_date)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                    ON CONFLICT (storage, lang, name) DO UPDATE SET
+                        id = excluded.id,
+                        hidden = excluded.hidden,
+                        metadata = excluded.metadata,
+                        body = excluded.body,
+                        visibility = excluded.visibility,
+                        publish_date = excluded.publish_date,
+                        expire_date = excluded.expire_date",
+                    format!("{slug}/{lang}/{name}"),
+                    params![
+                        slug,
+                        lang,
+                        name,
+                        metadata.id.value() as i64,
+                        metadata.hidden,
+                        encoded,
+                        body,
+                        visibility_column(&metadata),
+                        metadata.publish_date,
+                        metadata.expire_date
+                    ],
+                )?;
+                self.check_id_collisions()
+            }
+            StorageWriteMethod::DeletePage(ref name) => self.execute_write(
+                "DELETE FROM pages WHERE storage = ?1 AND lang = ?2 AND name = ?3",
+                format!("{slug}/{lang}/{name}"),
+                params![slug, lang, name],
+            ),
+
+            StorageWriteMethod::SaveContext(ref name, ref data) => {
+                let data = toml::to_string(data)
+                    .map_err(|e| SqliteStorageError::TomlEncode(format!("{name}: {e:?}")))?;
+                self.execute_write(
+                    "INSERT OR REPLACE INTO contexts (storage, lang, name, data)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    format!("context {slug}/{lang}/{name}"),
+                    params![slug, lang, name, data],
+                )
+            }
+            StorageWriteMethod::DeleteContext(ref name) => self.execute_write(
+                "DELETE FROM contexts WHERE storage = ?1 AND lang = ?2 AND name = ?3",
+                format!("context {slug}/{lang}/{name}"),
+                params![slug, lang, name],
+            ),
+
+            StorageWriteMethod::SaveStaticFile(ref fname, ref data) => {
+                let fpath = normalize_static_path(fname)?;
+                self.execute_write(
+                    "INSERT OR REPLACE INTO static_files (path, data) VALUES (?1, ?2)",
+                    fpath.clone(),
+                    params![fpath, data],
+                )
+            }
+            StorageWriteMethod::DeleteStaticFile(ref fname) => {
+                let fpath = normalize_static_path(fname)?;
+                self.execute_write(
+                    "DELETE FROM static_files WHERE path = ?1",
+                    fpath.clone(),
+                    params![fpath],
+                )
+            }
+        }
+    }
+
+    pub fn select_lang(&self, qry: &StorageQuery) -> Option<String> {
+        let lang = qry.lang_pref.as_ref()?;
+        lang.iter()
+            .find_map(|l| supported_lang_of(l, &self.supported_lang))
+            .cloned()
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    type Error = SqliteStorageError;
+
+    fn init(&mut self, config: &Config) -> Result<(), Self::Error> {
+        self.open(config)?;
+        log::debug!("Initialized sqlite storage from {:?}", self.database);
+        log::debug!("Supported langs: {:?}", self.supported_lang);
+        Ok(())
+    }
+
+    async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        let Some(answered) = self.versions.lock().get(qry).copied() else {
+            return true;
+        };
+        match self.data_version() {
+            Ok(current) => current != answered,
+            Err(e) => {
+                log::error!("{e:?}");
+                true
+            }
+        }
+    }
+
+    async fn query(&self, qry: StorageQuery) -> StorageData {
+        // Errors are cached as well, so the version is recorded in any case
+        if let Ok(version) = self.data_version() {
+            self.versions.lock().insert(qry.clone(), version);
+        }
+        match self.dispatch(qry) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("{e:?}");
+                StorageData::Error(e.into())
+            }
+        }
+    }
+
+    async fn write(&self, wrt: StorageWrite) -> Result<(), Self::Error> {
+        self.write_data(wrt).inspect_err(|e| log::error!("{e:?}"))
+    }
+
+    fn forget(&self, qry: &StorageQuery) {
+        self.versions.lock().remove(qry);
+    }
+
+    async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT MIN(date) FROM (
+                    SELECT MIN(publish_date) AS date FROM pages
+                    WHERE storage = ?1 AND publish_date > ?2
+                    UNION ALL
+                    SELECT MIN(expire_date) FROM pages
+                    WHERE storage = ?1 AND expire_date > ?2
+                )",
+            )?;
+            Ok(stmt.query_row(params![qry.storage_slug, now], |row| {
+                row.get::<_, Option<i64>>(0)
+            })?)
+        })
+        .inspect_err(|e| log::error!("{e:?}"))
+        .ok()
+        .flatten()
+    }
+
+    fn supported_lang(&self) -> Vec<String> {
+        self.supported_lang.clone()
+    }
+}
+
+// Adds the listing columns to the pages of databases created by earlier versions, and indexes
+// the pages in the default order of the listings
+fn migrate_schema(conn: &Connection, default_sort: &[String]) -> Result<(), SqliteStorageError> {
+    conn.execute_batch(SCHEMA)?;
+    let columns = conn
+        .prepare("SELECT name FROM pragma_table_info('pages')")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if !columns.iter().any(|c| c == "visibility") {
+        conn.execute_batch(
+            "ALTER TABLE pages ADD COLUMN visibility TEXT;
+            ALTER TABLE pages ADD COLUMN publish_date INTEGER;
+            ALTER TABLE pages ADD COLUMN expire_date INTEGER;",
+        )?;
+    }
+    conn.execute_batch(INDEXES)?;
+
+    // Rebuilt only when the default sort keys changed
+    let sorted = match default_sort.is_empty() {
+        true => None,
+        false => {
+            let (rank, value) = sort_exprs(default_sort)?;
+            Some(format!(
+                "CREATE INDEX pages_sorted ON pages (storage, {rank}, {value}, name, lang)"
+            ))
+        }
+    };
+    let current = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'index' AND name = 'pages_sorted'",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    if current != sorted {
+        conn.execute_batch("DROP INDEX IF EXISTS pages_sorted")?;
+        if let Some(ref sql) = sorted {
+            conn.execute_batch(sql)?;
+        }
+    }
+    Ok(())
+}
+
+// Visibility of a page stored along with it, to be filtered by the listings
+fn visibility_column(md: &PageMetadata) -> &'static str {
+    match md.visibility() {
+        Visibility::Public => "public",
+        Visibility::Unlisted => "unlisted",
+        Visibility::Draft => "draft",
+        Visibility::Private => "private",
+    }
+}
+
+// Pages are listed from the greatest value of the sort keys to the lowest, as `compare_md`
+// does, the pages without it last
+fn order_by(keys: &[String], rev: bool) -> Result<String, SqliteStorageError> {
+    let dir = if rev { "ASC" } else { "DESC" };
+    if keys.is_empty() {
+        return Ok(format!(" ORDER BY name {dir}, lang {dir}"));
+    }
+    let (rank, value) = sort_exprs(keys)?;
+    Ok(format!(
+        " ORDER BY {rank} {dir}, {value} {dir}, name {dir}, lang {dir}"
+    ))
+}
+
+// Rank of the type of nested metadata keys, as `value_type_rank` gives it from 1 and 0 when
+// missing, then their value, written out so the index of the default sort can be used.
+// Values of the same scalar type compare in SQLite as `compare_tera_values` does.
+fn sort_exprs(keys: &[String]) -> Result<(String, String), SqliteStorageError> {
+    if keys.iter().any(|k| k.contains('"')) {
+        return Err(SqliteStorageError::BadRequest(format!(
+            "sort keys {keys:?}"
+        )));
+    }
+    let path = keys
+        .iter()
+        .map(|k| format!(".\"{}\"", k.replace('\'', "''")))
+        .collect::<String>();
+    let path = format!("'$.metadata{path}'");
+    let rank = format!(
+        "(CASE json_type(metadata, {path}) WHEN 'null' THEN 1 WHEN 'true' THEN 2 \
+        WHEN 'false' THEN 2 WHEN 'integer' THEN 3 WHEN 'real' THEN 3 WHEN 'text' THEN 4 \
+        WHEN 'array' THEN 5 WHEN 'object' THEN 6 ELSE 0 END)"
+    );
+    Ok((rank, format!("json_extract(metadata, {path})")))
+}
+
+fn decode_metadata(data: &str) -> Result<PageMetadata, SqliteStorageError> {
+    serde_json::from_str(data).map_err(|e| SqliteStorageError::JsonDecode(format!("{e:?}")))
+}
+
+// Static files are keyed by their relative path, there is no directory to escape
+// from but we refuse the same requests LocalStorage would consider as an attack
+fn normalize_static_path(f: &str) -> Result<String, SqliteStorageError> {
+    normalize_relative_path(f).map_err(|e| match e {
+        RelativePathError::NoFileName => SqliteStorageError::BadRequest("no file name".to_string()),
+        RelativePathError::IllegalName => {
+            SqliteStorageError::BadRequest("illegal characters in file name".to_string())
+        }
+        RelativePathError::Traversal => {
+            log::error!("Possible directory traversal attack spotted");
+            log::error!("Got a request for static file {f:?}");
+            SqliteStorageError::AttackSuspected(
+                "sqlite-storage::static-file::directory-traversal".to_string(),
+            )
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::Backend;
+
+    fn names(pages: &[PageMetadata]) -> Vec<&str> {
+        pages
+            .iter()
+            .map(|m| m.metadata["title"].as_str().unwrap())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn lists_pages_of_a_migrated_database() {
+        let root = std::env::temp_dir().join(format!("ecoweb-sqlite-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        let conn = Connection::open(root.join("site.db")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pages (
+                storage TEXT NOT NULL,
+                lang TEXT NOT NULL DEFAULT '',
+                name TEXT NOT NULL,
+                id INTEGER NOT NULL DEFAULT 0,
+                hidden INTEGER NOT NULL DEFAULT 0,
+                metadata TEXT NOT NULL DEFAULT '{}',
+                body TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (storage, lang, name)
+            );
+            CREATE INDEX pages_listed ON pages (storage, hidden);",
+        )
+        .unwrap();
+        let pages = [
+            ("a", 0, r#"{"metadata": {"title": "A", "date": 3}}"#),
+            ("b", 0, r#"{"metadata": {"title": "B", "date": 1}}"#),
+            (
+                "c",
+                0,
+                r#"{"visibility": "draft", "metadata": {"title": "C", "date": 2}}"#,
+            ),
+            ("d", 1, r#"{"metadata": {"title": "D", "date": 4}}"#),
+            (
+                "e",
+                0,
+                r#"{"publish_date": 4102444800, "metadata": {"title": "E"}}"#,
+            ),
+            ("f", 0, r#"{"metadata": {"title": "F"}}"#),
+        ];
+        for (name, hidden, metadata) in pages {
+            conn.execute(
+                "INSERT INTO pages (storage, name, hidden, metadata) VALUES ('blog', ?1, ?2, ?3)",
+                params![name, hidden, metadata],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let mut storage: SqliteStorage =
+            toml::from_str("database = \"site.db\"\ndefault_sort = [[\"date\"], false]").unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Sqlite(storage.clone())))
+            .unwrap();
+
+        let slug = "blog".to_string();
+        let listing = |opts: &str, drafts: bool| {
+            let mut qry = StorageQuery::recent_pages(&slug, &toml::from_str(opts).unwrap());
+            if drafts {
+                qry.show_drafts();
+            }
+            qry
+        };
+        let qry = listing("limit = 2", false);
+        let (pages, total) = storage.query(qry).await.recent_pages().unwrap();
+        assert_eq!((names(&pages), total), (vec!["A", "B"], 3));
+
+        let qry = listing("limit = 2\noffset = 2", false);
+        let (pages, _) = storage.query(qry).await.recent_pages().unwrap();
+        assert_eq!(names(&pages), ["F"]);
+
+        let qry = listing("limit = 2\nsort_by = [\"date\"]\nrev_sort = true", true);
+        let (pages, total) = storage.query(qry).await.recent_pages().unwrap();
+        assert_eq!((names(&pages), total), (vec!["F", "B"], 4));
+
+        let qry = listing("", false);
+        assert_eq!(storage.next_schedule(&qry, 0).await, Some(4102444800));
+
+        let metadata = PageMetadata {
+            publish_date: Some(10),
+            ..decode_metadata(r#"{"metadata": {"title": "G", "date": 5}}"#).unwrap()
+        };
+        let save = StorageWriteMethod::SavePage {
+            name: "g".to_string(),
+            metadata,
+            body: String::new(),
+        };
+        storage.write(save.build_write(&slug)).await.unwrap();
+        assert_eq!(storage.next_schedule(&qry, 0).await, Some(10));
+        let (pages, _) = storage.query(qry).await.recent_pages().unwrap();
+        assert_eq!(names(&pages), ["G", "A", "B", "F"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn sorts_mixed_types_as_other_backends() {
+        let root = std::env::temp_dir().join(format!("ecoweb-sqlite-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut storage: SqliteStorage =
+            toml::from_str("database = \"site.db\"\ndefault_sort = [[\"date\"], false]").unwrap();
+        storage
+            .init(&Config::test(&root, Backend::Sqlite(storage.clone())))
+            .unwrap();
+
+        let slug = "blog".to_string();
+        let pages = [
+            r#"{"metadata": {"title": "Int", "date": 3}}"#,
+            r#"{"metadata": {"title": "Real", "date": 2.5}}"#,
+            r#"{"metadata": {"title": "Half", "date": 0.5}}"#,
+            r#"{"metadata": {"title": "Text", "date": "1"}}"#,
+            r#"{"metadata": {"title": "True", "date": true}}"#,
+            r#"{"metadata": {"title": "False", "date": false}}"#,
+            r#"{"metadata": {"title": "Null", "date": null}}"#,
+            r#"{"metadata": {"title": "Missing"}}"#,
+        ];
+        let save = |name: &str, metadata: &str| {
+            StorageWriteMethod::SavePage {
+                name: name.to_string(),
+                metadata: decode_metadata(metadata).unwrap(),
+                body: String::new(),
+            }
+            .build_write(&slug)
+        };
+        for (n, metadata) in pages.iter().enumerate() {
+            storage.write(save(&n.to_string(), metadata)).await.unwrap();
+        }
+
+        // The order the backends sorting with `compare_md` give
+        let mut expected = pages
+            .iter()
+            .map(|md| decode_metadata(md).unwrap())
+            .collect::<Vec<PageMetadata>>();
+        expected.sort_by(|a, b| a.compare_md(&["date".to_string()], b));
+        let expected = names(&expected);
+        assert_eq!(
+            expected,
+            ["Text", "Int", "Real", "Half", "True", "False", "Null", "Missing"]
+        );
+
+        let listing =
+            |opts: &str| StorageQuery::recent_pages(&slug, &toml::from_str(opts).unwrap());
+        let (listed, _) = storage.query(listing("")).await.recent_pages().unwrap();
+        assert_eq!(names(&listed), expected);
+        let (listed, _) = storage
+            .query(listing("rev_sort = true\nsort_by = [\"date\"]"))
+            .await
+            .recent_pages()
+            .unwrap();
+        assert_eq!(
+            names(&listed),
+            expected.iter().rev().copied().collect::<Vec<_>>()
+        );
+
+        // Arrays are ordered by `compare_md` rather than by their JSON text
+        let composite = [
+            r#"{"metadata": {"title": "Nine", "date": [9]}}"#,
+            r#"{"metadata": {"title": "Ten", "date": [10]}}"#,
+        ];
+        for (n, metadata) in composite.iter().enumerate() {
+            storage
+                .write(save(&format!("c{n}"), metadata))
+                .await
+                .unwrap();
+        }
+        let (listed, total) = storage
+            .query(listing("limit = 3"))
+            .await
+            .recent_pages()
+            .unwrap();
+        assert_eq!((names(&listed), total), (vec!["Ten", "Nine", "Text"], 10));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
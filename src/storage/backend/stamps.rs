@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    // None if the file doesn't exist, so its creation is detected as well
+    pub fn of(path: &Path) -> Option<FileStamp> {
+        let md = std::fs::metadata(path).ok()?;
+        Some(FileStamp {
+            modified: md.modified().ok(),
+            len: md.len(),
+        })
+    }
+}
+
+/// State of a set of files at the time some data was loaded from them
+#[derive(Debug, Clone, Default)]
+pub struct FileStamps(Vec<(PathBuf, Option<FileStamp>)>);
+
+impl FileStamps {
+    pub fn new() -> FileStamps {
+        FileStamps::default()
+    }
+
+    pub fn add(&mut self, path: &Path) {
+        self.0.push((path.to_path_buf(), FileStamp::of(path)));
+    }
+
+    pub fn extend(&mut self, other: FileStamps) {
+        self.0.extend(other.0);
+    }
+
+    pub fn has_changed(&self) -> bool {
+        self.0
+            .iter()
+            .any(|(path, stamp)| &FileStamp::of(path) != stamp)
+    }
+
+    // Stamps every file and directory under the root, directories changing
+    // whenever an entry is added or removed from them
+    pub fn of_tree(root: &Path) -> FileStamps {
+        let mut stamps = FileStamps::new();
+        stamps.add_tree(root);
+        stamps
+    }
+
+    pub fn add_tree(&mut self, root: &Path) {
+        self.add(root);
+        let Ok(entries) = std::fs::read_dir(root) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.add_tree(&path);
+            } else {
+                self.add(&path);
+            }
+        }
+    }
+}
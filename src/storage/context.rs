@@ -4,12 +4,22 @@ use tera::Context;
 use crate::page::PageMetadata;
 use crate::{errors::Errcode, routes::RequestArgs};
 
+use super::aggregate::Aggregation;
+use super::filter::FilterExpr;
 use super::query::QueryListOptions;
 use super::{StorageData, StorageQuery};
 
 pub type MetadataQuery = Vec<String>;
 pub type MetadataFilter = (Vec<String>, Option<serde_json::Value>);
 
+/// Pages a metadata query reads, from the exact value of a key or from a filter expression
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ContextFilter {
+    Exact(MetadataFilter),
+    Expr(FilterExpr),
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "query", content = "args")]
 #[serde(rename_all = "snake_case")]
@@ -23,26 +33,38 @@ pub enum ContextQuery {
 
     // Query metadata from pages
     QueryMetadata(String, MetadataQuery),
-    QueryFilterMetadata(String, MetadataFilter, MetadataQuery),
+    QueryFilterMetadata(String, ContextFilter, MetadataQuery),
+    AggregateMetadata(String, Aggregation),
 
     // Query content
     QueryContext(String, String),
 }
 
 impl ContextQuery {
+    // Paginated listings get their position from the arguments of the request
     pub fn insert_data(
         &self,
         name: &String,
         ctxt: &mut Context,
         data: StorageData,
+        args: Option<&RequestArgs>,
     ) -> Result<(), Errcode> {
         match self {
             ContextQuery::Plain(..) => {}
-            ContextQuery::RecentPages(..) => ctxt.insert(name, &data.recent_pages()?),
-            ContextQuery::SimilarPagesFromMetadata(..) => ctxt.insert(name, &data.similar_pages()?),
-            ContextQuery::SimilarPagesFromUri(..) => ctxt.insert(name, &data.similar_pages()?),
+            ContextQuery::RecentPages(_, opts) => {
+                let (pages, total) = data.recent_pages()?;
+                ctxt.insert(name, &pages);
+                insert_pagination(name, ctxt, opts, args, total)?;
+            }
+            ContextQuery::SimilarPagesFromMetadata(.., opts)
+            | ContextQuery::SimilarPagesFromUri(.., opts) => {
+                let (pages, total) = data.similar_pages()?;
+                ctxt.insert(name, &pages);
+                insert_pagination(name, ctxt, opts, args, total)?;
+            }
             ContextQuery::QueryMetadata(..) => ctxt.insert(name, &data.query_metadata()?),
             ContextQuery::QueryFilterMetadata(..) => ctxt.insert(name, &data.query_metadata()?),
+            ContextQuery::AggregateMetadata(..) => ctxt.insert(name, &data.aggregates()?),
             ContextQuery::QueryContext(..) => ctxt.insert(name, &data.context()?),
         }
         Ok(())
@@ -59,9 +81,21 @@ impl ContextQuery {
                 (vec![], None),
                 query.clone(),
             ))),
-            ContextQuery::QueryFilterMetadata(slug, filter, query) => Ok(Some(
-                StorageQuery::query_metadata(slug, filter.clone(), query.clone()),
-            )),
+            ContextQuery::QueryFilterMetadata(slug, ContextFilter::Exact(filter), query) => {
+                Ok(Some(StorageQuery::query_metadata(
+                    slug,
+                    filter.clone(),
+                    query.clone(),
+                )))
+            }
+            ContextQuery::QueryFilterMetadata(slug, ContextFilter::Expr(filter), query) => {
+                let mut qry = StorageQuery::query_metadata(slug, (vec![], None), query.clone());
+                qry.set_filter(filter.clone());
+                Ok(Some(qry))
+            }
+            ContextQuery::AggregateMetadata(slug, agg) => {
+                Ok(Some(StorageQuery::aggregate_metadata(slug, agg)))
+            }
             ContextQuery::QueryContext(slug, name) => {
                 Ok(Some(StorageQuery::query_context(slug, name.clone())))
             }
@@ -71,6 +105,27 @@ impl ContextQuery {
         }
     }
 
+    fn list_options(&self) -> Option<&QueryListOptions> {
+        match self {
+            ContextQuery::RecentPages(_, opts)
+            | ContextQuery::SimilarPagesFromMetadata(.., opts)
+            | ContextQuery::SimilarPagesFromUri(.., opts) => Some(opts),
+            _ => None,
+        }
+    }
+
+    // Runs the query, at the page of the listing asked for by the request
+    pub async fn run_query(
+        &self,
+        qry: StorageQuery,
+        args: &RequestArgs,
+    ) -> Result<StorageData, Errcode> {
+        match self.list_options() {
+            Some(opts) => query_listing_page(qry, opts, args).await,
+            None => Ok(args.storage.query(qry).await),
+        }
+    }
+
     #[inline]
     pub fn get_storage_query(
         &self,
@@ -109,3 +164,67 @@ impl ContextQuery {
         }
     }
 }
+
+// Queries the page of a paginated listing asked for by the request. Pages after the first
+// one are only queried, and cached, once the first one shows the listing reaches them.
+pub async fn query_listing_page(
+    mut qry: StorageQuery,
+    opts: &QueryListOptions,
+    args: &RequestArgs,
+) -> Result<StorageData, Errcode> {
+    let Some(ref param) = opts.page_param else {
+        return Ok(args.storage.query(qry).await);
+    };
+    let page = args.get_page_number(param)?;
+    if page > 1 {
+        let total = match args.storage.query(qry.clone()).await {
+            StorageData::RecentPages(_, total) | StorageData::SimilarPages(_, total) => total,
+            StorageData::Error(e) => return Err(Errcode::StorageError(e)),
+            _ => return Err(Errcode::WrongStorageData("RecentPages")),
+        };
+        if page > opts.page_count(total) {
+            return Err(Errcode::PageNumberParsing(page.to_string()));
+        }
+        let offset = opts
+            .page_offset(page)
+            .ok_or_else(|| Errcode::PageNumberParsing(page.to_string()))?;
+        qry.set_offset(offset);
+    }
+    Ok(args.storage.query(qry).await)
+}
+
+// Position of the page of a listing shown, for templates to link to the other ones
+#[derive(Serialize)]
+struct Pagination {
+    page: usize,
+    page_count: usize,
+    total: usize,
+    prev_url: Option<String>,
+    next_url: Option<String>,
+}
+
+fn insert_pagination(
+    name: &String,
+    ctxt: &mut Context,
+    opts: &QueryListOptions,
+    args: Option<&RequestArgs>,
+    total: usize,
+) -> Result<(), Errcode> {
+    let (Some(param), Some(args)) = (opts.page_param.as_ref(), args) else {
+        return Ok(());
+    };
+    let page = args.get_page_number(param)?;
+    let page_count = opts.page_count(total);
+    if page > page_count {
+        return Err(Errcode::PageNumberParsing(page.to_string()));
+    }
+    let pagination = Pagination {
+        page,
+        page_count,
+        total,
+        prev_url: (page > 1).then(|| args.page_url(param, page - 1)),
+        next_url: (page < page_count).then(|| args.page_url(param, page + 1)),
+    };
+    ctxt.insert(format!("{name}_pagination"), &pagination);
+    Ok(())
+}
@@ -1,18 +1,29 @@
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
 
+use actix_web::body::{BodyStream, BoxBody};
+use actix_web::web::Bytes;
+use futures_core::Stream;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::Errcode;
 use crate::page::PageMetadata;
 
+use super::aggregate::Aggregate;
 use super::StorageErrorType;
 
+// Page contents are moved around as they are, without boxing their metadata
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum StorageData {
     Nothing,
-    RecentPages(Vec<PageMetadata>),
-    SimilarPages(Vec<PageMetadata>),
+    // Pages of the listing after its offset, with the number of pages listed in total
+    RecentPages(Vec<PageMetadata>, usize),
+    SimilarPages(Vec<PageMetadata>, usize),
     QueryMetadata(Vec<serde_json::Value>),
+    Aggregates(Vec<Aggregate>),
     PageContent {
         metadata: PageMetadata,
         body: String,
@@ -20,11 +31,40 @@ pub enum StorageData {
     },
     Templates(HashMap<String, String>),
     StaticFileData(Vec<u8>),
+    // Sent as it is read, never cached
+    #[serde(skip)]
+    StaticFileStream(FileStream),
     Error(StorageErrorType),
     Context(toml::Value),
 }
 
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>;
+
+/// Content of a file read from the storage as it is sent, can only be consumed once
+#[derive(Clone)]
+pub struct FileStream(Arc<Mutex<Option<ByteStream>>>);
+
+impl FileStream {
+    pub fn new(stream: ByteStream) -> FileStream {
+        FileStream(Arc::new(Mutex::new(Some(stream))))
+    }
+
+    pub fn take(&self) -> Option<ByteStream> {
+        self.0.lock().take()
+    }
+}
+
+impl std::fmt::Debug for FileStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileStream")
+    }
+}
+
 impl StorageData {
+    pub fn is_stream(&self) -> bool {
+        matches!(self, StorageData::StaticFileStream(_))
+    }
+
     #[inline]
     pub fn query_metadata(self) -> Result<Vec<serde_json::Value>, Errcode> {
         match self {
@@ -35,9 +75,18 @@ impl StorageData {
     }
 
     #[inline]
-    pub fn similar_pages(self) -> Result<Vec<PageMetadata>, Errcode> {
+    pub fn aggregates(self) -> Result<Vec<Aggregate>, Errcode> {
         match self {
-            StorageData::SimilarPages(pages) => Ok(pages),
+            StorageData::Aggregates(groups) => Ok(groups),
+            StorageData::Error(e) => Err(Errcode::StorageError(e)),
+            _ => Err(Errcode::WrongStorageData("Aggregates")),
+        }
+    }
+
+    #[inline]
+    pub fn similar_pages(self) -> Result<(Vec<PageMetadata>, usize), Errcode> {
+        match self {
+            StorageData::SimilarPages(pages, total) => Ok((pages, total)),
             StorageData::Error(e) => Err(Errcode::StorageError(e)),
             _ => Err(Errcode::WrongStorageData("SimilarPages")),
         }
@@ -57,9 +106,9 @@ impl StorageData {
     }
 
     #[inline]
-    pub fn recent_pages(self) -> Result<Vec<PageMetadata>, Errcode> {
+    pub fn recent_pages(self) -> Result<(Vec<PageMetadata>, usize), Errcode> {
         match self {
-            StorageData::RecentPages(pages) => Ok(pages),
+            StorageData::RecentPages(pages, total) => Ok((pages, total)),
             StorageData::Error(e) => Err(Errcode::StorageError(e)),
             _ => Err(Errcode::WrongStorageData("RecentPages")),
         }
@@ -83,6 +132,20 @@ impl StorageData {
         }
     }
 
+    // Body of a static file, whether it was fully read or is streamed
+    #[inline]
+    pub fn static_file_body(self) -> Result<BoxBody, Errcode> {
+        match self {
+            StorageData::StaticFileData(data) => Ok(BoxBody::new(data)),
+            StorageData::StaticFileStream(stream) => match stream.take() {
+                Some(stream) => Ok(BoxBody::new(BodyStream::new(stream))),
+                None => Err(Errcode::WrongStorageData("StaticFileStream")),
+            },
+            StorageData::Error(e) => Err(Errcode::StorageError(e)),
+            _ => Err(Errcode::WrongStorageData("StaticFileData")),
+        }
+    }
+
     #[inline]
     pub fn context(self) -> Result<toml::Value, Errcode> {
         match self {
@@ -0,0 +1,411 @@
+use std::cmp::Ordering;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::page::{metadata_date, PageMetadata};
+
+use super::context::MetadataQuery;
+
+/// Condition on the metadata of the pages, written as an expression such as
+/// `tags contains "rust" and (date >= "2023-01-01" or not pinned exists)`.
+///
+/// Conditions are a key path, an operator and a value: `==`, `!=`, `<`, `<=`, `>`, `>=`
+/// on numbers and dates, `in [..]`, `contains`, `matches "<regex>"`, or `exists` with no
+/// value. They are combined with `and`, `or`, `not` and parentheses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FilterExpr {
+    // Written form of the expression, identifying it in the queries
+    source: String,
+    node: FilterNode,
+}
+
+impl FilterExpr {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, metadata: &PageMetadata) -> bool {
+        self.node.matches(metadata)
+    }
+}
+
+impl PartialEq for FilterExpr {
+    fn eq(&self, other: &FilterExpr) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for FilterExpr {}
+
+impl TryFrom<String> for FilterExpr {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<FilterExpr, String> {
+        let tokens = tokenize(&source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let node = parser.parse_or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {token:?} in filter {source:?}"));
+        }
+        Ok(FilterExpr { source, node })
+    }
+}
+
+impl std::str::FromStr for FilterExpr {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<FilterExpr, String> {
+        FilterExpr::try_from(source.to_string())
+    }
+}
+
+impl From<FilterExpr> for String {
+    fn from(expr: FilterExpr) -> String {
+        expr.source
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum FilterNode {
+    And(Box<FilterNode>, Box<FilterNode>),
+    Or(Box<FilterNode>, Box<FilterNode>),
+    Not(Box<FilterNode>),
+    Compare(MetadataQuery, CompareOp, Value),
+    In(MetadataQuery, Vec<Value>),
+    Contains(MetadataQuery, Value),
+    Matches(MetadataQuery, Regex),
+    Exists(MetadataQuery),
+}
+
+impl FilterNode {
+    fn matches(&self, metadata: &PageMetadata) -> bool {
+        match self {
+            FilterNode::And(a, b) => a.matches(metadata) && b.matches(metadata),
+            FilterNode::Or(a, b) => a.matches(metadata) || b.matches(metadata),
+            FilterNode::Not(a) => !a.matches(metadata),
+            // A missing key is different from any value, and can't be compared
+            FilterNode::Compare(keys, op, val) => match metadata.get_metadata(keys) {
+                None => *op == CompareOp::Ne,
+                Some(md) => compare(md, *op, val),
+            },
+            FilterNode::In(keys, vals) => match metadata.get_metadata(keys) {
+                Some(Value::Array(mds)) => mds.iter().any(|md| vals.iter().any(|v| eq(md, v))),
+                Some(md) => vals.iter().any(|v| eq(md, v)),
+                None => false,
+            },
+            FilterNode::Contains(keys, val) => match metadata.get_metadata(keys) {
+                Some(Value::Array(mds)) => mds.iter().any(|md| eq(md, val)),
+                Some(Value::String(s)) => val.as_str().is_some_and(|v| s.contains(v)),
+                _ => false,
+            },
+            FilterNode::Matches(keys, re) => match metadata.get_metadata(keys) {
+                Some(Value::Array(mds)) => mds
+                    .iter()
+                    .any(|md| md.as_str().is_some_and(|s| re.is_match(s))),
+                Some(Value::String(s)) => re.is_match(s),
+                _ => false,
+            },
+            FilterNode::Exists(keys) => metadata.get_metadata(keys).is_some_and(|md| !md.is_null()),
+        }
+    }
+}
+
+fn compare(md: &Value, op: CompareOp, val: &Value) -> bool {
+    match op {
+        CompareOp::Eq => eq(md, val),
+        CompareOp::Ne => !eq(md, val),
+        _ => {
+            let Some(ord) = order(md, val) else {
+                return false;
+            };
+            match op {
+                CompareOp::Lt => ord == Ordering::Less,
+                CompareOp::Le => ord != Ordering::Greater,
+                CompareOp::Gt => ord == Ordering::Greater,
+                CompareOp::Ge => ord != Ordering::Less,
+                CompareOp::Eq | CompareOp::Ne => unreachable!(),
+            }
+        }
+    }
+}
+
+// Numbers are equal whether written as integers or floats
+fn eq(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Order of two numbers, or of two dates
+fn order(a: &Value, b: &Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+        return a.partial_cmp(&b);
+    }
+    Some(metadata_date(a)?.cmp(&metadata_date(b)?))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Op(CompareOp),
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '[' => Token::OpenList,
+            ']' => Token::CloseList,
+            ',' => Token::Comma,
+            '=' | '!' | '<' | '>' => {
+                let with_eq = chars.next_if(|(_, c)| *c == '=').is_some();
+                Token::Op(match (c, with_eq) {
+                    ('=', true) => CompareOp::Eq,
+                    ('!', true) => CompareOp::Ne,
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    ('>', true) => CompareOp::Ge,
+                    _ => return Err(format!("unexpected {c:?} in filter {source:?}")),
+                })
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => text.push(c),
+                            None => {
+                                return Err(format!("unterminated string in filter {source:?}"))
+                            }
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => return Err(format!("unterminated string in filter {source:?}")),
+                    }
+                }
+                Token::Literal(Value::String(text))
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || "_-.+".contains(*c))
+                {
+                    end = i + c.len_utf8();
+                }
+                let word = &source[start..end];
+                match word {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => match serde_json::from_str::<serde_json::Number>(word) {
+                        Ok(n) => Token::Literal(Value::Number(n)),
+                        Err(_) => Token::Ident(word.to_string()),
+                    },
+                }
+            }
+            c => return Err(format!("unexpected {c:?} in filter {source:?}")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_is_keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.tokens.get(self.pos), Some(Token::Ident(w)) if w == keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn parse_or(&mut self) -> Result<FilterNode, String> {
+        let mut node = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            node = FilterNode::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<FilterNode, String> {
+        let mut node = self.parse_unary()?;
+        while self.next_is_keyword("and") {
+            node = FilterNode::And(Box::new(node), Box::new(self.parse_unary()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_unary(&mut self) -> Result<FilterNode, String> {
+        if self.next_is_keyword("not") {
+            return Ok(FilterNode::Not(Box::new(self.parse_unary()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let node = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(node),
+                    token => Err(format!("expected ')', found {token:?}")),
+                }
+            }
+            Some(Token::Ident(path)) => self.parse_condition(&path),
+            token => Err(format!("expected a key or '(', found {token:?}")),
+        }
+    }
+
+    fn parse_condition(&mut self, path: &str) -> Result<FilterNode, String> {
+        let keys = path
+            .split('.')
+            .map(|k| k.to_string())
+            .collect::<MetadataQuery>();
+        if keys.iter().any(|k| k.is_empty()) {
+            return Err(format!("invalid key {path:?}"));
+        }
+        match self.next() {
+            Some(Token::Op(op)) => Ok(FilterNode::Compare(keys, op, self.parse_literal()?)),
+            Some(Token::Ident(w)) if w == "exists" => Ok(FilterNode::Exists(keys)),
+            Some(Token::Ident(w)) if w == "contains" => {
+                Ok(FilterNode::Contains(keys, self.parse_literal()?))
+            }
+            Some(Token::Ident(w)) if w == "matches" => match self.parse_literal()? {
+                Value::String(pattern) => {
+                    let re = Regex::new(&pattern).map_err(|e| format!("{e}"))?;
+                    Ok(FilterNode::Matches(keys, re))
+                }
+                val => Err(format!(
+                    "expected a regex string after matches, found {val}"
+                )),
+            },
+            Some(Token::Ident(w)) if w == "in" => {
+                if self.next() != Some(Token::OpenList) {
+                    return Err("expected '[' after in".to_string());
+                }
+                let mut vals = vec![];
+                loop {
+                    if self.tokens.get(self.pos) == Some(&Token::CloseList) && vals.is_empty() {
+                        self.pos += 1;
+                        break;
+                    }
+                    vals.push(self.parse_literal()?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::CloseList) => break,
+                        token => return Err(format!("expected ',' or ']', found {token:?}")),
+                    }
+                }
+                Ok(FilterNode::In(keys, vals))
+            }
+            token => Err(format!(
+                "expected an operator after {path:?}, found {token:?}"
+            )),
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Literal(val)) => Ok(val),
+            token => Err(format!("expected a value, found {token:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(metadata: &str) -> PageMetadata {
+        PageMetadata {
+            metadata: serde_json::from_str(metadata).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn matches(expr: &str, metadata: &str) -> bool {
+        expr.parse::<FilterExpr>().unwrap().matches(&page(metadata))
+    }
+
+    #[test]
+    fn compares_values() {
+        let md = r#"{"tags": ["rust", "web"], "stars": 4, "date": "2023-05-01", "lang": "en"}"#;
+        assert!(matches(r#"tags contains "rust""#, md));
+        assert!(!matches(r#"tags contains "go""#, md));
+        assert!(matches("stars >= 4.0 and stars < 5", md));
+        assert!(matches(r#"date > "2023-01-01""#, md));
+        assert!(!matches(r#"date > "2023-12-31T00:00:00Z""#, md));
+        assert!(matches(r#"lang in ["fr", "en"]"#, md));
+        assert!(matches(r#"tags in ["go", "web"]"#, md));
+        assert!(matches(r#"lang matches "^e""#, md));
+        assert!(matches("stars exists and not pinned exists", md));
+        assert!(matches(r#"pinned != true"#, md));
+        assert!(!matches(r#"pinned == true"#, md));
+    }
+
+    #[test]
+    fn follows_precedence() {
+        let md = r#"{"a": 1, "b": 2, "nested": {"key": "x"}}"#;
+        assert!(matches("a == 1 or a == 3 and b == 3", md));
+        assert!(!matches("(a == 1 or a == 3) and b == 3", md));
+        assert!(matches("not (a == 2) and nested.key == \"x\"", md));
+    }
+
+    #[test]
+    fn refuses_invalid_expressions() {
+        for expr in [
+            "",
+            "a ==",
+            "a == 1 and",
+            "(a == 1",
+            "a in [1, 2",
+            "a matches 1",
+            r#"a matches "(""#,
+            r#"a == "unterminated"#,
+            "a ~ 1",
+            "a..b exists",
+        ] {
+            assert!(expr.parse::<FilterExpr>().is_err(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn deserializes_from_its_source() {
+        let expr: FilterExpr = serde_json::from_str(r#""a == 1""#).unwrap();
+        assert_eq!(expr.source(), "a == 1");
+        assert_eq!(serde_json::to_string(&expr).unwrap(), r#""a == 1""#);
+        assert!(serde_json::from_str::<FilterExpr>(r#""a ==""#).is_err());
+    }
+}
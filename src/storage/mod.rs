@@ -1,33 +1,62 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use parking_lot::RwLock;
+
 use crate::cache::Cache;
 use crate::config::Config;
+use crate::page::timestamp_now;
 
+mod aggregate;
 pub mod backend;
 mod context;
 mod data;
+mod filter;
+mod mount;
 mod query;
+mod write;
 
-use backend::StorageBackend;
-pub use context::ContextQuery;
+use backend::{StorageBackend, StorageChange};
+pub use context::{query_listing_page, ContextQuery};
 pub use data::StorageData;
-pub use query::{StorageQuery, StorageQueryMethod};
+use mount::MountTable;
+pub use query::{QueryListOptions, StorageQuery, StorageQueryMethod};
+pub use write::StorageWrite;
 
 pub type StorageSlug = String;
 
-#[cfg(feature = "storage-local")]
-pub type Storage = StorageImpl<backend::local::LocalStorage>;
-#[cfg(feature = "storage-local")]
-pub type StorageErrorType = backend::local::LocalStorageError;
+pub type Storage = StorageImpl<backend::Backend>;
+pub type StorageErrorType = backend::BackendError;
 
 pub struct StorageImpl<T: StorageBackend> {
     cache: Cache<StorageQuery, StorageData>,
-    backend: T,
+    mounts: MountTable<T>,
+
+    // Time at which cached answers change because a page gets published or expires
+    schedules: RwLock<HashMap<StorageQuery, i64>>,
+
+    // Counts the changes found in the data of each slug, for the data derived from it
+    generations: RwLock<HashMap<StorageSlug, usize>>,
 }
 
-impl<T: StorageBackend> StorageImpl<T> {
-    pub fn init(config: &Config) -> Result<StorageImpl<T>, T::Error> {
+impl<T: StorageBackend> StorageImpl<T>
+where
+    T::Error: Into<StorageErrorType>,
+{
+    pub fn init(
+        backend: T,
+        mounts: HashMap<String, Vec<T>>,
+        config: &Config,
+    ) -> Result<StorageImpl<T>, StorageErrorType> {
+        let mut cache = Cache::empty(config.cache_size);
+        if config.dev_mode {
+            cache.disable();
+        }
         Ok(StorageImpl {
-            cache: Cache::empty(1024), // TODO Get from config
-            backend: T::init(config)?,
+            cache,
+            mounts: MountTable::init(backend, mounts, config)?,
+            schedules: RwLock::new(HashMap::new()),
+            generations: RwLock::new(HashMap::new()),
         })
     }
 
@@ -36,15 +65,121 @@ impl<T: StorageBackend> StorageImpl<T> {
             if !self.has_changed(&qry).await {
                 return data;
             }
+            self.changed(&qry.storage_slug);
+        }
+        let mount = self.mounts.route(&qry.storage_slug);
+        let data = mount.query(qry.clone()).await;
+        if qry.method.reads_pages() {
+            match mount.next_schedule(&qry, timestamp_now()).await {
+                Some(at) => self.schedules.write().insert(qry.clone(), at),
+                None => self.schedules.write().remove(&qry),
+            };
         }
-        let data = self.backend.query(qry.clone()).await;
-        self.cache.add(qry, data.clone());
+        let uncached = match data.is_stream() {
+            true => vec![qry],
+            false => self.cache.add(qry, data.clone()),
+        };
+        self.forget(&uncached);
+        data
+    }
+
+    // Answers a query from the backends of its slug without caching the data, for data
+    // read once to derive something else from it
+    pub async fn query_uncached(&self, qry: StorageQuery) -> StorageData {
+        let data = self
+            .mounts
+            .route(&qry.storage_slug)
+            .query(qry.clone())
+            .await;
+        self.forget(&[qry]);
         data
     }
 
     pub async fn has_changed(&self, qry: &StorageQuery) -> bool {
-        self.backend.has_changed(qry).await
+        if let Some(at) = self.schedules.read().get(qry) {
+            if *at <= timestamp_now() {
+                return true;
+            }
+        }
+        self.mounts.route(&qry.storage_slug).has_changed(qry).await
     }
 
-    // TODO    Add a way to save data into the storage as well
+    // Languages the pages of a storage slug can be translated in
+    pub fn supported_lang(&self, slug: &str) -> Vec<String> {
+        self.mounts.route(slug).supported_lang()
+    }
+
+    // Paths to watch for changes, without the ones already contained in another
+    pub fn watch(&self) -> Vec<PathBuf> {
+        let mut paths = self
+            .mounts
+            .all()
+            .flat_map(|mount| mount.watch())
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+        paths.dedup();
+        let mut watched: Vec<PathBuf> = vec![];
+        for path in paths {
+            if !watched.iter().any(|w| path.starts_with(w)) {
+                watched.push(path);
+            }
+        }
+        watched
+    }
+
+    pub fn files_changed(&self, paths: &[PathBuf]) -> HashSet<StorageChange> {
+        let changes = self
+            .mounts
+            .all()
+            .flat_map(|mount| mount.files_changed(paths))
+            .collect::<HashSet<StorageChange>>();
+        for change in changes.iter() {
+            let slug = match change {
+                StorageChange::Pages(slug) => slug.as_str(),
+                StorageChange::Templates => "templates",
+                StorageChange::StaticFiles => "static",
+            };
+            log::debug!("Invalidating cached data of {slug}");
+            let removed = self.cache.invalidate(|qry| qry.storage_slug == slug);
+            self.forget(&removed);
+            self.changed(slug);
+        }
+        changes
+    }
+
+    pub async fn write(&self, wrt: StorageWrite) -> Result<(), StorageErrorType> {
+        let slug = wrt.storage_slug.clone();
+        let res = self.mounts.route(&slug).write(wrt).await;
+        // Listings and metadata queries of the slug may depend on what was written
+        let removed = self.cache.invalidate(|qry| qry.storage_slug == slug);
+        self.forget(&removed);
+        self.changed(&slug);
+        res
+    }
+
+    // Number of changes found in the data of a slug so far
+    pub fn generation(&self, slug: &str) -> usize {
+        self.generations.read().get(slug).copied().unwrap_or(0)
+    }
+
+    // Queries are only tracked while their data is cached, so the maps keyed by them
+    // stay as bounded as the cache is
+    fn forget(&self, qrys: &[StorageQuery]) {
+        if qrys.is_empty() {
+            return;
+        }
+        let mut schedules = self.schedules.write();
+        for qry in qrys {
+            schedules.remove(qry);
+            self.mounts.route(&qry.storage_slug).forget(qry);
+        }
+    }
+
+    fn changed(&self, slug: &str) {
+        *self
+            .generations
+            .write()
+            .entry(slug.to_string())
+            .or_default() += 1;
+    }
 }
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use parking_lot::RwLock;
+
+use crate::config::Config;
+
+use super::backend::{BackendError, StorageBackend, StorageChange};
+use super::{StorageData, StorageErrorType, StorageQuery, StorageQueryMethod, StorageWrite};
+
+pub const DEFAULT_MOUNT: &str = "default";
+
+/// Backends serving a storage slug, tried in order until one of them has the data
+pub struct Mount<T: StorageBackend> {
+    name: String,
+    backends: Vec<T>,
+
+    // Index of the backend that answered each query
+    answered_by: RwLock<HashMap<StorageQuery, usize>>,
+}
+
+impl<T: StorageBackend> Mount<T>
+where
+    T::Error: Into<StorageErrorType>,
+{
+    pub fn init(
+        name: &str,
+        mut backends: Vec<T>,
+        config: &Config,
+    ) -> Result<Self, StorageErrorType> {
+        if backends.is_empty() {
+            return Err(BackendError::EmptyMount(name.to_string()));
+        }
+        for (n, backend) in backends.iter_mut().enumerate() {
+            backend
+                .init(config)
+                .map_err(|e| BackendError::Mount(name.to_string(), n, Box::new(e.into())))?;
+        }
+        log::debug!("Mounted {} backends on {name}", backends.len());
+        Ok(Mount {
+            name: name.to_string(),
+            backends,
+            answered_by: RwLock::new(HashMap::new()),
+        })
+    }
+
+    fn wrap_error(&self, n: usize, data: StorageData) -> StorageData {
+        match data {
+            StorageData::Error(e) => {
+                StorageData::Error(BackendError::Mount(self.name.clone(), n, Box::new(e)))
+            }
+            data => data,
+        }
+    }
+
+    pub async fn query(&self, qry: StorageQuery) -> StorageData {
+        // Templates from every backend are merged, the first backends overriding the last ones
+        if qry.method == StorageQueryMethod::QueryTemplates {
+            let mut all_templates = HashMap::new();
+            for (n, backend) in self.backends.iter().enumerate().rev() {
+                match backend.query(qry.clone()).await {
+                    StorageData::Templates(templates) => all_templates.extend(templates),
+                    StorageData::Error(e) if e.is_not_found() => continue,
+                    data => return self.wrap_error(n, data),
+                }
+            }
+            self.answered_by
+                .write()
+                .insert(qry, self.backends.len() - 1);
+            return StorageData::Templates(all_templates);
+        }
+
+        let mut last = StorageData::Nothing;
+        for (n, backend) in self.backends.iter().enumerate() {
+            let data = backend.query(qry.clone()).await;
+            if let StorageData::Error(ref e) = data {
+                if e.is_not_found() {
+                    last = self.wrap_error(n, data);
+                    continue;
+                }
+            }
+            self.answered_by.write().insert(qry, n);
+            return self.wrap_error(n, data);
+        }
+        last
+    }
+
+    // Writes always go to the first backend of the mount
+    pub async fn write(&self, wrt: StorageWrite) -> Result<(), StorageErrorType> {
+        self.backends[0]
+            .write(wrt)
+            .await
+            .map_err(|e| BackendError::Mount(self.name.clone(), 0, Box::new(e.into())))
+    }
+
+    pub fn watch(&self) -> Vec<PathBuf> {
+        self.backends.iter().flat_map(|b| b.watch()).collect()
+    }
+
+    pub fn files_changed(&self, paths: &[PathBuf]) -> Vec<StorageChange> {
+        self.backends
+            .iter()
+            .flat_map(|b| b.files_changed(paths))
+            .collect()
+    }
+
+    // Languages supported by any of the backends, in the order they declare them
+    pub fn supported_lang(&self) -> Vec<String> {
+        let mut langs: Vec<String> = vec![];
+        for lang in self.backends.iter().flat_map(|b| b.supported_lang()) {
+            if !langs.contains(&lang) {
+                langs.push(lang);
+            }
+        }
+        langs
+    }
+
+    pub async fn next_schedule(&self, qry: &StorageQuery, now: i64) -> Option<i64> {
+        let mut next = None;
+        for backend in self.backends.iter() {
+            if let Some(at) = backend.next_schedule(qry, now).await {
+                next = Some(next.map_or(at, |n: i64| n.min(at)));
+            }
+        }
+        next
+    }
+
+    pub fn forget(&self, qry: &StorageQuery) {
+        self.answered_by.write().remove(qry);
+        for backend in self.backends.iter() {
+            backend.forget(qry);
+        }
+    }
+
+    pub async fn has_changed(&self, qry: &StorageQuery) -> bool {
+        // If the query wasn't answered, any of the backends could now have the data
+        let upto = self
+            .answered_by
+            .read()
+            .get(qry)
+            .copied()
+            .unwrap_or(self.backends.len() - 1);
+        for backend in self.backends.iter().take(upto + 1) {
+            if backend.has_changed(qry).await {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Routes each query to the mount declared for its storage slug
+pub struct MountTable<T: StorageBackend> {
+    default: Mount<T>,
+    exact: HashMap<String, Mount<T>>,
+    // Sorted from the longest prefix to the shortest
+    prefixes: Vec<(String, Mount<T>)>,
+}
+
+impl<T: StorageBackend> MountTable<T>
+where
+    T::Error: Into<StorageErrorType>,
+{
+    pub fn init(
+        default: T,
+        mounts: HashMap<String, Vec<T>>,
+        config: &Config,
+    ) -> Result<Self, StorageErrorType> {
+        let mut exact = HashMap::new();
+        let mut prefixes = vec![];
+        for (pattern, backends) in mounts {
+            let mount = Mount::init(&pattern, backends, config)?;
+            if let Some(prefix) = pattern.strip_suffix('*') {
+                prefixes.push((prefix.to_string(), mount));
+            } else {
+                exact.insert(pattern, mount);
+            }
+        }
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(MountTable {
+            default: Mount::init(DEFAULT_MOUNT, vec![default], config)?,
+            exact,
+            prefixes,
+        })
+    }
+
+    pub fn route(&self, slug: &str) -> &Mount<T> {
+        if let Some(mount) = self.exact.get(slug) {
+            return mount;
+        }
+        for (prefix, mount) in self.prefixes.iter() {
+            if slug.starts_with(prefix.as_str()) {
+                return mount;
+            }
+        }
+        &self.default
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Mount<T>> {
+        std::iter::once(&self.default)
+            .chain(self.exact.values())
+            .chain(self.prefixes.iter().map(|(_, mount)| mount))
+    }
+}
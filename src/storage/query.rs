@@ -3,7 +3,11 @@ use std::hash::Hasher;
 
 use serde::{Deserialize, Serialize};
 
+use crate::page::PageMetadata;
+
+use super::aggregate::Aggregation;
 use super::context::{MetadataFilter, MetadataQuery};
+use super::filter::FilterExpr;
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct QueryListOptions {
@@ -13,6 +17,38 @@ pub struct QueryListOptions {
     sort_by: Option<Vec<String>>,
     #[serde(default)]
     rev_sort: bool,
+
+    // Pages skipped at the start of the listing
+    #[serde(default)]
+    offset: usize,
+
+    // URL segment or query parameter holding the number of the page of the listing
+    // to show, starting from 1, each page listing `limit` pages
+    #[serde(default)]
+    pub page_param: Option<String>,
+
+    // Only list the pages whose metadata match this expression
+    #[serde(default)]
+    filter: Option<FilterExpr>,
+}
+
+impl QueryListOptions {
+    // Pages skipped at the start of the listing to show a page of it, none if too far
+    pub fn page_offset(&self, page: usize) -> Option<usize> {
+        page.checked_sub(1)?
+            .checked_mul(self.limit)?
+            .checked_add(self.offset)
+    }
+
+    // Number of pages of a listing, counting the first one even if empty
+    pub fn page_count(&self, total: usize) -> usize {
+        let listed = total.saturating_sub(self.offset);
+        if self.limit == 0 || listed == 0 {
+            1
+        } else {
+            listed.div_ceil(self.limit)
+        }
+    }
 }
 
 #[repr(u8)]
@@ -26,6 +62,8 @@ pub enum StorageQueryMethod {
     ContentFromName(String),
     ContentNumId(u64),
     ContentSlug(String),
+    // Page that had this id before ids were derived from the path of the pages
+    ContentLegacyId(u64),
 
     // Query other pages
     RecentPages,
@@ -36,8 +74,12 @@ pub enum StorageQueryMethod {
 
     // Query data
     StaticFile(String),
+    // Asset of a page bundle, from the name of the page and the path of the asset in it
+    PageAsset(String, String),
     QueryContext(String),
     QueryMetadata(MetadataFilter, MetadataQuery),
+    // Distinct values of a metadata key in the listed pages, with their counts
+    AggregateMetadata(Aggregation),
 }
 
 impl StorageQueryMethod {
@@ -50,6 +92,31 @@ impl StorageQueryMethod {
         qry.update_key();
         qry
     }
+
+    // Whether the page is the one looked up from its id
+    pub fn matches_id(&self, metadata: &PageMetadata) -> bool {
+        match self {
+            StorageQueryMethod::ContentNumId(id) => metadata.id == *id,
+            StorageQueryMethod::ContentLegacyId(id) => metadata.legacy_id == *id,
+            _ => false,
+        }
+    }
+
+    // Whether the answer depends on the visibility and publication dates of the pages
+    pub fn reads_pages(&self) -> bool {
+        matches!(
+            self,
+            StorageQueryMethod::ContentFromName(_)
+                | StorageQueryMethod::ContentNumId(_)
+                | StorageQueryMethod::ContentLegacyId(_)
+                | StorageQueryMethod::ContentSlug(_)
+                | StorageQueryMethod::RecentPages
+                | StorageQueryMethod::GetSimilarPages(_)
+                | StorageQueryMethod::QueryMetadata(..)
+                | StorageQueryMethod::AggregateMetadata(_)
+                | StorageQueryMethod::PageAsset(..)
+        )
+    }
 }
 
 #[derive(Debug, Default, Eq, Clone)]
@@ -58,8 +125,15 @@ pub struct StorageQuery {
     pub storage_slug: String,
     pub method: StorageQueryMethod,
     pub limit: usize,
+    pub offset: usize,
     pub lang_pref: Option<Vec<String>>,
     pub sort_by: Option<(Vec<String>, bool)>,
+    // Version of the data to read instead of the published one, for backends supporting it
+    pub revision: Option<String>,
+    // Draft pages are answered as well, in dev mode or with the preview token
+    pub drafts: bool,
+    // Only answer from the pages whose metadata match this expression
+    pub filter: Option<FilterExpr>,
 }
 
 impl std::hash::Hash for StorageQuery {
@@ -86,6 +160,13 @@ impl StorageQuery {
     ) -> StorageQuery {
         StorageQueryMethod::QueryMetadata(filter, qry).build_query(slug)
     }
+    pub fn aggregate_metadata(slug: &String, agg: &Aggregation) -> StorageQuery {
+        let mut qry = StorageQueryMethod::AggregateMetadata(agg.clone()).build_query(slug);
+        if let Some(ref filter) = agg.filter {
+            qry.set_filter(filter.clone());
+        }
+        qry
+    }
     pub fn similar_pages(
         slug: &String,
         keys: MetadataFilter,
@@ -98,6 +179,9 @@ impl StorageQuery {
     pub fn static_file(fname: String) -> StorageQuery {
         StorageQueryMethod::StaticFile(fname).build_query("static")
     }
+    pub fn page_asset(slug: &String, name: String, asset: String) -> StorageQuery {
+        StorageQueryMethod::PageAsset(name, asset).build_query(slug)
+    }
     pub fn templates() -> StorageQuery {
         StorageQueryMethod::QueryTemplates.build_query("templates")
     }
@@ -158,6 +242,34 @@ impl StorageQuery {
                 s.write_u8(10);
                 s.write(name.as_bytes());
             }
+            StorageQueryMethod::PageAsset(ref name, ref asset) => {
+                s.write_u8(11);
+                s.write(name.as_bytes());
+                s.write(asset.as_bytes());
+            }
+            StorageQueryMethod::ContentLegacyId(id) => {
+                s.write_u8(12);
+                s.write_u64(id);
+            }
+            // The filter of the aggregation is hashed along with the one of the query
+            StorageQueryMethod::AggregateMetadata(ref agg) => {
+                s.write_u8(13);
+                for k in agg.group_by.iter() {
+                    s.write(k.as_bytes());
+                }
+                s.write_u8(agg.derive.map_or(0, |d| d as u8 + 1));
+                if let Some(ref date) = agg.date {
+                    s.write_u8(1);
+                    for k in date.iter() {
+                        s.write(k.as_bytes());
+                    }
+                } else {
+                    s.write_u8(0);
+                }
+                s.write_u8(agg.sort_by as u8);
+                s.write_u8(agg.rev_sort as u8);
+                s.write_usize(agg.limit);
+            }
         }
         if let Some(ref langs) = self.lang_pref {
             s.write_u8(1);
@@ -176,6 +288,20 @@ impl StorageQuery {
             s.write_u8(0);
         }
         s.write_usize(self.limit);
+        s.write_usize(self.offset);
+        if let Some(ref rev) = self.revision {
+            s.write_u8(1);
+            s.write(rev.as_bytes());
+        } else {
+            s.write_u8(0);
+        }
+        s.write_u8(self.drafts as u8);
+        if let Some(ref filter) = self.filter {
+            s.write_u8(1);
+            s.write(filter.source().as_bytes());
+        } else {
+            s.write_u8(0);
+        }
         self.key = s.finish();
     }
 
@@ -184,9 +310,54 @@ impl StorageQuery {
         self.update_key();
     }
 
+    pub fn set_revision(&mut self, revision: String) {
+        self.revision = Some(revision);
+        self.update_key();
+    }
+
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+        self.update_key();
+    }
+
+    pub fn set_filter(&mut self, filter: FilterExpr) {
+        self.filter = Some(filter);
+        self.update_key();
+    }
+
+    // Whether a page is part of the answer to a listing or metadata query
+    pub fn matches_filter(&self, metadata: &PageMetadata) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.matches(metadata))
+    }
+
+    pub fn show_drafts(&mut self) {
+        self.drafts = true;
+        self.update_key();
+    }
+
     pub fn list_opts(&mut self, opts: &QueryListOptions) {
         self.limit = opts.limit;
+        self.offset = opts.offset;
         self.sort_by = opts.sort_by.clone().map(|s| (s, opts.rev_sort));
+        self.filter = opts.filter.clone();
         self.update_key();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_offsets() {
+        let opts: QueryListOptions = toml::from_str("limit = 10\noffset = 2").unwrap();
+        assert_eq!(opts.page_offset(1), Some(2));
+        assert_eq!(opts.page_offset(3), Some(22));
+        assert_eq!(opts.page_offset(0), None);
+        assert_eq!(opts.page_offset(usize::MAX), None);
+        assert_eq!(opts.page_count(0), 1);
+        assert_eq!(opts.page_count(2), 1);
+        assert_eq!(opts.page_count(22), 2);
+        assert_eq!(opts.page_count(23), 3);
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::page::PageMetadata;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// All the write operations that a storage have to implement
+#[allow(clippy::large_enum_variant)]
+pub enum StorageWriteMethod {
+    SavePage {
+        name: String,
+        metadata: PageMetadata,
+        body: String,
+    },
+    DeletePage(String),
+
+    SaveContext(String, toml::Value),
+    DeleteContext(String),
+
+    SaveStaticFile(String, Vec<u8>),
+    DeleteStaticFile(String),
+}
+
+impl StorageWriteMethod {
+    pub fn build_write<T: ToString + ?Sized>(self, slug: &T) -> StorageWrite {
+        StorageWrite {
+            storage_slug: slug.to_string(),
+            lang: None,
+            method: self,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageWrite {
+    pub storage_slug: String,
+    pub method: StorageWriteMethod,
+    // Unlike queries, a write targets a single language
+    pub lang: Option<String>,
+}
+
+impl StorageWrite {
+    pub fn save_page(
+        slug: &String,
+        name: String,
+        metadata: PageMetadata,
+        body: String,
+    ) -> StorageWrite {
+        StorageWriteMethod::SavePage {
+            name,
+            metadata,
+            body,
+        }
+        .build_write(slug)
+    }
+
+    pub fn delete_page(slug: &String, name: String) -> StorageWrite {
+        StorageWriteMethod::DeletePage(name).build_write(slug)
+    }
+
+    pub fn save_context(slug: &String, name: String, data: toml::Value) -> StorageWrite {
+        StorageWriteMethod::SaveContext(name, data).build_write(slug)
+    }
+
+    pub fn delete_context(slug: &String, name: String) -> StorageWrite {
+        StorageWriteMethod::DeleteContext(name).build_write(slug)
+    }
+
+    pub fn save_static_file(fname: String, data: Vec<u8>) -> StorageWrite {
+        StorageWriteMethod::SaveStaticFile(fname, data).build_write("static")
+    }
+
+    pub fn delete_static_file(fname: String) -> StorageWrite {
+        StorageWriteMethod::DeleteStaticFile(fname).build_write("static")
+    }
+
+    pub fn set_lang(&mut self, lang: String) {
+        self.lang = Some(lang);
+    }
+}
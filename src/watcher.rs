@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::errors::Errcode;
+use crate::render::Render;
+use crate::storage::backend::StorageChange;
+use crate::storage::Storage;
+
+// Editors usually trigger several events when saving a single file
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+pub struct FsWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl FsWatcher {
+    pub fn start(storage: Arc<Storage>, render: Arc<Render>) -> Result<FsWatcher, Errcode> {
+        let (tx, rx) = channel();
+        let mut watcher =
+            notify::recommended_watcher(tx).map_err(|e| Errcode::FileWatcher(format!("{e:?}")))?;
+        for path in storage.watch() {
+            log::debug!("Watching {path:?} for changes");
+            watcher
+                .watch(&path, RecursiveMode::Recursive)
+                .map_err(|e| Errcode::FileWatcher(format!("{path:?}: {e:?}")))?;
+        }
+
+        std::thread::spawn(move || {
+            let system = actix_web::rt::System::new();
+            system.block_on(Self::handle_events(rx, storage, render));
+        });
+        Ok(FsWatcher { _watcher: watcher })
+    }
+
+    async fn handle_events(
+        rx: Receiver<notify::Result<Event>>,
+        storage: Arc<Storage>,
+        render: Arc<Render>,
+    ) {
+        while let Ok(event) = rx.recv() {
+            let mut paths: Vec<PathBuf> = vec![];
+            let mut add_event = |event: notify::Result<Event>| match event {
+                Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+                Ok(_) => {}
+                Err(e) => log::warn!("File watcher error: {e:?}"),
+            };
+            add_event(event);
+            while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+                add_event(event);
+            }
+            if paths.is_empty() {
+                continue;
+            }
+
+            log::debug!("Files changed: {paths:?}");
+            let changes = storage.files_changed(&paths);
+            if changes.contains(&StorageChange::Templates) {
+                log::info!("Templates changed, reloading render engine");
+                if let Err(e) = render.reload_engine().await {
+                    log::error!("Unable to reload templates: {e:?}");
+                }
+            }
+        }
+        log::debug!("File watcher stopped");
+    }
+}